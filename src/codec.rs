@@ -0,0 +1,235 @@
+// The public interface to the encoder and decoder. `decode::State` and
+// `encode::State` have many methods for reading and writing the individual
+// parts of the format, which are kept private; `Decoder` and `Encoder` wrap
+// them, exposing only their settings and the entry points meant for users.
+
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+
+#[cfg(feature = "std")]
+use crate::component::ComponentId;
+use crate::decode;
+use crate::encode;
+use crate::error::DecodeError;
+use crate::value::Value;
+#[cfg(feature = "std")]
+use crate::world::WorldData;
+
+#[cfg(feature = "std")]
+pub use crate::decode::Observed;
+pub use crate::decode::Source;
+pub use crate::encode::{FloatPolicy, Sink};
+
+// An error reported by a `Source` or `Sink`. This doesn't depend on `std::io`,
+// so that the traits can be implemented for inputs and outputs that don't go
+// through it. The implementations for `Read` and `Write` report `Io` errors.
+#[derive(Debug)]
+pub enum StreamError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    Other(Cow<'static, str>),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            StreamError::Io(err) => err.fmt(f),
+            StreamError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+// Errors from decoding and encoding are reported as `io::Error`s, so stream
+// errors are converted to them once they reach the rest of the crate.
+impl From<StreamError> for io::Error {
+    fn from(err: StreamError) -> Self {
+        match err {
+            #[cfg(feature = "std")]
+            StreamError::Io(err) => err,
+            StreamError::Other(message) => io::Error::new(io::ErrorKind::Other, message.into_owned()),
+        }
+    }
+}
+
+macro_rules! forward_le {
+    (decode: $($name:ident -> $t:ty),*) => {
+        $(
+            pub fn $name(&mut self) -> Result<$t, DecodeError> {
+                self.0.$name()
+            }
+        )*
+    };
+
+    (encode: $($name:ident($t:ty)),*) => {
+        $(
+            pub fn $name(&mut self, x: $t) -> io::Result<()> {
+                self.0.$name(x)
+            }
+        )*
+    };
+}
+
+pub struct Decoder<R: Source>(decode::State<R>);
+
+#[cfg(feature = "std")]
+impl<R: io::Read, F: FnMut(&[u8])> Decoder<Observed<R, F>> {
+    // Create a decoder which feeds each byte it reads to `hasher`, so that the
+    // input can be hashed in the same pass that decodes it. See `Observed` for
+    // the one byte it may read past what it consumes.
+    pub fn new_hashing(reader: R, hasher: F) -> Self {
+        Self(decode::State::new_hashing(reader, hasher))
+    }
+}
+
+impl<R: Source> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self(decode::State::new(reader))
+    }
+
+    // The number of bytes consumed so far.
+    pub fn idx(&self) -> usize {
+        self.0.idx()
+    }
+
+    // Set the limit on how deeply values may be nested.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.0.set_max_depth(max_depth);
+    }
+
+    // Set an overall budget on the size of the input. No limit is set by default.
+    pub fn set_max_total_bytes(&mut self, max_total_bytes: usize) {
+        self.0.set_max_total_bytes(max_total_bytes);
+    }
+
+    // Reject value tags added in a later version of the format than the one
+    // being decoded, instead of decoding them. Enabled by default.
+    pub fn set_strict_tags(&mut self, strict: bool) {
+        self.0.set_strict_tags(strict);
+    }
+
+    // Share a single allocation between identical field names in the schemes
+    // decoded from now on. Disabled by default.
+    pub fn set_intern_field_names(&mut self, intern: bool) {
+        self.0.set_intern_field_names(intern);
+    }
+
+    pub fn decode_value(&mut self) -> Result<Value, DecodeError> {
+        self.0.decode_value()
+    }
+
+    // Decode a value, failing if it takes up more than `max_bytes` bytes of input.
+    pub fn decode_value_within(&mut self, max_bytes: usize) -> Result<Value, DecodeError> {
+        self.0.decode_value_within(max_bytes)
+    }
+
+    // Decode `count` values which must all be integers, appending them to `out`.
+    pub fn decode_small_ints_into(&mut self, out: &mut Vec<i64>, count: usize) -> Result<(), DecodeError> {
+        self.0.decode_small_ints_into(out, count)
+    }
+
+    // Little-endian primitives, for decoding foreign data embedded in
+    // `Value::Bytes` blobs. The world format itself is always big-endian.
+    forward_le!(decode:
+        decode_u16_le -> u16, decode_i16_le -> i16,
+        decode_u32_le -> u32, decode_i32_le -> i32,
+        decode_u64_le -> u64, decode_i64_le -> i64,
+        decode_f32_le -> f32, decode_f64_le -> f64
+    );
+}
+
+#[cfg(feature = "std")]
+impl<R: io::Read> Decoder<R> {
+    pub fn decode_world(&mut self) -> Result<WorldData, DecodeError> {
+        self.0.decode_world()
+    }
+
+    // Decode a world, keeping only the component arrays whose IDs are in `keep`.
+    pub fn decode_world_filtered(&mut self, keep: &HashSet<ComponentId>) -> Result<WorldData, DecodeError> {
+        self.0.decode_world_filtered(keep)
+    }
+
+    // Decode a world written by `Encoder::encode_world_framed`.
+    pub fn decode_world_framed(&mut self) -> Result<WorldData, DecodeError> {
+        self.0.decode_world_framed()
+    }
+
+    // Decode a header line, splitting it on every space, along with the index
+    // of the byte each field starts at. This is for formats built on this
+    // decoder whose fields are positional and may be empty.
+    pub fn decode_header_line_exact(&mut self, ex: &'static str) -> Result<Vec<(String, usize)>, DecodeError> {
+        self.0.decode_header_line_exact(ex)
+    }
+}
+
+pub struct Encoder<W: Sink>(encode::State<W>);
+
+impl<W: Sink> Encoder<W> {
+    pub fn new(out: W) -> Self {
+        Self(encode::State::new(out))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.0.into_inner()
+    }
+
+    // Compress the values of each component array, whenever doing so makes
+    // the array smaller. Disabled by default.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, compress: bool) {
+        self.0.set_compression(compress);
+    }
+
+    // Write component arrays sparsely whenever doing so makes them smaller.
+    // Disabled by default.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.0.set_sparse(sparse);
+    }
+
+    pub fn set_float_policy(&mut self, policy: FloatPolicy) {
+        self.0.set_float_policy(policy);
+    }
+
+    pub fn encode_value(&mut self, value: &Value) -> io::Result<()> {
+        self.0.encode_value(value, &mut |_| {})
+    }
+
+    // Little-endian primitives, for encoding foreign data embedded in
+    // `Value::Bytes` blobs. The world format itself is always big-endian.
+    forward_le!(encode:
+        encode_u16_le(u16), encode_i16_le(i16),
+        encode_u32_le(u32), encode_i32_le(i32),
+        encode_u64_le(u64), encode_i64_le(i64),
+        encode_f32_le(f32), encode_f64_le(f64)
+    );
+}
+
+impl Encoder<Vec<u8>> {
+    // An encoder writing to a new vector with room for `capacity` bytes, for
+    // example the `encoded_size` of a world.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(encode::State::with_capacity(capacity))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> Encoder<W> {
+    pub fn encode_world(&mut self, world: &WorldData) -> io::Result<()> {
+        self.0.encode_world(world)
+    }
+
+    // Encode a world preceded by its length in bytes, so that it can be
+    // embedded in a larger stream. Decode it with `Decoder::decode_world_framed`.
+    pub fn encode_world_framed(&mut self, world: &WorldData) -> io::Result<()> {
+        self.0.encode_world_framed(world)
+    }
+
+    // Write a component array that has already been encoded, followed by the
+    // newline that separates it from the next section of a world.
+    pub fn write_raw_component(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_raw_component(bytes)
+    }
+}
@@ -1,54 +1,146 @@
+use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::ops::Range;
+use std::sync::Arc;
 
 use super::encode;
 use super::decode;
+use super::error;
 
 use super::value::{Value, EntityId};
 
+// Check whether a name can be written in a header line and read back unchanged.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii() && !c.is_whitespace())
+}
+
 // Find the first duplicate in `vals` using an `O(n^2)` algorithm.
 // This should probably only be used on small arrays.
-fn find_duplicate_quadratic<T: Eq>(ts: &[T]) -> Option<&T> {
-    ts.iter().enumerate().find_map(
-        |(i, t)| if ts[..i].contains(t) {
-            Some(t)
-        } else {
-            None
-        })
+pub(crate) fn find_duplicate_quadratic<T: Eq>(ts: &[T]) -> Option<&T> {
+    find_duplicate_idx_quadratic(ts).map(|i| &ts[i])
+}
+
+// Like `find_duplicate_quadratic`, but return the index of the duplicate.
+pub(crate) fn find_duplicate_idx_quadratic<T: Eq>(ts: &[T]) -> Option<usize> {
+    (0..ts.len()).find(|&i| ts[..i].contains(&ts[i]))
 }
 
+// The ID of a type of component, as written in the header of its array. It is a
+// separate type so that it can't be mixed up with an entity ID or a row index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ComponentId(pub u16);
+
+// Formats the ID as the bare number, as it appears in the encoded format.
+impl fmt::Display for ComponentId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// The name of a field in a scheme. These are reference-counted so that arrays
+// decoded with `codec::Decoder::set_intern_field_names` can share them.
+pub type FieldName = Arc<str>;
+
+#[derive(Clone, Debug)]
 pub struct ComponentArray {
     name: String,
     id: u16,
-    scheme: Vec<String>,
+    scheme: Vec<FieldName>,
     values: Vec<Value>,
+    // whether the array may have changed since it was decoded, or since
+    // `WorldContext::clear_dirty` was last called
+    dirty: bool,
 }
 
+// Arrays are equal if they have the same name, ID, scheme, and values.
+impl PartialEq for ComponentArray {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.id == other.id
+            && self.scheme == other.scheme
+            && self.values == other.values
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct GlobalComponent {
-    scheme: Vec<String>,
+    scheme: Vec<FieldName>,
     values: Vec<Value>,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct ComponentRef<'a> {
-    pub scheme: &'a [String],
+    pub scheme: &'a [FieldName],
     pub values: &'a [Value],
 }
 
 pub struct ComponentMut<'a> {
-    pub scheme: &'a [String],
+    pub scheme: &'a [FieldName],
     pub values: &'a mut [Value],
+    pub(crate) observer: Option<ChangeObserver<'a>>,
+}
+
+// A callback registered with `WorldContext::on_component_changed`.
+pub(crate) type ChangeCallback = Box<dyn Fn(EntityId, &str, &Value) + Send + Sync>;
+
+// The callbacks to notify when a field of an entity's component is set.
+pub(crate) struct ChangeObserver<'a> {
+    pub(crate) callbacks: &'a [ChangeCallback],
+    // every live entity that shares the component, which is more than one
+    // after `WorldContext::intern_components` merges identical components
+    pub(crate) entities: Vec<EntityId>,
 }
 
 impl ComponentArray {
+    // Decode a standalone component array, which must make up the entire input.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::DecodeError> {
+        let mut state = decode::State::new(reader);
+        let array = state.decode_component_array()?;
+        state.expect_eof()?;
+        Ok(array)
+    }
+
+    // Encode a standalone component array. Any `EntityId`s it contains are written as-is.
+    pub fn to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        encode::State::new(writer).encode_component_array(self, |_| {})
+    }
+
+    pub(crate) fn new(name: String, id: u16, scheme: Vec<String>) -> Self {
+        Self::with_scheme(name, id, scheme.into_iter().map(FieldName::from).collect())
+    }
+
+    pub(crate) fn with_scheme(name: String, id: u16, scheme: Vec<FieldName>) -> Self {
+        Self { name, id, scheme, values: Vec::new(), dirty: false }
+    }
+
+    // Append a component to the array, returning its index.
+    // The number of values must match the length of the scheme.
+    pub(crate) fn push(&mut self, values: Vec<Value>) -> u32 {
+        debug_assert_eq!(values.len(), self.scheme.len());
+        let idx = self.len() as u32;
+        self.values.extend(values);
+        self.dirty = true;
+        idx
+    }
+
+    // Change the ID of the array, as `WorldContext::remap_component_id` does.
+    pub(crate) fn set_id(&mut self, id: ComponentId) {
+        self.id = id.0;
+        self.dirty = true;
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    pub fn id(&self) -> u16 {
-        self.id
+    pub fn id(&self) -> ComponentId {
+        ComponentId(self.id)
     }
 
-    pub fn scheme(&self) -> &[String] {
+    pub fn scheme(&self) -> &[FieldName] {
         &self.scheme
     }
 
@@ -57,28 +149,206 @@ impl ComponentArray {
     }
 
     pub fn field_idx(&self, name: &str) -> Option<usize> {
-        self.scheme.iter().position(|n| n == name)
+        self.scheme.iter().position(|n| &**n == name)
+    }
+
+    // The number of components stored in the array. Marker components
+    // store no values, so this is always zero for them.
+    pub fn len(&self) -> usize {
+        self.values.len()
+            .checked_div(self.scheme.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The number of components the array can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+            .checked_div(self.scheme.len())
+            .unwrap_or(0)
+    }
+
+    // Release any memory the array has reserved beyond what its components use,
+    // for example after `retain` has removed most of them.
+    pub fn shrink_to_fit(&mut self) {
+        self.values.shrink_to_fit();
+        self.scheme.shrink_to_fit();
+    }
+
+    // Whether the array may have been modified since it was decoded, or since
+    // it was last marked clean. Any mutable access to a component counts as a
+    // modification, whether or not its values were actually changed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+
+    // The values of every component in the array, in order.
+    pub(crate) fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    // Like `values`, but marks the array as dirty.
+    pub(crate) fn values_mut(&mut self) -> &mut [Value] {
+        self.dirty = true;
+        &mut self.values
+    }
+
+    // Replace every component in the array with the rows in `values`, which
+    // holds the fields of each row in turn, so its length must be a multiple of
+    // the length of the scheme (or zero, for marker components). Entities that
+    // refer to rows past the new end of the array are left dangling, so they
+    // need to be updated by the caller.
+    pub fn set_values(&mut self, values: Vec<Value>) -> Result<(), error::BuildError> {
+        let width = self.scheme.len();
+        let partial = match values.len().checked_rem(width) {
+            Some(partial) => partial,
+            None => values.len(),
+        };
+        if partial != 0 {
+            // report the incomplete last row
+            return Err(error::BuildError::ValueCount { id: ComponentId(self.id), expected: width, got: partial });
+        }
+
+        self.values = values;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Keep only the components for which `f` returns true, shifting the
+    // remaining ones down to fill the gaps. Since this changes the indices
+    // of components, return a vector mapping each old index to its new one
+    // (or to `None` if it was removed), so that references can be fixed up.
+    pub fn retain<F: FnMut(ComponentRef) -> bool>(&mut self, mut f: F) -> Vec<Option<u32>> {
+        let width = self.scheme.len();
+        let len = self.len();
+
+        let mut remap = Vec::with_capacity(len);
+        let mut keep = Vec::with_capacity(len);
+        let mut num_kept = 0;
+        for row in self.values.chunks(width.max(1)) {
+            let kept = f(ComponentRef { scheme: &self.scheme, values: row });
+            keep.push(kept);
+            if kept {
+                remap.push(Some(num_kept));
+                num_kept += 1;
+            } else {
+                remap.push(None);
+            }
+        }
+
+        let mut i = 0;
+        self.values.retain(|_| {
+            let kept = keep[i / width];
+            i += 1;
+            kept
+        });
+        if num_kept as usize != len {
+            self.dirty = true;
+        }
+
+        remap
     }
 
-    pub fn get(&self, idx: u32) -> Option<ComponentRef> {
-        let scheme_len = self.scheme.len() as u32;
-        if scheme_len == 0 && idx != 0 { return None; }
-        let start = idx.checked_mul(scheme_len)? as usize;
-        let end = idx.checked_add(1)?.checked_mul(scheme_len)? as usize;
+    // Merge components whose values are bitwise identical into a single row, so
+    // that `0.0` and `-0.0` are kept apart and equal NaNs are merged, returning
+    // a vector that maps each old index to its new one. Components which were
+    // merged share their storage afterward, so mutating one mutates all of them.
+    pub(crate) fn dedup(&mut self) -> Vec<u32> {
+        let width = self.scheme.len();
+        let len = self.len();
+        let mut remap = Vec::with_capacity(len);
+        if width == 0 {
+            return remap;
+        }
+
+        // maps the hash of a row to the new indices of the rows with that hash
+        let mut rows_by_hash: HashMap<u64, Vec<u32>> = HashMap::new();
+        let mut old_values = std::mem::take(&mut self.values).into_iter();
+        let mut num_rows = 0;
+
+        for _ in 0..len {
+            let row: Vec<Value> = old_values.by_ref().take(width).collect();
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+
+            let candidates = rows_by_hash.entry(hasher.finish()).or_default();
+            let values = &self.values;
+            let existing = candidates.iter().copied().find(|&i| {
+                let start = i as usize * width;
+                values[start .. start + width].iter().zip(&row).all(|(a, b)| a.bitwise_eq(b))
+            });
+
+            match existing {
+                Some(i) => remap.push(i),
+                None => {
+                    candidates.push(num_rows);
+                    remap.push(num_rows);
+                    self.values.extend(row);
+                    num_rows += 1;
+                }
+            }
+        }
+        if num_rows as usize != len {
+            self.dirty = true;
+        }
+
+        remap
+    }
+
+    // Rearrange the components so that the one at index `i` is the one that
+    // was at index `order[i]`. `order` must be a permutation of the indices.
+    pub(crate) fn permute(&mut self, order: &[u32]) {
+        let width = self.scheme.len();
+        debug_assert_eq!(order.len(), self.len());
+        if order.iter().enumerate().all(|(i, &old)| i as u32 == old) {
+            return;
+        }
+
+        let mut old_values = std::mem::take(&mut self.values);
+        self.values.reserve_exact(old_values.len());
+        for &old in order {
+            let start = old as usize * width;
+            let row = &mut old_values[start .. start + width];
+            self.values.extend(row.iter_mut().map(|v| std::mem::replace(v, Value::Bool(false))));
+        }
+        self.dirty = true;
+    }
+
+    // The range of `values` holding the component at `idx`, or `None` if the
+    // arithmetic overflows. This is done in `usize`, so that nothing is lost
+    // converting between it and `u32` on any platform; the range is still
+    // bounds-checked when it is used.
+    fn row_range(&self, idx: u32) -> Option<Range<usize>> {
+        let width = self.scheme.len();
+        if width == 0 && idx != 0 { return None; }
+        let start = usize::try_from(idx).ok()?.checked_mul(width)?;
+        let end = start.checked_add(width)?;
+        Some(start .. end)
+    }
+
+    pub fn get(&self, idx: u32) -> Option<ComponentRef<'_>> {
+        let range = self.row_range(idx)?;
         Some(ComponentRef {
             scheme: &self.scheme,
-            values: self.values.get(start .. end)?,
+            values: self.values.get(range)?,
         })
     }
 
-    pub fn get_mut(&mut self, idx: u32) -> Option<ComponentMut> {
-        let scheme_len = self.scheme.len() as u32;
-        if scheme_len == 0 && idx != 0 { return None; }
-        let start = idx.checked_mul(scheme_len)? as usize;
-        let end = idx.checked_add(1)?.checked_mul(scheme_len)? as usize;
+    pub fn get_mut(&mut self, idx: u32) -> Option<ComponentMut<'_>> {
+        let range = self.row_range(idx)?;
+        let values = self.values.get_mut(range)?;
+        self.dirty = true;
         Some(ComponentMut {
             scheme: &self.scheme,
-            values: self.values.get_mut(start .. end)?,
+            values,
+            observer: None,
         })
     }
 }
@@ -91,7 +361,26 @@ impl GlobalComponent {
         }
     }
 
-    pub fn scheme(&self) -> &[String] {
+    pub(crate) fn new(scheme: Vec<String>, values: Vec<Value>) -> Self {
+        debug_assert_eq!(values.len(), scheme.len());
+        let scheme = scheme.into_iter().map(FieldName::from).collect();
+        Self { scheme, values }
+    }
+
+    // Decode a standalone global component, which must make up the entire input.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::DecodeError> {
+        let mut state = decode::State::new(reader);
+        let global = state.decode_global_component()?;
+        state.expect_eof()?;
+        Ok(global)
+    }
+
+    // Encode a standalone global component. Any `EntityId`s it contains are written as-is.
+    pub fn to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        encode::State::new(writer).encode_global_component(self, |_| {})
+    }
+
+    pub fn scheme(&self) -> &[FieldName] {
         &self.scheme
     }
 
@@ -100,37 +389,43 @@ impl GlobalComponent {
     }
 
     pub fn field_idx(&self, name: &str) -> Option<usize> {
-        self.scheme.iter().position(|n| n == name)
+        self.scheme.iter().position(|n| &**n == name)
     }
 
-    pub fn get(&self) -> ComponentRef {
+    pub fn get(&self) -> ComponentRef<'_> {
         ComponentRef {
             scheme: &self.scheme,
             values: &self.values,
         }
     }
 
-    pub fn get_mut(&mut self) -> ComponentMut {
+    pub fn get_mut(&mut self) -> ComponentMut<'_> {
         ComponentMut {
             scheme: &self.scheme,
             values: &mut self.values,
+            observer: None,
         }
     }
 }
 
 impl<'a> ComponentRef<'a> {
     pub fn field_idx(self, name: &str) -> Option<usize> {
-        self.scheme.iter().position(|n| n == name)
+        self.scheme.iter().position(|n| &**n == name)
     }
 
     pub fn field(self, name: &str) -> Option<&'a Value> {
         Some(&self.values[self.field_idx(name)?])
     }
+
+    // Iterate over the name and value of each field, in scheme order.
+    pub fn fields(self) -> impl Iterator<Item = (&'a str, &'a Value)> {
+        self.scheme.iter().map(|n| &**n).zip(self.values)
+    }
 }
 
 impl<'a> ComponentMut<'a> {
     pub fn field_idx(&self, name: &str) -> Option<usize> {
-        self.scheme.iter().position(|n| n == name)
+        self.scheme.iter().position(|n| &**n == name)
     }
 
     pub fn field(&'a self, name: &str) -> Option<&'a Value> {
@@ -141,102 +436,357 @@ impl<'a> ComponentMut<'a> {
         Some(&mut self.values[self.field_idx(name)?])
     }
 
+    // Set the named field, returning its previous value, or `None` if there is no
+    // such field. This notifies any callbacks registered for the component with
+    // `WorldContext::on_component_changed`, once for each entity sharing it;
+    // modifying `values` directly does not.
+    pub fn set_field(&mut self, name: &str, value: Value) -> Option<Value> {
+        let idx = self.field_idx(name)?;
+        let old = std::mem::replace(&mut self.values[idx], value);
+        if let Some(observer) = &self.observer {
+            for &entity in &observer.entities {
+                for callback in observer.callbacks {
+                    callback(entity, &self.scheme[idx], &self.values[idx]);
+                }
+            }
+        }
+        Some(old)
+    }
+
+    // Iterate over the name and value of each field mutably, in scheme order.
+    pub fn fields_mut(&mut self) -> impl Iterator<Item = (&str, &mut Value)> {
+        self.scheme.iter().map(|n| &**n).zip(self.values.iter_mut())
+    }
+
     pub fn to_ref(&'a self) -> ComponentRef<'a> {
         ComponentRef {
-            scheme: &self.scheme,
-            values: &self.values,
+            scheme: self.scheme,
+            values: self.values,
         }
     }
 }
 
+// How the values of a component array are laid out after its header.
+#[derive(Clone, Copy)]
+enum Layout {
+    Dense,
+    // deflate-compressed, taking up the given number of bytes
+    Compressed(u32),
+    BitPacked,
+    Sparse,
+}
+
+// The header line of a component array, which is enough to find the end of
+// its values without decoding them.
+pub(crate) struct ComponentHeader {
+    pub(crate) name: String,
+    pub(crate) id: u16,
+    num_components: u32,
+    scheme: Vec<FieldName>,
+    num_values: u32,
+    layout: Layout,
+}
+
 impl<R: io::Read> decode::State<R> {
     pub fn decode_component_array(&mut self) -> Result<ComponentArray, decode::Error> {
-        let mut header = self.decode_header_line("component array header")?;
+        let header = self.decode_component_header()?;
+        self.decode_component_values(header)
+    }
+
+    pub(crate) fn decode_component_header(&mut self) -> Result<ComponentHeader, decode::Error> {
+        let mut header = self.decode_header_line_with_offsets("component array header")?;
 
         if header.len() < 4 {
             return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
                 "component array header",
                 "too few fields",
             ));
         }
 
-        // the first entry in the header should be the literal string `COMPONENT`
-        let signature = header.remove(0);
-        if signature != "COMPONENT" {
-            return Err(self.err_unexpected(
+        // the first entry in the header should be the literal string `COMPONENT`,
+        // or `COMPONENT-DEFLATE` if the values are compressed, `COMPONENT-BITS`
+        // if they are booleans packed into bits, or `COMPONENT-SPARSE` if only
+        // the fields that differ from a default row are stored
+        let (signature, at) = header.remove(0);
+        let (compressed, bit_packed, sparse) = match &*signature {
+            "COMPONENT" => (false, false, false),
+            "COMPONENT-DEFLATE" if self.version() >= 1 => (true, false, false),
+            "COMPONENT-BITS" if self.version() >= 3 => (false, true, false),
+            "COMPONENT-SPARSE" if self.version() >= 6 => (false, false, true),
+            _ => return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::BadSignature,
                 "component array signature (COMPONENT)",
                 format!("invalid signature: {:?}", signature),
-            ));
-        }
+            )),
+        };
         
         // the second entry in the header should be the name of the component
-        let name = header.remove(0);
+        let (name, _) = header.remove(0);
 
         // the third entry is the ID of the component
-        let id = match header.remove(0).parse::<u16>() {
+        let (id, at) = header.remove(0);
+        let id = match id.parse::<u16>() {
             Ok(id) => id,
-            Err(_) => return Err(self.err_unexpected(
+            Err(_) => return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::InvalidNumber,
                 "16-bit component ID",
-                "invalid ID",
+                format!("invalid ID: {:?}", id),
             )),
         };
 
         // the fourth entry is the number of components
-        let num_components = match header.remove(0).parse::<u32>() {
+        let (num_components, at) = header.remove(0);
+        let num_components = match num_components.parse::<u32>() {
             Ok(n) => n,
-            Err(_) => return Err(self.err_unexpected(
+            Err(_) => return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::InvalidNumber,
                 "32-bit component count",
-                "invalid component count",
+                format!("invalid component count: {:?}", num_components),
             )),
         };
 
+        // compressed arrays have a fifth entry: the size of the compressed values
+        let compressed_len = if compressed {
+            match header.first().map(|(s, at)| (s.parse::<u32>(), s, *at)) {
+                Some((Ok(n), _, _)) => {
+                    header.remove(0);
+                    Some(n)
+                }
+                Some((Err(_), s, at)) => return Err(self.err_unexpected_at(
+                    at,
+                    decode::ErrorKind::InvalidNumber,
+                    "32-bit compressed length",
+                    format!("invalid compressed length: {:?}", s),
+                )),
+                None => return Err(self.err_unexpected(
+                    decode::ErrorKind::InvalidNumber,
+                    "32-bit compressed length",
+                    "invalid compressed length",
+                )),
+            }
+        } else {
+            None
+        };
+
         // the rest of the entries describe the scheme
-        let scheme = header;
+        let (scheme, offsets) = self.intern_scheme(header);
         
         // ensure that the scheme has no duplicate fields
-        if let Some(dup) = find_duplicate_quadratic(&scheme) {
-            return Err(self.err_unexpected(
+        if let Some(i) = find_duplicate_idx_quadratic(&scheme) {
+            return Err(self.err_unexpected_at(
+                offsets[i],
+                decode::ErrorKind::DuplicateField,
                 "distinct field names",
-                format!("duplicate name: {:?}", dup),
+                format!("duplicate name: {:?}", scheme[i]),
             ))
         }
 
-        // decode the list of values comprising the component fields
-        let num_values = num_components * scheme.len() as u32;
-        let mut values = Vec::with_capacity(num_values as usize);
+        if bit_packed && scheme.len() != 1 {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
+                "bit-packed component array with one field",
+                format!("{} fields", scheme.len()),
+            ));
+        }
+
+        // the product is computed in `usize` so that it can't wrap around, but components
+        // are addressed with `u32` indices, so the total must still fit in a `u32`
+        let num_values = (num_components as usize).checked_mul(scheme.len())
+            .and_then(|n| u32::try_from(n).ok());
+        let num_values = match num_values {
+            Some(n) => n,
+            None => return Err(self.err_unexpected(
+                decode::ErrorKind::LengthLimitExceeded,
+                "component count fitting in memory",
+                format!(
+                    "count * fields overflows ({} components with {} fields each)",
+                    num_components,
+                    scheme.len(),
+                ),
+            )),
+        };
+
+        let layout = match compressed_len {
+            Some(len) => Layout::Compressed(len),
+            None if bit_packed => Layout::BitPacked,
+            None if sparse => Layout::Sparse,
+            None => Layout::Dense,
+        };
+        Ok(ComponentHeader { name, id, num_components, scheme, num_values, layout })
+    }
+
+    // Decode the list of values comprising the component fields of the array
+    // whose header has just been read.
+    pub(crate) fn decode_component_values(&mut self, header: ComponentHeader) -> Result<ComponentArray, decode::Error> {
+        let ComponentHeader { name, id, num_components, scheme, num_values, layout } = header;
+        let values = match layout {
+            Layout::Compressed(len) => self.decode_compressed_values(num_values, len)?,
+            Layout::BitPacked => self.decode_bit_packed_values(num_values)?,
+            Layout::Sparse => self.decode_sparse_values(num_components, scheme.len())?,
+            Layout::Dense => {
+                let mut values = Vec::with_capacity(decode::capacity_hint(num_values as usize));
+                for _ in 0..num_values {
+                    values.push(self.decode_value()?);
+                }
+                values
+            }
+        };
+
+        Ok(ComponentArray { name, id, scheme, values, dirty: false })
+    }
+
+    // Read past the values of the array whose header has just been read,
+    // without keeping them. Their tags and lengths are checked as if they were
+    // being decoded, but strings aren't checked to be valid UTF-8, and
+    // compressed values are passed over without being inflated.
+    pub(crate) fn skip_component_values(&mut self, header: &ComponentHeader) -> Result<(), decode::Error> {
+        match header.layout {
+            Layout::Compressed(len) => self.skip_raw_bytes(len as usize, "compressed values"),
+            Layout::BitPacked => self.skip_raw_bytes((header.num_values as usize + 7) / 8, "bit-packed values"),
+            Layout::Sparse => {
+                let width = header.scheme.len();
+                for _ in 0..width {
+                    self.skip_value()?;
+                }
+                for _ in 0..header.num_components {
+                    for byte in 0..(width + 7) / 8 {
+                        // padding bits past the last field are ignored
+                        let fields = (width - byte * 8).min(8);
+                        let mask = self.next("field bitmask")? as u16 & ((1 << fields) - 1);
+                        for _ in 0..mask.count_ones() {
+                            self.skip_value()?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Layout::Dense => {
+                for _ in 0..header.num_values {
+                    self.skip_value()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Decode `num_values` booleans packed eight to a byte, least significant bit
+    // first. Any padding bits in the last byte are ignored.
+    fn decode_bit_packed_values(&mut self, num_values: u32) -> Result<Vec<Value>, decode::Error> {
+        let num_bytes = (num_values as usize + 7) / 8;
+        let bytes = self.decode_raw_bytes(num_bytes, "bit-packed values")?;
+        Ok((0..num_values as usize)
+            .map(|i| Value::Bool(bytes[i / 8] & (1 << (i % 8)) != 0))
+            .collect())
+    }
+
+    // Decode a default row of `width` values, followed by `num_components` rows
+    // which each begin with a bitmask of the fields they set (least significant
+    // bit first, padded to a whole number of bytes) and then hold the values of
+    // just those fields. The other fields take the default row's values.
+    fn decode_sparse_values(&mut self, num_components: u32, width: usize) -> Result<Vec<Value>, decode::Error> {
+        let mut default = Vec::with_capacity(width);
+        for _ in 0..width {
+            default.push(self.decode_value()?);
+        }
+
+        let mask_len = (width + 7) / 8;
+        // the caller has already checked that this doesn't overflow
+        let num_values = (num_components as usize).saturating_mul(width);
+        let mut values = Vec::with_capacity(decode::capacity_hint(num_values));
+        for _ in 0..num_components {
+            let mask = self.decode_raw_bytes(mask_len, "field bitmask")?;
+            for (i, default) in default.iter().enumerate() {
+                if mask[i / 8] & (1 << (i % 8)) != 0 {
+                    values.push(self.decode_value()?);
+                } else {
+                    values.push(default.clone());
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    // Inflate a block of `len` compressed bytes and decode `num_values` values
+    // from it, which must use up the whole block. Errors inside the block are
+    // reported at the block's offset. The values are decoded as they are
+    // inflated, so that a small block can't expand into more memory than the
+    // values it holds take up.
+    #[cfg(feature = "compression")]
+    fn decode_compressed_values(
+        &mut self,
+        num_values: u32,
+        len: u32,
+    ) -> Result<Vec<Value>, decode::Error> {
+        let start = self.idx();
+        let compressed = self.decode_raw_bytes(len as usize, "compressed values")?;
+        let mut inner = self.with_settings_of(flate2::read::DeflateDecoder::new(&compressed[..]), start);
+
+        // the decoder only fails on reads when the block is corrupt
+        let invalid = |err| match err {
+            decode::Error::Io(e) => self.err_unexpected(
+                decode::ErrorKind::InvalidCompressedData,
+                "deflate-compressed values",
+                e.to_string(),
+            ),
+            err => err,
+        };
+
+        let mut values = Vec::with_capacity(decode::capacity_hint(num_values as usize));
         for _ in 0..num_values {
-            values.push(self.decode_value()?);
+            values.push(inner.decode_value().map_err(invalid)?);
         }
+        inner.expect_eof().map_err(invalid)?;
+        Ok(values)
+    }
 
-        Ok(ComponentArray { name, id, scheme, values })
+    #[cfg(not(feature = "compression"))]
+    fn decode_compressed_values(
+        &mut self,
+        _num_values: u32,
+        _len: u32,
+    ) -> Result<Vec<Value>, decode::Error> {
+        Err(self.err_unexpected(
+            decode::ErrorKind::InvalidCompressedData,
+            "uncompressed component array",
+            "compressed array (enable the `compression` feature)",
+        ))
     }
 
     pub fn decode_global_component(&mut self) -> Result<GlobalComponent, decode::Error> {
-        let mut header = self.decode_header_line("global component header")?;
+        let mut header = self.decode_header_line_with_offsets("global component header")?;
         
         if header.is_empty() {
             return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
                 "global component header",
                 "too few fields",
             ));
         }
 
         // the first entry in the header should be the literal string "GLOBAL"
-        let signature = header.remove(0);
+        let (signature, at) = header.remove(0);
         if signature != "GLOBAL" {
-            return Err(self.err_unexpected(
+            return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::BadSignature,
                 "global component signature (GLOBAL)",
                 format!("invalid signature: {:?}", signature),
             ));
         }
 
-        let scheme = header;
+        let (scheme, offsets) = self.intern_scheme(header);
 
         // ensure that the scheme has no duplicate fields
-        if let Some(dup) = find_duplicate_quadratic(&scheme) {
-            return Err(self.err_unexpected(
+        if let Some(i) = find_duplicate_idx_quadratic(&scheme) {
+            return Err(self.err_unexpected_at(
+                offsets[i],
+                decode::ErrorKind::DuplicateField,
                 "distinct field names",
-                format!("duplicate name: {:?}", dup),
+                format!("duplicate name: {:?}", scheme[i]),
             ));
         }
 
@@ -248,6 +798,72 @@ impl<R: io::Read> decode::State<R> {
 
         Ok(GlobalComponent { scheme, values })
     }
+
+    // Split the fields of a header line that make up a scheme into the field
+    // names and the offsets they were found at.
+    fn intern_scheme(&mut self, fields: Vec<(String, usize)>) -> (Vec<FieldName>, Vec<usize>) {
+        fields.into_iter().map(|(name, at)| (self.intern(name), at)).unzip()
+    }
+}
+
+// Pack the values of a component array with a single boolean field into bits,
+// if that would take up less space than writing them normally.
+fn bit_pack(array: &ComponentArray) -> Option<Vec<u8>> {
+    let len = array.values.len();
+    if array.scheme.len() != 1 || (len + 7) / 8 + "-BITS".len() >= len {
+        return None;
+    }
+
+    let mut bits = vec![0; (len + 7) / 8];
+    for (i, value) in array.values.iter().enumerate() {
+        match value {
+            Value::Bool(true) => bits[i / 8] |= 1 << (i % 8),
+            Value::Bool(false) => {}
+            _ => return None,
+        }
+    }
+    Some(bits)
+}
+
+// Choose a default row for writing an array sparsely, made of the most common
+// value of each field, if at least half of the values in the array match it.
+// Otherwise writing the array sparsely can't save enough to be worth trying.
+// Also returns the number of values of each field which match the default.
+fn sparse_default(array: &ComponentArray) -> Option<(Vec<Value>, Vec<usize>)> {
+    let width = array.scheme.len();
+    if width == 0 || array.len() < 2 {
+        return None;
+    }
+
+    let mut default = Vec::with_capacity(width);
+    let mut matching = Vec::with_capacity(width);
+    for field in 0..width {
+        // `Value` isn't `Eq`, so group the values by hash and compare them
+        // within each group, as `dedup` does. Ties go to the value seen first,
+        // so that the output doesn't depend on the order of the map.
+        let mut counts: HashMap<u64, Vec<(&Value, usize, usize)>> = HashMap::new();
+        for (row_idx, row) in array.values.chunks(width).enumerate() {
+            let value = &row[field];
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            let candidates = counts.entry(hasher.finish()).or_default();
+            match candidates.iter_mut().find(|(v, _, _)| v.bitwise_eq(value)) {
+                Some((_, count, _)) => *count += 1,
+                None => candidates.push((value, 1, row_idx)),
+            }
+        }
+        let (value, count, _) = counts.into_values()
+            .flatten()
+            .max_by_key(|&(_, count, first)| (count, std::cmp::Reverse(first)))?;
+        default.push(value.clone());
+        matching.push(count);
+    }
+
+    if matching.iter().sum::<usize>() * 2 >= array.values.len() {
+        Some((default, matching))
+    } else {
+        None
+    }
 }
 
 impl<W: io::Write> encode::State<W> {
@@ -256,19 +872,163 @@ impl<W: io::Write> encode::State<W> {
         array: &ComponentArray,
         mut e_id_transform: ET,
     ) -> io::Result<()> {
-        let len = array.values.len()
-            .checked_div(array.scheme.len())
-            .unwrap_or(0);
-        self.write_fmt(format_args!("COMPONENT {} {} {}", array.name, array.id, len))?;
+        if let Some(bits) = bit_pack(array) {
+            self.encode_component_header(array, "COMPONENT-BITS", None)?;
+            return self.write(&bits);
+        }
+
+        #[cfg(feature = "compression")]
+        {
+            if self.compress && !array.values.is_empty() {
+                return self.encode_compressed_component_array(array, e_id_transform);
+            }
+        }
+
+        if self.sparse {
+            if let Some((default, matching)) = sparse_default(array) {
+                if self.sparse_is_smaller(array, &default, &matching, &mut e_id_transform)? {
+                    return self.encode_sparse_component_array(array, &default, e_id_transform);
+                }
+            }
+        }
+
+        self.encode_component_header(array, "COMPONENT", None)?;
+        for value in &array.values {
+            self.encode_value(value, &mut e_id_transform)?;
+        }
+        Ok(())
+    }
+
+    // Whether writing an array sparsely, with `default` as its default row, takes
+    // up fewer bytes than writing it normally, given the number of values of
+    // each field which match the default. Values which don't match are written
+    // the same way either way, so only the default row needs to be measured:
+    // each matching value saves its length, and the default row, the masks and
+    // the longer signature cost theirs.
+    fn sparse_is_smaller<ET: FnMut(&mut EntityId)>(
+        &self,
+        array: &ComponentArray,
+        default: &[Value],
+        matching: &[usize],
+        e_id_transform: &mut ET,
+    ) -> io::Result<bool> {
+        let mut saved = 0;
+        let mut cost = array.len() * ((default.len() + 7) / 8) + "-SPARSE".len();
+        for (value, &count) in default.iter().zip(matching) {
+            let mut counter = encode::State::with_max_depth(encode::Counter::default(), self.max_depth);
+            counter.set_float_policy(self.float_policy);
+            counter.encode_value(value, e_id_transform)?;
+            let len = counter.into_inner().0;
+            saved += len * count;
+            cost += len;
+        }
+        Ok(cost < saved)
+    }
+
+    fn encode_sparse_component_array<ET: FnMut(&mut EntityId)>(
+        &mut self,
+        array: &ComponentArray,
+        default: &[Value],
+        mut e_id_transform: ET,
+    ) -> io::Result<()> {
+        self.encode_component_header(array, "COMPONENT-SPARSE", None)?;
+        self.encode_sparse_values(array, default, &mut e_id_transform)
+    }
+
+    // Write the values in the form read by `decode_sparse_values`.
+    fn encode_sparse_values<ET: FnMut(&mut EntityId)>(
+        &mut self,
+        array: &ComponentArray,
+        default: &[Value],
+        e_id_transform: &mut ET,
+    ) -> io::Result<()> {
+        for value in default {
+            self.encode_value(value, e_id_transform)?;
+        }
+
+        let mut mask = vec![0; (default.len() + 7) / 8];
+        for row in array.values.chunks(default.len()) {
+            mask.iter_mut().for_each(|byte| *byte = 0);
+            for (i, (value, default)) in row.iter().zip(default).enumerate() {
+                if !value.bitwise_eq(default) {
+                    mask[i / 8] |= 1 << (i % 8);
+                }
+            }
+            self.write(&mask)?;
+            for (value, default) in row.iter().zip(default) {
+                if !value.bitwise_eq(default) {
+                    self.encode_value(value, e_id_transform)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_component_header(
+        &mut self,
+        array: &ComponentArray,
+        signature: &str,
+        compressed_len: Option<usize>,
+    ) -> io::Result<()> {
+        let len = array.len();
+        self.write_fmt(format_args!("{} {} {} {}", signature, array.name, array.id, len))?;
+        if let Some(compressed_len) = compressed_len {
+            self.write_fmt(format_args!(" {}", compressed_len))?;
+        }
         for field_name in &array.scheme {
             self.write(b" ")?;
             self.write(field_name.as_bytes())?;
         }
-        self.write(b"\n")?;
+        self.write(b"\n")
+    }
+
+    // Encode the values into a buffer, and write them deflate-compressed if
+    // that is smaller (including the extra header field) than writing them raw.
+    #[cfg(feature = "compression")]
+    fn encode_compressed_component_array<ET: FnMut(&mut EntityId)>(
+        &mut self,
+        array: &ComponentArray,
+        mut e_id_transform: ET,
+    ) -> io::Result<()> {
+        use std::io::Write;
+
+        let mut raw = encode::State::with_max_depth(Vec::new(), self.max_depth);
+        raw.set_float_policy(self.float_policy);
         for value in &array.values {
-            self.encode_value(value, &mut e_id_transform)?;
+            raw.encode_value(value, &mut e_id_transform)?;
         }
-        Ok(())
+        let raw = raw.into_inner();
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&raw)?;
+        let compressed = encoder.finish()?;
+
+        let overhead = compressed.len().to_string().len() + 1 + "-DEFLATE".len();
+        if compressed.len() + overhead < raw.len() && compressed.len() <= u32::MAX as usize {
+            self.encode_component_header(array, "COMPONENT-DEFLATE", Some(compressed.len()))?;
+            self.write(&compressed)
+        } else {
+            self.encode_component_header(array, "COMPONENT", None)?;
+            self.write(&raw)
+        }
+    }
+
+    // Write a component array that has already been encoded, followed by the
+    // newline that separates it from the next section of a world. The bytes are
+    // decoded first to check that they hold exactly one well-formed array and its
+    // trailing newline, but any `EntityId`s they contain are written as-is.
+    pub fn write_raw_component(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = decode::State::new(bytes);
+        let checked = state.decode_component_array()
+            .and_then(|_| state.expect_newline())
+            .and_then(|_| state.expect_eof());
+        if let Err(err) = checked {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid raw component array: {}", err),
+            ));
+        }
+        self.write(bytes)
     }
 
     pub fn encode_global_component<ET: FnMut(&mut EntityId)>(
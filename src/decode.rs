@@ -1,26 +1,212 @@
 use std::ascii;
 use std::borrow::Cow;
-use std::io::{self, Read};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+#[cfg(feature = "std")]
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::codec::StreamError;
+
+// The category of a decoding error, for callers that need to handle
+// different failures differently. The `ex` and `got` strings of the
+// error give a human-readable description of the specifics.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    // The input ended before decoding was finished.
+    UnexpectedEof,
+    // A header line started with the wrong signature.
+    BadSignature,
+    // A header line had the wrong number of fields or contained non-ASCII bytes.
+    MalformedHeader,
+    // A numeric field in a header line could not be parsed.
+    InvalidNumber,
+    // A newline was expected between sections.
+    MissingNewline,
+    // A tag byte did not correspond to any known encoding.
+    InvalidTag,
+    InvalidUtf8,
+    // A scheme contained the same field name more than once.
+    DuplicateField,
+    // Two component arrays had the same name or ID.
+    DuplicateComponent,
+    // A component array had an ID greater than the maximum declared by the world.
+    ComponentIdOutOfRange,
+    // There were bytes left over after decoding a standalone item.
+    TrailingBytes,
+    // A value was nested more deeply than the state's maximum depth.
+    DepthLimitExceeded,
+    // A length read from the input was too large to represent, or the input
+    // was longer than `State::set_max_total_bytes` allowed.
+    LengthLimitExceeded,
+    // The input declared a format version newer than this library supports.
+    UnsupportedVersion,
+    // A value took up more bytes than `State::decode_value_within` allowed.
+    SizeLimitExceeded,
+    // A compressed block could not be decompressed, or compression support
+    // was not compiled in.
+    InvalidCompressedData,
+    // A component array in an incremental save did not match the array it
+    // replaces in the base world.
+    MismatchedComponent,
+    // The underlying reader returned an error.
+    Io,
+}
 
 #[derive(Debug)]
 pub enum Error {
     Unexpected {
         idx: usize,
+        kind: ErrorKind,
         ex: Cow<'static, str>,
         got: Cow<'static, str>,
     },
     Io(io::Error),
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Unexpected { kind, .. } => *kind,
+            Error::Io(_) => ErrorKind::Io,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unexpected { idx, ex, got, .. } =>
+                write!(f, "at byte {}: expected {}, got {}", idx, ex, got),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Unexpected { .. } => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Self::Io(err)
     }
 }
 
-pub struct State<R: Read> {
+impl From<StreamError> for Error {
+    fn from(err: StreamError) -> Self {
+        Self::Io(err.into())
+    }
+}
+
+// The version of the format written by the encoder. Each version is a superset
+// of the previous ones, so older files can still be decoded.
+//
+// - Version 0 (no version field in the world header) is the original format.
+// - Version 1 adds the string, decimal, and packed integer array value tags,
+//   and deflate-compressed component arrays.
+// - Version 2 adds deleted-entity tombstones. These reuse the component count
+//   byte 0xfe, so a count of 254 is always written in its escaped form.
+// - Version 3 adds bit-packed component arrays of a single boolean field.
+// - Version 4 adds `META` lines of metadata after the world header.
+// - Version 5 adds the 24-bit entity ID value tag.
+// - Version 6 adds sparse component arrays, which store only the fields that
+//   differ from a default row.
+pub const FORMAT_VERSION: u32 = 6;
+
+// The largest number of elements to allocate space for up front based on a
+// length read from the input. Longer sequences are still decoded, but their
+// storage grows as they are read, so a corrupt length can't trigger a huge
+// allocation before the input runs out.
+const MAX_PREALLOC: usize = 4096;
+
+pub(crate) fn capacity_hint(len: usize) -> usize {
+    len.min(MAX_PREALLOC)
+}
+
+// A source of input bytes for `State`. With the `std` feature, this is
+// implemented for every `Read`, but it can also be implemented directly for
+// inputs that don't go through `std::io`. Only values can be decoded from a
+// plain `Source`: headers, and the sections of a world that begin with them,
+// need a `Read`.
+pub trait Source {
+    // The next byte of the input, or `None` at the end of it.
+    fn next_byte(&mut self) -> Result<Option<u8>, StreamError>;
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Source for R {
+    fn next_byte(&mut self) -> Result<Option<u8>, StreamError> {
+        let mut byte = 0;
+        loop {
+            match self.read(std::slice::from_mut(&mut byte)) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte)),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(StreamError::Io(err)),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Source for &[u8] {
+    fn next_byte(&mut self) -> Result<Option<u8>, StreamError> {
+        let (&first, rest) = match self.split_first() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        *self = rest;
+        Ok(Some(first))
+    }
+}
+
+pub struct State<R: Source> {
     idx: usize,
-    bytes: io::Bytes<R>,
+    source: R,
+    pub(crate) depth: usize,
+    pub(crate) max_depth: usize,
+    version: u32,
+    // the index past which no more bytes may be consumed
+    pub(crate) limit: usize,
+    // the overall budget set by `set_max_total_bytes`, which unlike `limit` is
+    // never lifted once the current item has been decoded
+    max_total_bytes: usize,
+    // previously-decoded field names, if they are being interned
+    field_names: Option<HashSet<Arc<str>>>,
+    // a header line which was read but turned out to belong to the next
+    // section, and the index of its first byte
+    pending_header: Option<(String, usize)>,
+    // a byte which was read by `at_eof` but not yet consumed
+    peeked: Option<u8>,
+    // whether to reject tags added after the version being decoded
+    pub(crate) strict_tags: bool,
+}
+
+// A reader that passes every byte read through it to a callback. `State` reads
+// one byte at a time, so the callback sees the bytes that were decoded, plus at
+// most one byte past them: `at_eof` and `peek` read the next byte ahead of
+// consuming it, and a state that stops after calling them leaves it unconsumed.
+#[cfg(feature = "std")]
+pub struct Observed<R, F> {
+    reader: R,
+    observer: F,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, F: FnMut(&[u8])> Read for Observed<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        (self.observer)(&buf[..n]);
+        Ok(n)
+    }
 }
 
 macro_rules! declare_decode_primitive {
@@ -36,6 +222,16 @@ macro_rules! declare_decode_primitive {
         }
     };
     
+    // little-endian variant
+    (le: $name:ident, $t:ty, $desc:literal, $($vars:ident)*) => {
+        pub fn $name(&mut self) -> Result<$t, Error> {
+            $(
+                let $vars = self.next($desc)?;
+            )*
+            Ok(<$t>::from_le_bytes([$($vars),*]))
+        }
+    };
+
     ($name:ident, $t:ty, $desc:literal, $($vars:ident)*) => {
         pub fn $name(&mut self) -> Result<$t, Error> {
             $(
@@ -46,53 +242,242 @@ macro_rules! declare_decode_primitive {
     }
 }
 
-impl<R: Read> State<R> {
+#[cfg(feature = "std")]
+impl<R: Read, F: FnMut(&[u8])> State<Observed<R, F>> {
+    // Create a state which feeds each byte it reads to `hasher`, so that the
+    // input can be hashed (for example, to address it by its content) in the
+    // same pass that decodes it. See `Observed` for when that includes a byte
+    // past the ones consumed.
+    pub fn new_hashing(reader: R, hasher: F) -> Self {
+        Self::new(Observed { reader, observer: hasher })
+    }
+}
+
+impl<R: Source> State<R> {
     pub fn new(reader: R) -> Self {
+        Self::with_offset(reader, 0)
+    }
+
+    // Create a state for a reader that begins `idx` bytes into the input,
+    // so that errors report positions relative to the start of the input.
+    pub fn with_offset(reader: R, idx: usize) -> Self {
         Self {
-            idx: 0,
-            bytes: reader.bytes(),
+            idx,
+            source: reader,
+            depth: 0,
+            max_depth: crate::encode::DEFAULT_MAX_DEPTH,
+            version: FORMAT_VERSION,
+            limit: usize::MAX,
+            max_total_bytes: usize::MAX,
+            field_names: None,
+            pending_header: None,
+            peeked: None,
+            strict_tags: true,
+        }
+    }
+
+    // A fresh state reading from `source`, which begins `idx` bytes into the
+    // input, with the same format version, depth limit, size limit, overall
+    // budget and tag checking as this one. Interned field names aren't shared.
+    pub(crate) fn with_settings_of<S: Source>(&self, source: S, idx: usize) -> State<S> {
+        State {
+            idx,
+            source,
+            depth: 0,
+            max_depth: self.max_depth,
+            version: self.version,
+            limit: self.limit,
+            max_total_bytes: self.max_total_bytes,
+            field_names: None,
+            pending_header: None,
+            peeked: None,
+            strict_tags: self.strict_tags,
+        }
+    }
+
+    // The format version being decoded. This is `FORMAT_VERSION` unless
+    // a world header has declared otherwise.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    // Reject value tags that were added in a later version of the format than
+    // the one being decoded with `InvalidTag`. Otherwise, since a tag is never
+    // given a new meaning once assigned, such tags are decoded as they would be
+    // in the version that added them. Tags this decoder doesn't know at all are
+    // rejected either way. Enabled by default.
+    pub fn set_strict_tags(&mut self, strict: bool) {
+        self.strict_tags = strict;
+    }
+
+    // Set the limit on how deeply values may be nested. The default is the
+    // same as when encoding, so that any decoded value can be re-encoded.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // Set an overall budget on the size of the input, so that decoding untrusted
+    // input gives up with `LengthLimitExceeded` once `idx` would pass it, however
+    // the input is divided into arrays and values. No limit is set by default.
+    pub fn set_max_total_bytes(&mut self, max_total_bytes: usize) {
+        self.max_total_bytes = max_total_bytes;
+    }
+
+    // Share a single allocation between identical field names in the schemes of
+    // the component arrays and global component decoded from now on. This saves
+    // memory when many arrays use the same field names. Disabled by default.
+    pub fn set_intern_field_names(&mut self, intern: bool) {
+        self.field_names = if intern { Some(HashSet::new()) } else { None };
+    }
+
+    pub(crate) fn intern(&mut self, name: String) -> Arc<str> {
+        match &mut self.field_names {
+            None => name.into(),
+            Some(names) => match names.get(name.as_str()) {
+                Some(interned) => interned.clone(),
+                None => {
+                    let interned: Arc<str> = name.into();
+                    names.insert(interned.clone());
+                    interned
+                }
+            },
         }
     }
 
+    // The number of bytes consumed so far.
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
     pub fn err_unexpected(
         &self,
+        kind: ErrorKind,
+        ex: impl Into<Cow<'static, str>>,
+        got: impl Into<Cow<'static, str>>,
+    ) -> Error {
+        self.err_unexpected_at(self.idx, kind, ex, got)
+    }
+
+    // Like `err_unexpected`, but for an error at an earlier position in the
+    // input, such as a field of a header line that has already been read.
+    pub fn err_unexpected_at(
+        &self,
+        idx: usize,
+        kind: ErrorKind,
         ex: impl Into<Cow<'static, str>>,
         got: impl Into<Cow<'static, str>>,
     ) -> Error {
         Error::Unexpected {
-            idx: self.idx,
+            idx,
+            kind,
             ex: ex.into(),
             got: got.into(),
         }
     }
 
     pub fn try_next(&mut self) -> Result<Option<u8>, Error> {
-        let byte = self.bytes.next().transpose()?;
+        if self.idx >= self.limit {
+            return Err(self.err_over_limit());
+        }
+        let byte = match self.peeked.take() {
+            Some(byte) => Some(byte),
+            None => self.source.next_byte()?,
+        };
         if byte.is_some() {
+            // the budget only counts bytes that are actually there, so input
+            // that ends exactly at the budget is still accepted
+            if self.idx >= self.max_total_bytes {
+                self.peeked = byte;
+                return Err(self.err_over_budget());
+            }
             self.idx += 1;
         }
         Ok(byte)
     }
 
+    // Whether the input has ended, without consuming anything. This can be
+    // used between values to tell the end of a stream of them apart from a
+    // truncated value. Note that checking reads the next byte from the source,
+    // so a hashing state's callback may see one byte more than was consumed.
+    pub fn at_eof(&mut self) -> Result<bool, Error> {
+        if self.peeked.is_none() {
+            self.peeked = self.source.next_byte()?;
+        }
+        Ok(self.peeked.is_none())
+    }
+
+    // The next byte, without consuming it. Unlike `at_eof`, this never reads
+    // past the size limit, and returns `None` there as well as at the end of input.
+    pub(crate) fn peek(&mut self) -> Result<Option<u8>, Error> {
+        if self.idx >= self.limit {
+            return Ok(None);
+        }
+        if self.peeked.is_none() {
+            self.peeked = self.source.next_byte()?;
+        }
+        Ok(self.peeked)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn err_over_budget(&self) -> Error {
+        self.err_unexpected(
+            ErrorKind::LengthLimitExceeded,
+            format!("input of at most {} bytes", self.max_total_bytes),
+            "more bytes",
+        )
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn err_over_limit(&self) -> Error {
+        self.err_unexpected(
+            ErrorKind::SizeLimitExceeded,
+            "no more bytes than the size limit",
+            "more bytes",
+        )
+    }
+
     pub fn next(&mut self, ex: impl Into<Cow<'static, str>>) -> Result<u8, Error> {
         match self.try_next()? {
             Some(byte) => Ok(byte),
-            None => Err(self.err_unexpected(ex, "EOF")),
+            None => Err(self.err_unexpected(ErrorKind::UnexpectedEof, ex, "EOF")),
         }
     }
 
+    // Consume the newline which ends a section, tolerating a CRLF line ending
+    // as header lines do.
     pub fn expect_newline(&mut self) -> Result<(), Error> {
-        let byte = self.next("newline")?;
+        let mut byte = self.next("newline")?;
+        if byte == b'\r' {
+            byte = self.next("newline")?;
+        }
         if byte == b'\n' {
             Ok(())
         } else {
             Err(self.err_unexpected(
+                ErrorKind::MissingNewline,
                 "newline",
                 format!("non-newline byte: {}", ascii::escape_default(byte)),
             ))
         }
     }
 
+    pub fn expect_eof(&mut self) -> Result<(), Error> {
+        match self.try_next()? {
+            None => Ok(()),
+            Some(_) => Err(self.err_unexpected(
+                ErrorKind::TrailingBytes,
+                "end of input",
+                "trailing bytes",
+            )),
+        }
+    }
+
     declare_decode_primitive!(decode_u8, u8, "8-bit uint", a);
     declare_decode_primitive!(decode_i8, i8, "8-bit int", a);
 
@@ -104,26 +489,135 @@ impl<R: Read> State<R> {
     declare_decode_primitive!(decode_u32, u32, "32-bit uint", a b c d);
     declare_decode_primitive!(decode_i32, i32, "32-bit int", a b c d);
 
+    declare_decode_primitive!(decode_u64, u64, "64-bit uint", a b c d e f g h);
     declare_decode_primitive!(decode_i64, i64, "64-bit int", a b c d e f g h);
 
     declare_decode_primitive!(decode_f32, f32, "float", a b c d);
     declare_decode_primitive!(decode_f64, f64, "double", a b c d e f g h);
 
+    // Little-endian primitives. The world format itself is always big-endian:
+    // these are intended for custom decoders of foreign data embedded in
+    // `Value::Bytes` blobs.
+
+    declare_decode_primitive!(le: decode_u16_le, u16, "16-bit LE uint", a b);
+    declare_decode_primitive!(le: decode_i16_le, i16, "16-bit LE int", a b);
+
+    declare_decode_primitive!(le: decode_u32_le, u32, "32-bit LE uint", a b c d);
+    declare_decode_primitive!(le: decode_i32_le, i32, "32-bit LE int", a b c d);
+
+    declare_decode_primitive!(le: decode_u64_le, u64, "64-bit LE uint", a b c d e f g h);
+    declare_decode_primitive!(le: decode_i64_le, i64, "64-bit LE int", a b c d e f g h);
+
+    declare_decode_primitive!(le: decode_f32_le, f32, "LE float", a b c d);
+    declare_decode_primitive!(le: decode_f64_le, f64, "LE double", a b c d e f g h);
+}
+
+// There are two ways of splitting a header line into fields:
+//
+// - `decode_header_line` and `decode_header_line_with_offsets` split on runs of
+//   whitespace, ignoring any at the start or end of the line. Every header in
+//   the world format (world, metadata, component array, global component, and
+//   entity array headers) is read this way, since the names in them can't be
+//   empty or contain whitespace.
+// - `decode_header_line_exact` splits on each single space, so that empty
+//   fields and the exact spacing are kept. This is for formats built on this
+//   decoder whose fields are positional and may be empty.
+#[cfg(feature = "std")]
+impl<R: Read> State<R> {
     pub fn decode_header_line(&mut self, ex: &'static str) -> Result<Vec<String>, Error> {
-        let mut line = String::new();
+        let fields = self.decode_header_line_with_offsets(ex)?;
+        Ok(fields.into_iter().map(|(field, _)| field).collect())
+    }
+
+    // Decode a header line, returning each field along with the index of the
+    // byte it starts at, so that errors about a field can point at it.
+    pub fn decode_header_line_with_offsets(
+        &mut self,
+        ex: &'static str,
+    ) -> Result<Vec<(String, usize)>, Error> {
+        let (line, start) = self.read_header_line(ex)?;
+        Ok(split_header_line(&line, start))
+    }
+
+    // Decode a header line, splitting it on every space, so that consecutive
+    // spaces (or ones at the start or end of the line) delimit empty fields.
+    // An empty line has a single empty field. Other whitespace is kept as part
+    // of the fields, apart from the line ending.
+    pub fn decode_header_line_exact(
+        &mut self,
+        ex: &'static str,
+    ) -> Result<Vec<(String, usize)>, Error> {
+        let (line, start) = self.read_header_line(ex)?;
+        let mut fields = Vec::new();
+        let mut field_start = 0;
+        for field in line.split(' ') {
+            fields.push((field.to_string(), start + field_start));
+            field_start += field.len() + 1;
+        }
+        Ok(fields)
+    }
+
+    // Read the next header line which isn't a comment, without its line ending,
+    // returning it along with the index of its first byte. A line that was put
+    // back with `unread_header_line` is returned again first.
+    pub(crate) fn read_header_line(&mut self, ex: &'static str) -> Result<(String, usize), Error> {
+        if let Some(pending) = self.pending_header.take() {
+            return Ok(pending);
+        }
         loop {
-            let byte = self.next(ex)?;
-            if byte == b'\n' {
-                break;
-            } else if byte.is_ascii() {
-                line.push(byte as char);
-            } else {
-                return Err(self.err_unexpected(
-                    ex,
-                    format!("non-ASCII byte: {}", ascii::escape_default(byte)),
-                ))
+            let start = self.idx;
+            let mut line = String::new();
+            loop {
+                let byte = self.next(ex)?;
+                if byte == b'\n' {
+                    break;
+                } else if byte.is_ascii() || line.starts_with('#') {
+                    line.push(byte as char);
+                } else {
+                    return Err(self.err_unexpected(
+                        ErrorKind::MalformedHeader,
+                        ex,
+                        format!("non-ASCII byte: {}", ascii::escape_default(byte)),
+                    ))
+                }
+            }
+            // lines beginning with `#` are comments
+            if line.starts_with('#') {
+                continue;
+            }
+            // tolerate CRLF line endings
+            if line.ends_with('\r') {
+                line.pop();
+            }
+            return Ok((line, start));
+        }
+    }
+
+    // Make a line that was read with `read_header_line` the next one it
+    // returns, so that it can be decoded as part of the next section of the
+    // input. The line is kept whole, so it can still be split either way.
+    pub(crate) fn unread_header_line(&mut self, line: String, start: usize) {
+        debug_assert!(self.pending_header.is_none());
+        self.pending_header = Some((line, start));
+    }
+}
+
+// Split a header line which starts at index `start` of the input on runs of
+// whitespace, returning each field along with the index of its first byte.
+#[cfg(feature = "std")]
+pub(crate) fn split_header_line(line: &str, start: usize) -> Vec<(String, usize)> {
+    // header lines are ASCII, so byte offsets within the line are char offsets
+    let mut fields = Vec::new();
+    let mut field_start = None;
+    for (i, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+        match (field_start, c.is_whitespace()) {
+            (None, false) => field_start = Some(i),
+            (Some(j), true) => {
+                fields.push((line[j..i].to_string(), start + j));
+                field_start = None;
             }
+            _ => {}
         }
-        Ok(line.split_whitespace().map(String::from).collect())
     }
+    fields
 }
@@ -1,19 +1,172 @@
+#[cfg(feature = "std")]
 use std::fmt::Display;
-use std::io::{self, Write};
+use std::io;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use crate::codec::StreamError;
+
+// A writer that discards its input, keeping track only of its length.
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub struct Counter(pub usize);
+
+#[cfg(feature = "std")]
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The default limit on how deeply values may be nested when encoding or decoding.
+// This is kept fairly low so that unoptimized builds don't overflow the stack of
+// threads with a small (2 MiB) stack before reaching it.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+macro_rules! declare_encode_primitive_le {
+    ($name:ident, $t:ty) => {
+        pub fn $name(&mut self, x: $t) -> io::Result<()> {
+            self.write(&x.to_le_bytes())
+        }
+    };
+}
+
+// How `Value::Float`s are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FloatPolicy {
+    // Use 32 bits if the value can be represented exactly, and 64 otherwise.
+    #[default]
+    Auto,
+    // Always use 32 bits. This is lossy: values are rounded to the nearest `f32`.
+    AlwaysF32,
+    // Always use 64 bits, so the output doesn't depend on the values themselves.
+    AlwaysF64,
+}
 
 pub struct State<W> {
     out: W,
+    pub(crate) depth: usize,
+    pub(crate) max_depth: usize,
+    pub(crate) float_policy: FloatPolicy,
+    pub(crate) sparse: bool,
+    #[cfg(feature = "compression")]
+    pub(crate) compress: bool,
 }
 
-impl<W: Write> State<W> {
+// A destination for the bytes written by `State`. With the `std` feature,
+// this is implemented for every `Write`, but it can also be implemented
+// directly for outputs that don't go through `std::io`. Only values can be
+// encoded to a plain `Sink`: headers, and the sections of a world that begin
+// with them, need a `Write`.
+pub trait Sink {
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), StreamError>;
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Sink for W {
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), StreamError> {
+        self.write_all(buf).map_err(StreamError::Io)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sink for Vec<u8> {
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), StreamError> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl<W: Sink> State<W> {
     pub fn new(out: W) -> State<W> {
-        Self { out }
+        Self::with_max_depth(out, DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(out: W, max_depth: usize) -> State<W> {
+        Self {
+            out,
+            depth: 0,
+            max_depth,
+            float_policy: FloatPolicy::default(),
+            sparse: false,
+            #[cfg(feature = "compression")]
+            compress: false,
+        }
+    }
+
+    /// Compress the values of each component array written by this state,
+    /// whenever doing so makes the array smaller. Disabled by default.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Write each component array as `COMPONENT-SPARSE`, storing only the fields
+    /// that differ from its most common row, whenever doing so makes the array
+    /// smaller. Finding that row takes an extra pass over the values, so this is
+    /// disabled by default.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+
+    pub fn set_float_policy(&mut self, policy: FloatPolicy) {
+        self.float_policy = policy;
+    }
+
+    // A fresh state writing to `out` with the same settings as this one, for
+    // encoding part of the output somewhere else first.
+    pub(crate) fn with_settings_of<O: Sink>(&self, out: O) -> State<O> {
+        State {
+            out,
+            depth: 0,
+            max_depth: self.max_depth,
+            float_policy: self.float_policy,
+            sparse: self.sparse,
+            #[cfg(feature = "compression")]
+            compress: self.compress,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.out.write_all(buf)
+        Ok(self.out.write_bytes(buf)?)
     }
 
+    // Little-endian primitives. The world format itself is always big-endian:
+    // these are intended for custom encoders of foreign data embedded in
+    // `Value::Bytes` blobs.
+
+    declare_encode_primitive_le!(encode_u16_le, u16);
+    declare_encode_primitive_le!(encode_i16_le, i16);
+
+    declare_encode_primitive_le!(encode_u32_le, u32);
+    declare_encode_primitive_le!(encode_i32_le, i32);
+
+    declare_encode_primitive_le!(encode_u64_le, u64);
+    declare_encode_primitive_le!(encode_i64_le, i64);
+
+    declare_encode_primitive_le!(encode_f32_le, f32);
+    declare_encode_primitive_le!(encode_f64_le, f64);
+}
+
+impl State<Vec<u8>> {
+    // A state writing to a new vector with room for `capacity` bytes, so that
+    // encoding up to that many doesn't reallocate it.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::new(Vec::with_capacity(capacity))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> State<W> {
     pub fn write_fmt<T: Display>(&mut self, t: T) -> io::Result<()> {
         write!(self.out, "{}", t)
     }
@@ -4,6 +4,9 @@ use std::io;
 use super::encode;
 use super::decode;
 
+use super::component::ComponentId;
+use super::value::EntityId;
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub(crate) struct ComponentIdx {
     // identifies the type of component
@@ -12,12 +15,72 @@ pub(crate) struct ComponentIdx {
     pub(crate) idx: u32,
 }
 
-pub(crate) struct EntityData {
+// What holds a component value that refers to an entity, as reported by
+// `WorldContext::referrers` and `all_entity_references`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Owner {
+    Global,
+    Entity(EntityId),
+}
+
+// The components of an entity. These can't be constructed directly: entities
+// are created with `WorldBuilder::spawn` or `WorldContext::spawn`, which store
+// the component values and keep the indices into the arrays consistent.
+#[derive(Clone)]
+pub struct EntityData {
     pub(crate) is_deleted: bool,
+    // Kept sorted by component ID once the entity is part of a world, so that
+    // `component_idx` can binary search it. Anything adding components to an
+    // entity should go through `insert_component`.
     pub(crate) components: Vec<ComponentIdx>,
 }
 
-pub(crate) struct EntityArray {
+impl EntityData {
+    // Whether the entity has been deleted (and not yet dropped by encoding the world).
+    pub fn is_deleted(&self) -> bool {
+        self.is_deleted
+    }
+
+    // The IDs of the component types attached to this entity.
+    pub fn component_ids(&self) -> impl Iterator<Item = ComponentId> + '_ {
+        self.components.iter().map(|comp_idx| ComponentId(comp_idx.id))
+    }
+
+    // The position in `components` of the component with the given ID.
+    pub(crate) fn component_idx(&self, id: u16) -> Option<usize> {
+        self.components.binary_search_by_key(&id, |comp_idx| comp_idx.id).ok()
+    }
+
+    // Whether the entity has every component in `sorted_ids`, which must be
+    // sorted in increasing order without duplicates. Both lists are sorted, so they can be merged
+    // in a single pass rather than searching for each ID.
+    pub(crate) fn has_all_sorted(&self, sorted_ids: &[u16]) -> bool {
+        let mut components = self.components.iter().map(|comp_idx| comp_idx.id);
+        'ids: for &id in sorted_ids {
+            for comp_id in &mut components {
+                if comp_id == id {
+                    continue 'ids;
+                }
+                if comp_id > id {
+                    return false;
+                }
+            }
+            return false;
+        }
+        true
+    }
+
+    // Add a component, keeping the components sorted by ID.
+    pub(crate) fn insert_component(&mut self, comp_idx: ComponentIdx) {
+        let pos = self.components.partition_point(|c| c.id <= comp_idx.id);
+        self.components.insert(pos, comp_idx);
+    }
+}
+
+// The entities of a world, indexed by `EntityId`. This is only handed out by
+// `WorldContext::into_parts`, to be edited and passed back to `from_parts`.
+#[derive(Clone)]
+pub struct EntityArray {
     pub(crate) entries: Vec<EntityData>,
 }
 
@@ -28,11 +91,32 @@ impl EntityArray {
         }
     }
 
+    // The entities, including deleted ones, so that an entity's position is its ID.
+    pub fn entries(&self) -> &[EntityData] {
+        &self.entries
+    }
+
+    // The entities, which can be reordered, removed or duplicated. Note that
+    // doing so changes the IDs of the entities, so any `EntityId`s referring
+    // to them must be updated to match.
+    pub fn entries_mut(&mut self) -> &mut Vec<EntityData> {
+        &mut self.entries
+    }
+
     // Compute the packed indices of the entries: this vector
     // contains `None` at indices corresponding to deleted
     // entities, and contains `Some(i)` everywhere else,
     // with the `i` values forming an increasing sequence.
-    pub fn packed_idxs(&self) -> Vec<Option<u32>> {
+    //
+    // If no entities are deleted, every index maps to itself,
+    // so return `None` instead of allocating the vector. IDs
+    // past the end of the entries must still be treated as
+    // `Invalid`, as they are when the vector is returned.
+    pub fn packed_idxs(&self) -> Option<Vec<Option<u32>>> {
+        if !self.entries.iter().any(|e| e.is_deleted) {
+            return None;
+        }
+
         let mut idxs = Vec::with_capacity(self.entries.len());
         let mut i = 0;
         for entry in &self.entries {
@@ -43,10 +127,23 @@ impl EntityArray {
                 i += 1;
             }
         }
-        idxs
+        Some(idxs)
     }
 }
 
+// In place of a component count, marks an entity as deleted.
+const TOMBSTONE: u8 = 0xfe;
+
+// The entity count is written as a decimal string in the header, but
+// `decode_entity_array` parses it as a `u32` (since `EntityId` can only
+// address that many entities), so refuse to write anything larger.
+pub(crate) fn entity_count(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("too many entities to encode ({})", len),
+    ))
+}
+
 impl<R: io::Read> decode::State<R> {
     pub(crate) fn decode_component_idx(&mut self) -> Result<ComponentIdx, decode::Error> {
         let b = self.next("component index")?;
@@ -55,16 +152,17 @@ impl<R: io::Read> decode::State<R> {
             0x40 ..= 0x7f => ((b - 0x40) as u16, self.decode_u16()? as u32),
             0x80 => (self.decode_u8()? as u16, self.decode_u8()? as u32),
             0x81 => (self.decode_u8()? as u16, self.decode_u16()? as u32),
-            0x82 => (self.decode_u8()? as u16, self.decode_u24()? as u32),
+            0x82 => (self.decode_u8()? as u16, self.decode_u24()?),
             0x83 => (self.decode_u8()? as u16, self.decode_u32()?),
             0x84 => (self.decode_u16()?, self.decode_u8()? as u32),
             0x85 => (self.decode_u16()?, self.decode_u16()? as u32),
-            0x86 => (self.decode_u16()?, self.decode_u24()? as u32),
+            0x86 => (self.decode_u16()?, self.decode_u24()?),
             0x87 => (self.decode_u16()?, self.decode_u32()?),
             0x88 => (self.decode_u8()? as u16, 0),
-            0x89 => (self.decode_u16()? as u16, 0),
+            0x89 => (self.decode_u16()?, 0),
 
             0x8a ..= 0xbf => return Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
                 "component index",
                 format!("invalid byte ({:?})", b),
             )),
@@ -76,6 +174,9 @@ impl<R: io::Read> decode::State<R> {
 
     pub(crate) fn decode_entity_data(&mut self) -> Result<EntityData, decode::Error> {
         let b = self.next("component index count")?;
+        if b == TOMBSTONE && self.version() >= 2 {
+            return Ok(EntityData { is_deleted: true, components: Vec::new() });
+        }
         let num_comp_idxs = if b == 0xff { self.decode_u16()? } else { b as u16 };
 
         let mut components = Vec::with_capacity(num_comp_idxs as usize);
@@ -86,11 +187,37 @@ impl<R: io::Read> decode::State<R> {
         Ok(EntityData { is_deleted: false, components })
     }
 
-    pub(crate) fn decode_entity_array(&mut self) -> Result<EntityArray, decode::Error> {
+    // Decode an entity array, calling `progress` with the number of bytes
+    // consumed so far after each batch of `PROGRESS_INTERVAL` entities.
+    pub(crate) fn decode_entity_array(
+        &mut self,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<EntityArray, decode::Error> {
+        const PROGRESS_INTERVAL: u32 = 1024;
+
+        let mut iter = self.entity_array_iter()?;
+        let mut entries = Vec::with_capacity(decode::capacity_hint(iter.remaining as usize));
+        let mut i = 0;
+        while let Some(entity) = iter.next() {
+            entries.push(entity?);
+            i += 1;
+            if i % PROGRESS_INTERVAL == 0 {
+                progress(iter.state.idx());
+            }
+        }
+
+        Ok(EntityArray { entries })
+    }
+
+    // Decode the header of an entity array, then return an iterator which
+    // decodes its entities one at a time, for passes over worlds too large to
+    // hold every entity in memory at once.
+    pub fn entity_array_iter(&mut self) -> Result<EntityArrayIter<'_, R>, decode::Error> {
         let header = self.decode_header_line("entity array header")?;
 
         if header.len() != 2 {
             return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
                 "entity array header with two fields",
                 format!("{} fields", header.len())
             ));
@@ -99,6 +226,7 @@ impl<R: io::Read> decode::State<R> {
         let signature = &header[0];
         if signature != "ENTITIES" {
             return Err(self.err_unexpected(
+                decode::ErrorKind::BadSignature,
                 "entity array signature (ENTITIES)",
                 format!("invalid signature: {:?}", signature),
             ));
@@ -107,17 +235,47 @@ impl<R: io::Read> decode::State<R> {
         let num_entities = match header[1].parse::<u32>() {
             Ok(n) => n,
             Err(_) => return Err(self.err_unexpected(
+                decode::ErrorKind::InvalidNumber,
                 "32-bit entity count",
-                "invalid entity count",
+                format!("invalid entity count: {:?}", header[1]),
             )),
         };
 
-        let mut entries = Vec::with_capacity(num_entities as usize);
-        for _ in 0..num_entities {
-            entries.push(self.decode_entity_data()?);
+        Ok(EntityArrayIter { state: self, remaining: num_entities })
+    }
+}
+
+// Decodes the entities of an entity array in order. After an error is
+// returned, the iterator yields nothing more.
+pub struct EntityArrayIter<'a, R: io::Read> {
+    state: &'a mut decode::State<R>,
+    pub(crate) remaining: u32,
+}
+
+impl<'a, R: io::Read> Iterator for EntityArrayIter<'a, R> {
+    type Item = Result<EntityData, decode::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
         }
+        self.remaining -= 1;
 
-        Ok(EntityArray { entries })
+        match self.state.decode_entity_data() {
+            Ok(mut entity) => {
+                // files aren't required to list components in order
+                entity.components.sort_by_key(|comp_idx| comp_idx.id);
+                Some(Ok(entity))
+            }
+            Err(err) => {
+                self.remaining = 0;
+                Some(Err(err))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
     }
 }
 
@@ -173,7 +331,7 @@ impl<W: io::Write> encode::State<W> {
         use IdxScale as IdxS;
 
         let id_s = IdS::from_id(comp_idx.id);
-        let idx_s = IdxS::from_idx(comp_idx.idx as u32);
+        let idx_s = IdxS::from_idx(comp_idx.idx);
 
         /// Helper macro to call `self.write()` on the big-endian encoding of an integer.
         macro_rules! wi {
@@ -210,9 +368,14 @@ impl<W: io::Write> encode::State<W> {
         Ok(())
     }
 
+    // Deleted entities are written as a tombstone.
     pub(crate) fn encode_entity_data(&mut self, data: &EntityData) -> io::Result<()> {
+        if data.is_deleted {
+            return self.write(&[TOMBSTONE]);
+        }
+
         let len = data.components.len();
-        if len < 0xff {
+        if len < TOMBSTONE as usize {
             self.write(&[len as u8])?
         } else {
             debug_assert!(len < 0x10000, "entity cannot have >u16 components");
@@ -234,7 +397,7 @@ impl<W: io::Write> encode::State<W> {
     // the entity IDs with their packed versions.
     pub(crate) fn encode_entity_array(&mut self, array: &EntityArray) -> io::Result<()> {
         let filtered = array.entries.iter().filter(|e| !e.is_deleted);
-        let len = filtered.clone().count();
+        let len = entity_count(filtered.clone().count())?;
         self.write_fmt(format_args!("ENTITIES {}\n", len))?;
         
         for entry in filtered {
@@ -1,9 +1,53 @@
 use std::io;
 
-pub use super::decode::Error as DecodeError;
+#[cfg(feature = "std")]
+use super::component::ComponentId;
+
+pub use super::decode::{Error as DecodeError, ErrorKind as DecodeErrorKind};
 
 #[derive(Debug)]
 pub enum Error {
     Decode(DecodeError),
     Encode(io::Error),
 }
+
+#[cfg(feature = "std")]
+// An error encountered when validating a world constructed with a `WorldBuilder`,
+// an entity or component added with `WorldContext::spawn` or `add_component`,
+// a world assembled with `WorldContext::from_parts`, a patch passed to
+// `WorldContext::apply_patch`, or one computed by `WorldContext::diff`.
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    // A component, field or metadata name is empty, or contains whitespace or
+    // non-ASCII characters.
+    InvalidName(String),
+    // A metadata value contains a line break or non-ASCII characters.
+    InvalidMetadataValue(String),
+    DuplicateComponentId(ComponentId),
+    // A component array was added to a world that already uses every component ID.
+    ComponentIdsExhausted,
+    DuplicateComponentName(String),
+    DuplicateField(String),
+    // The global component was given a different number of values than its scheme has fields.
+    GlobalValueCount { expected: usize, got: usize },
+    // An entity was spawned with a component whose ID was never declared, or
+    // `WorldContext::check_component_refs` found an entity referring to one.
+    UnknownComponent(ComponentId),
+    // An entity was spawned with a component with the wrong number of values.
+    ValueCount { id: ComponentId, expected: usize, got: usize },
+    // A value refers to an entity that was never spawned, or a component was
+    // added to an entity that doesn't exist. `Invalid` is reported as `u32::MAX`.
+    InvalidEntityId(u32),
+    // A component was added to an entity that already has one with the same ID.
+    ComponentAlreadyPresent { entity: u32, id: ComponentId },
+    // An entity passed to `WorldContext::from_parts`, or checked with
+    // `WorldContext::check_component_refs`, refers to a component that its
+    // array doesn't contain.
+    MissingComponent { id: ComponentId, idx: u32 },
+    // A patch sets a field that the component does not have. The ID is `None`
+    // for the global component.
+    InvalidField { id: Option<ComponentId>, field: u16 },
+    // A diff found a changed field whose index is too large to record in a
+    // patch. The ID is `None` for the global component.
+    TooManyFields { id: Option<ComponentId>, count: usize },
+}
@@ -0,0 +1,31 @@
+use crate::decode;
+use crate::encode;
+
+/// An entry point for fuzzing the decoder.
+///
+/// Decodes `data` as a world, and if that succeeds, checks that encoding
+/// the world and decoding it again round-trips. No input should cause
+/// this to panic, except by violating that property.
+pub fn fuzz_decode(data: &[u8]) {
+    let world = match decode::State::new(data).decode_world() {
+        Ok(world) => world,
+        Err(_) => return,
+    };
+
+    let mut encoded = Vec::new();
+    encode::State::new(&mut encoded)
+        .encode_world(&world)
+        .expect("failed to encode a decoded world");
+
+    let world = decode::State::new(&encoded[..])
+        .decode_world()
+        .expect("failed to decode a re-encoded world");
+
+    // compare the encodings rather than the worlds, since NaN floats
+    // don't compare equal to themselves
+    let mut re_encoded = Vec::new();
+    encode::State::new(&mut re_encoded)
+        .encode_world(&world)
+        .expect("failed to encode a decoded world");
+    assert_eq!(encoded, re_encoded, "world did not round-trip");
+}
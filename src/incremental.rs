@@ -0,0 +1,175 @@
+use std::io;
+
+use super::decode;
+use super::encode;
+use super::error;
+
+use super::component::{ComponentArray, ComponentId};
+use super::value::EntityId;
+use super::world::{check_entity_id, pack_entity_id, WorldData};
+
+impl WorldData {
+    /// The IDs of the component arrays that may have been modified since the
+    /// world was decoded, or since `clear_dirty` was last called.
+    pub fn dirty_component_ids(&self) -> impl Iterator<Item = ComponentId> + '_ {
+        self.components.values()
+            .filter(|array| array.is_dirty())
+            .map(ComponentArray::id)
+    }
+
+    /// Mark every component array as unmodified, for example after saving.
+    pub fn clear_dirty(&mut self) {
+        for array in self.components.values_mut() {
+            array.clear_dirty();
+        }
+    }
+
+    /// Encode only the component arrays which have been modified, to be merged
+    /// into a saved copy of the world with `apply_incremental`.
+    ///
+    /// The global component and the entity array are not included, so changes
+    /// to which entities exist or which components they have (such as those
+    /// made by `retain_entities`) still require the whole world to be saved.
+    pub fn encode_dirty<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        encode::State::new(writer).encode_dirty_components(self)
+    }
+
+    /// Replace component arrays of this world with the ones written by
+    /// `encode_dirty`. Each array must have the same name and scheme as the one
+    /// it replaces, must still contain every component the entities of this
+    /// world refer to, and may only refer to entities this world has. The input
+    /// is validated before anything is changed, so if an error is returned the
+    /// world is left as it was.
+    pub fn apply_incremental<R: io::Read>(&mut self, reader: R) -> Result<(), error::DecodeError> {
+        let mut state = decode::State::new(reader);
+        let arrays = state.decode_dirty_components()?;
+        state.expect_eof()?;
+
+        for array in &arrays {
+            if let Err(got) = self.check_replacement(array) {
+                return Err(state.err_unexpected(
+                    decode::ErrorKind::MismatchedComponent,
+                    format!("component array matching the one with ID {}", array.id()),
+                    got,
+                ));
+            }
+        }
+
+        for array in arrays {
+            self.components.insert(array.id().0 as usize, array);
+        }
+        Ok(())
+    }
+
+    // Check that `array` can take the place of the array with the same ID.
+    fn check_replacement(&self, array: &ComponentArray) -> Result<(), String> {
+        let old = match self.components.get(array.id().0 as usize) {
+            Some(old) => old,
+            None => return Err("nonexistent component array".to_string()),
+        };
+        if old.name() != array.name() {
+            return Err(format!("different name: {:?}", array.name()));
+        }
+        if old.scheme() != array.scheme() {
+            return Err("different scheme".to_string());
+        }
+
+        let missing = self.entities.entries.iter()
+            .filter(|entity| !entity.is_deleted)
+            .flat_map(|entity| &entity.components)
+            .filter(|comp_idx| comp_idx.id == array.id().0)
+            .find(|comp_idx| array.get(comp_idx.idx).is_none());
+        if let Some(comp_idx) = missing {
+            return Err(format!("no component at index {}", comp_idx.idx));
+        }
+
+        // `encode_dirty` writes references to entities past the end of the
+        // entity array as `Invalid`, so any left are from a different world
+        let num_entities = self.entities.entries.len();
+        let mut out_of_range = None;
+        for value in array.values() {
+            value.visit_entity_ids(&mut |id| if check_entity_id(num_entities, id) != id {
+                out_of_range = out_of_range.or(Some(id));
+            });
+        }
+        match out_of_range {
+            Some(id) => Err(format!("reference to nonexistent entity {:?}", id)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: io::Read> decode::State<R> {
+    // Decode the component arrays written by `encode_dirty_components`.
+    pub fn decode_dirty_components(&mut self) -> Result<Vec<ComponentArray>, decode::Error> {
+        let header = self.decode_header_line("dirty component header")?;
+
+        let signature = header.first().map(String::as_str);
+        if signature != Some("DIRTY") {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::BadSignature,
+                "dirty component signature (DIRTY)",
+                format!("invalid signature: {:?}", signature.unwrap_or("")),
+            ));
+        }
+
+        let mut ids = Vec::with_capacity(header.len() - 1);
+        for field in &header[1..] {
+            match field.parse::<u16>() {
+                Ok(id) => ids.push(id),
+                Err(_) => return Err(self.err_unexpected(
+                    decode::ErrorKind::InvalidNumber,
+                    "16-bit component ID",
+                    "invalid component ID",
+                )),
+            }
+        }
+
+        let mut arrays = Vec::with_capacity(ids.len());
+        for id in ids {
+            let array = self.decode_component_array()?;
+            if array.id().0 != id {
+                return Err(self.err_unexpected(
+                    decode::ErrorKind::MismatchedComponent,
+                    format!("component array with ID {}", id),
+                    format!("ID {}", array.id()),
+                ));
+            }
+            self.expect_newline()?;
+            arrays.push(array);
+        }
+
+        Ok(arrays)
+    }
+}
+
+impl<W: io::Write> encode::State<W> {
+    // Encode the dirty component arrays of a world, preceded by a header listing
+    // their IDs. `EntityId`s are written the same way `encode_world` writes them.
+    pub fn encode_dirty_components(&mut self, world: &WorldData) -> io::Result<()> {
+        let dirty: Vec<&ComponentArray> = world.components.values()
+            .filter(|array| array.is_dirty())
+            .collect();
+
+        self.write(b"DIRTY")?;
+        for array in &dirty {
+            self.write_fmt(format_args!(" {}", array.id()))?;
+        }
+        self.write(b"\n")?;
+
+        let packed_idxs = world.entities.packed_idxs();
+        let num_entities = world.entities.entries.len();
+        let mut transform_id = |id: &mut EntityId| {
+            *id = match &packed_idxs {
+                Some(packed_idxs) => pack_entity_id(packed_idxs, *id),
+                None => check_entity_id(num_entities, *id),
+            };
+        };
+        for array in dirty {
+            self.encode_component_array(array, &mut transform_id)?;
+            self.write(b"\n")?;
+        }
+
+        Ok(())
+    }
+}
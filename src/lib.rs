@@ -1,19 +1,48 @@
+// Without `std`, much of the crate's internals are only reachable through the
+// world codec, which is left out.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+#[cfg(feature = "std")]
 pub use rlua;
+#[cfg(feature = "std")]
+pub use vec_map;
 
 mod decode;
 mod encode;
 
+// Everything apart from the value codec reads and writes through `std::io`.
+#[cfg(feature = "std")]
 mod entity;
+#[cfg(feature = "std")]
 mod world;
+#[cfg(feature = "std")]
+mod patch;
+#[cfg(feature = "std")]
+mod incremental;
 
+#[cfg(feature = "std")]
 mod lua;
 
 pub mod error;
 pub mod value;
+pub mod tags;
+#[cfg(feature = "std")]
 pub mod component;
+pub mod codec;
+
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 
-pub use world::WorldData as WorldContext;
+#[cfg(feature = "std")]
+pub use world::{WorldData as WorldContext, WorldBuilder, WorldSnapshot, decode_world_lenient, transcode_world};
+#[cfg(feature = "std")]
 pub use lua::World;
+#[cfg(feature = "std")]
+pub use patch::WorldPatch;
+#[cfg(feature = "std")]
+pub use entity::{EntityData, EntityArray, EntityArrayIter, Owner};
+#[cfg(feature = "std")]
+pub use component::ComponentId;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test;
@@ -1,18 +1,22 @@
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use rlua::{Lua, RegistryKey};
+use rlua::{Context, FromLua, Lua, RegistryKey, ToLua};
 
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
+use std::path::Path;
 use std::sync::Arc;
 
 use crate::decode;
 use crate::encode;
 use crate::error;
+use crate::value::{EntityId, Value};
 use crate::WorldContext;
 
 mod script;
-use script::{System, Query};
+use script::{System, SystemHooks, Query};
 
+#[allow(unused_imports)]
 pub use script::ScriptType;
 
 #[derive(Default, Clone)]
@@ -23,22 +27,162 @@ pub struct World<ID, Q> {
     ctx_ref_key: RegistryKey,
 
     systems: HashMap<ID, System>,
+    // the number of Lua systems registered so far, used to order them when saved
+    system_seq: u64,
+    system_hooks: Option<SystemHooks<ID>>,
     queries: HashMap<ID, Query<Q>>,
 
     ctx_ref: ContextRef,
 }
 
+impl<ID, Q> fmt::Debug for World<ID, Q> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("World")
+            .field("context", &*self.ctx_ref.read())
+            .field("systems", &self.systems.len())
+            .field("queries", &self.queries.len())
+            .finish()
+    }
+}
+
+// Only the world data is compared, not the registered systems and queries.
+impl<ID, Q> PartialEq for World<ID, Q> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.ctx_ref.0, &other.ctx_ref.0)
+            || *self.ctx_ref.read() == *other.ctx_ref.read()
+    }
+}
+
 impl ContextRef {
-    fn read(&self) -> RwLockReadGuard<WorldContext> {
+    fn read(&self) -> RwLockReadGuard<'_, WorldContext> {
         self.0.read()
     }
 
-    fn write(&self) -> RwLockWriteGuard<WorldContext> {
+    fn write(&self) -> RwLockWriteGuard<'_, WorldContext> {
         self.0.write()
     }
 }
 
-impl rlua::UserData for ContextRef {}
+// Scripts are passed the world context as their argument, with these methods:
+//
+// - `ctx:global(field)` returns a field of the global component, or `nil` if
+//   there is no such field.
+// - `ctx:set_global(field, value)` sets a field of the global component, and
+//   raises an error if there is no such field.
+impl rlua::UserData for ContextRef {
+    fn add_methods<'lua, M: rlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("global", |ctx, this, field: String| {
+            let world = this.read();
+            match world.global.get().field(&field) {
+                Some(value) => value.clone().to_lua(ctx),
+                None => Ok(rlua::Value::Nil),
+            }
+        });
+
+        methods.add_method("set_global", |_, this, (field, value): (String, Value)| {
+            let mut world = this.write();
+            match world.global.get_mut().set_field(&field, value) {
+                Some(_) => Ok(()),
+                None => Err(rlua::Error::RuntimeError(
+                    format!("no such field of the global component: {:?}", field),
+                )),
+            }
+        });
+    }
+}
+
+// Entity IDs are passed to Lua as integers. Invalid IDs (such as references to
+// entities that have been deleted) become `nil`, so scripts can test for them
+// without needing to handle errors.
+impl<'lua> ToLua<'lua> for EntityId {
+    fn to_lua(self, ctx: Context<'lua>) -> rlua::Result<rlua::Value<'lua>> {
+        match self {
+            EntityId::Idx(idx) => idx.to_lua(ctx),
+            EntityId::Invalid => Ok(rlua::Value::Nil),
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for EntityId {
+    fn from_lua(value: rlua::Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self> {
+        match value {
+            rlua::Value::Nil => Ok(EntityId::Invalid),
+            value => Ok(EntityId::Idx(u32::from_lua(value, ctx)?)),
+        }
+    }
+}
+
+// Values are passed to Lua as the closest Lua type: decimals become numbers,
+// byte strings become strings, arrays become sequence tables, and empty
+// `Maybe`s become `nil`.
+impl<'lua> ToLua<'lua> for Value {
+    fn to_lua(self, ctx: Context<'lua>) -> rlua::Result<rlua::Value<'lua>> {
+        match self {
+            Value::Bool(b) => b.to_lua(ctx),
+            Value::Int(i) => i.to_lua(ctx),
+            Value::Float(x) => x.to_lua(ctx),
+            Value::Decimal { mantissa, scale } => {
+                (mantissa as f64 / 10f64.powi(scale as i32)).to_lua(ctx)
+            }
+            Value::Bytes(bs) => ctx.create_string(&bs).map(rlua::Value::String),
+            Value::Str(s) => s.to_lua(ctx),
+            Value::Array(vs) => ctx.create_sequence_from(vs).map(rlua::Value::Table),
+            Value::IntArray(is) => ctx.create_sequence_from(is).map(rlua::Value::Table),
+            Value::Maybe(None) => Ok(rlua::Value::Nil),
+            Value::Maybe(Some(v)) => v.to_lua(ctx),
+            Value::EntityId(id) => id.to_lua(ctx),
+        }
+    }
+}
+
+// Lua values are converted as follows: `nil` becomes an empty `Maybe`, strings
+// become `Str` (or `Bytes` if they aren't valid UTF-8), and tables become arrays
+// of their sequence elements. Since entity IDs are passed to Lua as integers,
+// they come back as `Int`s.
+impl<'lua> FromLua<'lua> for Value {
+    fn from_lua(value: rlua::Value<'lua>, _ctx: Context<'lua>) -> rlua::Result<Self> {
+        value_from_lua(value)
+    }
+}
+
+fn value_from_lua(value: rlua::Value) -> rlua::Result<Value> {
+    value_from_lua_within(value, encode::DEFAULT_MAX_DEPTH)
+}
+
+// Tables may contain themselves, so the nesting depth is capped the same way
+// the encoder caps it rather than recursing until the stack overflows.
+fn value_from_lua_within(value: rlua::Value, depth: usize) -> rlua::Result<Value> {
+    Ok(match value {
+        rlua::Value::Nil => Value::Maybe(None),
+        rlua::Value::Boolean(b) => Value::Bool(b),
+        rlua::Value::Integer(i) => Value::Int(i),
+        rlua::Value::Number(x) => Value::Float(x),
+        rlua::Value::String(s) => match s.to_str() {
+            Ok(s) => Value::Str(s.to_string()),
+            Err(_) => Value::Bytes(s.as_bytes().to_vec()),
+        },
+        rlua::Value::Table(_) if depth == 0 => return Err(rlua::Error::FromLuaConversionError {
+            from: "table",
+            to: "Value",
+            message: Some("table is nested too deeply (or contains itself)".to_string()),
+        }),
+        rlua::Value::Table(table) => Value::Array(
+            table.sequence_values::<rlua::Value>()
+                .map(|value| value_from_lua_within(value?, depth - 1))
+                .collect::<rlua::Result<_>>()?,
+        ),
+        value => return Err(rlua::Error::FromLuaConversionError {
+            from: match value {
+                rlua::Value::Function(_) => "function",
+                rlua::Value::Thread(_) => "thread",
+                rlua::Value::Error(_) => "error",
+                _ => "userdata",
+            },
+            to: "Value",
+            message: None,
+        }),
+    })
+}
 
 impl<ID, Q> World<ID, Q> {
     fn from_ctx_ref_with_lua(
@@ -53,12 +197,15 @@ impl<ID, Q> World<ID, Q> {
             ctx_ref_key,
 
             systems: HashMap::new(),
+            system_seq: 0,
+            system_hooks: None,
             queries: HashMap::new(),
 
             ctx_ref,
         }
     }
 
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self::with_lua(Lua::new())
     }
@@ -67,6 +214,15 @@ impl<ID, Q> World<ID, Q> {
         Self::from_ctx_ref_with_lua(ContextRef::default(), lua)
     }
 
+    pub fn from_context(ctx: WorldContext) -> Self {
+        Self::from_context_with_lua(ctx, Lua::new())
+    }
+
+    pub fn from_context_with_lua(ctx: WorldContext, lua: Lua) -> Self {
+        let ctx_ref = ContextRef(Arc::new(RwLock::new(ctx)));
+        Self::from_ctx_ref_with_lua(ctx_ref, lua)
+    }
+
     pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::DecodeError> {
         Self::from_reader_with_lua(reader, Lua::new())
     }
@@ -76,9 +232,51 @@ impl<ID, Q> World<ID, Q> {
         lua: Lua
     ) -> Result<Self, error::DecodeError> {
         let ctx = decode::State::new(reader).decode_world()?;
-        let ctx_ref = ContextRef(Arc::new(RwLock::new(ctx)));
-        
-        Ok(Self::from_ctx_ref_with_lua(ctx_ref, lua))
+        Ok(Self::from_context_with_lua(ctx, lua))
+    }
+
+    // Decode a world, periodically calling `progress` with the number of bytes
+    // read so far. This can be used to display progress when loading large worlds.
+    pub fn from_reader_with_progress<R: io::Read>(
+        reader: R,
+        mut progress: impl FnMut(usize),
+    ) -> Result<Self, error::DecodeError> {
+        let ctx = decode::State::new(reader).decode_world_with_progress(&mut progress)?;
+        Ok(Self::from_context_with_lua(ctx, Lua::new()))
+    }
+
+    pub fn from_embedded_reader<R: io::Read>(reader: R) -> Result<Self, error::DecodeError> {
+        Self::from_embedded_reader_with_lua(reader, Lua::new())
+    }
+
+    pub fn from_embedded_reader_with_lua<R: io::Read>(
+        reader: R,
+        lua: Lua
+    ) -> Result<Self, error::DecodeError> {
+        let ctx = decode::State::new(reader).decode_embedded_world()?;
+        Ok(Self::from_context_with_lua(ctx, lua))
+    }
+
+    // Convert a table produced by Lua code into the values of a row of the named
+    // component, taking each field of the component's scheme from the table.
+    pub fn table_to_row<'lua>(
+        &self,
+        component: &str,
+        table: rlua::Table<'lua>,
+    ) -> rlua::Result<Vec<Value>> {
+        let ctx = self.ctx_ref.read();
+        let array = ctx.components.values()
+            .find(|array| array.name() == component)
+            .ok_or_else(|| rlua::Error::RuntimeError(format!("no such component: {:?}", component)))?;
+
+        array.scheme().iter()
+            .map(|field| match table.get::<_, rlua::Value>(&**field)? {
+                rlua::Value::Nil => Err(rlua::Error::RuntimeError(
+                    format!("missing field {:?} of component {:?}", field, component),
+                )),
+                value => value_from_lua(value),
+            })
+            .collect()
     }
 
     pub fn to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
@@ -86,4 +284,18 @@ impl<ID, Q> World<ID, Q> {
         encode::State::new(writer)
             .encode_world(&world)
     }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        self.ctx_ref.read().to_vec()
+    }
+
+    /// Decode a world from the file at `path`, which must contain nothing else.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, error::Error> {
+        Ok(Self::from_context(WorldContext::load(path)?))
+    }
+
+    /// Encode the world into the file at `path`, replacing it if it already exists.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), error::Error> {
+        self.ctx_ref.read().save(path)
+    }
 }
@@ -1,8 +1,16 @@
-use rlua::RegistryKey;
+use rlua::{FromLua, RegistryKey};
 
+use std::error::Error;
+use std::fs;
 use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 
 use super::{World, WorldContext};
+use crate::decode;
+use crate::encode;
+use crate::value::Value;
 
 pub enum ScriptType {
     Lua,
@@ -10,9 +18,27 @@ pub enum ScriptType {
     None,
 }
 
+// The error type of a fallible native system, once boxed.
+type SystemError = Box<dyn Error + Send + Sync>;
+
+type NativeSystem = Box<dyn FnMut(&mut WorldContext) -> Result<(), SystemError>>;
+
 pub enum System {
-    Lua(RegistryKey),
-    Native(Box<dyn FnMut(&mut WorldContext)>),
+    Lua(RegistryKey, LuaSource),
+    Native(NativeSystem),
+}
+
+// The source of a Lua system, kept so that it can be written by `save_scripts`.
+pub struct LuaSource {
+    name: Vec<u8>,
+    code: Vec<u8>,
+    // when the system was registered, so that systems are saved in that order
+    seq: u64,
+}
+
+pub struct SystemHooks<ID> {
+    before: Box<dyn Fn(&ID)>,
+    after: Box<dyn Fn(&ID)>,
 }
 
 pub enum Query<Q> {
@@ -23,7 +49,7 @@ pub enum Query<Q> {
 impl ScriptType {
     fn from_opt_system(sys: Option<&System>) -> Self {
         match sys {
-            Some(System::Lua(_)) => Self::Lua,
+            Some(System::Lua(..)) => Self::Lua,
             Some(System::Native(_)) => Self::Native,
             None => Self::None,
         }
@@ -53,37 +79,201 @@ impl<ID, Q> World<ID, Q> where ID: Hash + Eq {
             let system_fn: rlua::Function = ctx.load(code).set_name(name)?.eval()?;
             ctx.create_registry_value(system_fn)
         })?;
-        let old = self.systems.insert(id, System::Lua(key));
+        let source = LuaSource { name: name.to_vec(), code: code.to_vec(), seq: self.system_seq };
+        self.system_seq += 1;
+        let old = self.systems.insert(id, System::Lua(key, source));
         Ok(ScriptType::from_opt_system(old.as_ref()))
     }
 
+    // Write the names and code of the registered Lua systems, in the order they
+    // were registered, so that they can be registered again by `load_scripts`,
+    // for example alongside a saved world. `id_to_value` converts each system's
+    // ID to a value that can be written. Native systems and queries aren't saved.
+    //
+    // Each system is written as an array of its ID, name, and code.
+    pub fn save_scripts<W: io::Write>(
+        &self,
+        writer: W,
+        mut id_to_value: impl FnMut(&ID) -> Value,
+    ) -> io::Result<()> {
+        let mut sources: Vec<(&ID, &LuaSource)> = self.systems.iter()
+            .filter_map(|(id, system)| match system {
+                System::Lua(_, source) => Some((id, source)),
+                System::Native(_) => None,
+            })
+            .collect();
+        sources.sort_by_key(|(_, source)| source.seq);
+
+        let mut state = encode::State::new(writer);
+        for (id, source) in sources {
+            let entry = Value::Array(vec![
+                id_to_value(id),
+                Value::Bytes(source.name.clone()),
+                Value::Bytes(source.code.clone()),
+            ]);
+            state.encode_value(&entry, &mut |_| {})?;
+        }
+        Ok(())
+    }
+
+    // Register the Lua systems written by `save_scripts`, in the order they were
+    // saved, replacing any existing systems with the same IDs. `id_from_value`
+    // converts each saved ID back, returning `None` if it isn't valid. If an
+    // error occurs, the systems before it are left registered.
+    pub fn load_scripts<R: io::Read>(
+        &mut self,
+        reader: R,
+        mut id_from_value: impl FnMut(Value) -> Option<ID>,
+    ) -> rlua::Result<()> {
+        let malformed = || rlua::Error::RuntimeError("malformed saved system".to_string());
+
+        let mut state = decode::State::new(reader);
+        for entry in state.values() {
+            let mut fields = match entry.map_err(rlua::Error::external)? {
+                Value::Array(fields) if fields.len() == 3 => fields.into_iter(),
+                _ => return Err(malformed()),
+            };
+            let (id, name, code) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(Value::Bytes(name)), Some(Value::Bytes(code))) => (id, name, code),
+                _ => return Err(malformed()),
+            };
+            let id = match id_from_value(id.clone()) {
+                Some(id) => id,
+                None => return Err(rlua::Error::RuntimeError(format!("invalid saved system ID: {:?}", id))),
+            };
+            self.register_named_lua_system(id, &name, &code)?;
+        }
+        Ok(())
+    }
+
+    // Check that `code` compiles and evaluates to a function, without registering
+    // it. The code is run in a copy of the globals, so it can use the standard
+    // library, but assigning a global only sets it in the copy. Tables such as
+    // `string` and `math` are copied one level deep, so that replacing one of
+    // their functions doesn't change the real one either, but anything nested
+    // more deeply, or reached through a metatable, is shared.
+    pub fn validate_lua_system(&self, code: &[u8]) -> rlua::Result<()> {
+        self.lua.context(|ctx| {
+            let env = ctx.create_table()?;
+            for pair in ctx.globals().pairs::<rlua::Value, rlua::Value>() {
+                let (key, value) = pair?;
+                let value = match value {
+                    rlua::Value::Table(table) => {
+                        let copy = ctx.create_table()?;
+                        for pair in table.pairs::<rlua::Value, rlua::Value>() {
+                            let (key, value) = pair?;
+                            copy.raw_set(key, value)?;
+                        }
+                        rlua::Value::Table(copy)
+                    }
+                    value => value,
+                };
+                env.raw_set(key, value)?;
+            }
+            env.raw_set("_G", env.clone())?;
+            let _: rlua::Function = ctx.load(code)
+                .set_name("unnamed system")?
+                .set_environment(env)?
+                .eval()?;
+            Ok(())
+        })
+    }
+
     pub fn register_native_system(
         &mut self,
         id: ID,
-        func: impl FnMut(&mut WorldContext) + 'static,
+        mut func: impl FnMut(&mut WorldContext) + 'static,
     ) -> ScriptType {
+        self.register_fallible_native_system(id, move |ctx| {
+            func(ctx);
+            Ok::<(), SystemError>(())
+        })
+    }
+
+    // Register a native system that can fail. When it returns an error,
+    // `run_system` returns it as an `rlua::Error::ExternalError`, which can be
+    // downcast back to the original error type.
+    pub fn register_fallible_native_system<E: Into<Box<dyn Error + Send + Sync>>>(
+        &mut self,
+        id: ID,
+        mut func: impl FnMut(&mut WorldContext) -> Result<(), E> + 'static,
+    ) -> ScriptType {
+        let func = move |ctx: &mut WorldContext| func(ctx).map_err(Into::into);
         let old = self.systems.insert(id, System::Native(Box::new(func)));
         ScriptType::from_opt_system(old.as_ref())
     }
 
+    // Set callbacks to be invoked with the ID of each system immediately
+    // before and after it runs. This replaces any previously-set hooks.
+    pub fn set_system_hooks(
+        &mut self,
+        before: impl Fn(&ID) + 'static,
+        after: impl Fn(&ID) + 'static,
+    ) {
+        self.system_hooks = Some(SystemHooks {
+            before: Box::new(before),
+            after: Box::new(after),
+        });
+    }
+
+    pub fn clear_system_hooks(&mut self) {
+        self.system_hooks = None;
+    }
+
     pub fn run_system(&mut self, id: &ID) -> rlua::Result<bool> {
-        match self.systems.get_mut(id) {
-            None => Ok(false),
-            Some(System::Lua(key)) => {
+        let result = self.run_system_with(id, |_, _| Ok(()))?;
+        Ok(result.is_some())
+    }
+
+    // Run a system and convert the value it returns, so that systems can pass
+    // information back, such as whether to stop running the rest of a schedule.
+    // Native systems return nothing, so they are treated as returning `nil`.
+    // Returns `None` if there is no such system.
+    pub fn run_system_returning<R>(&mut self, id: &ID) -> rlua::Result<Option<R>>
+    where
+        R: for<'lua> FromLua<'lua>,
+    {
+        self.run_system_with(id, |value, ctx| R::from_lua(value, ctx))
+    }
+
+    fn run_system_with<R>(
+        &mut self,
+        id: &ID,
+        convert: impl for<'lua> FnOnce(rlua::Value<'lua>, rlua::Context<'lua>) -> rlua::Result<R>,
+    ) -> rlua::Result<Option<R>> {
+        let system = match self.systems.get_mut(id) {
+            None => return Ok(None),
+            Some(system) => system,
+        };
+
+        if let Some(hooks) = &self.system_hooks {
+            (hooks.before)(id);
+        }
+
+        let result = match system {
+            System::Lua(key, _) => {
                 let ctx_ref_key = &self.ctx_ref_key;
                 self.lua.context(|ctx| {
                     let system_fn: rlua::Function = ctx.registry_value(key)?;
-                    let ctx_ref: rlua::Value = ctx.registry_value(&ctx_ref_key)?;
-                    let _: rlua::Value = system_fn.call(ctx_ref)?;
-                    Ok(true)
+                    let ctx_ref: rlua::Value = ctx.registry_value(ctx_ref_key)?;
+                    let result: rlua::Value = system_fn.call(ctx_ref)?;
+                    convert(result, ctx)
                 })
             }
-            Some(System::Native(ref mut func)) => {
-                let mut world = self.ctx_ref.write();
-                func(&mut *world);
-                Ok(true)
+            System::Native(ref mut func) => {
+                let result = func(&mut self.ctx_ref.write());
+                match result {
+                    Ok(()) => self.lua.context(|ctx| convert(rlua::Value::Nil, ctx)),
+                    Err(err) => Err(rlua::Error::ExternalError(Arc::from(err))),
+                }
             }
+        };
+
+        if let Some(hooks) = &self.system_hooks {
+            (hooks.after)(id);
         }
+
+        result.map(Some)
     }
 
     pub fn system_info(&self, id: &ID) -> ScriptType {
@@ -140,14 +330,14 @@ impl<ID, Q> World<ID, Q> where ID: Hash + Eq {
                 let ctx_ref_key = &self.ctx_ref_key;
                 self.lua.context(|ctx| {
                     let system_fn: rlua::Function = ctx.registry_value(key)?;
-                    let ctx_ref: rlua::Value = ctx.registry_value(&ctx_ref_key)?;
+                    let ctx_ref: rlua::Value = ctx.registry_value(ctx_ref_key)?;
                     let result: rlua::Value = system_fn.call(ctx_ref)?;
                     Ok(Some(post_process(result)))
                 })
             }
             Some(Query::Native(ref mut func)) => {
                 let mut world = self.ctx_ref.write();
-                Ok(Some(func(&mut *world)))
+                Ok(Some(func(&mut world)))
             }
         }
     }
@@ -169,9 +359,32 @@ impl<ID, Q> World<ID, Q> where ID: Hash + Eq {
         &self,
         code: &[u8],
         post_process: impl FnOnce(rlua::Value) -> R,
+    ) -> rlua::Result<R> {
+        self.run_named_lua(b"unnamed script", code, post_process)
+    }
+
+    // Run a script read from a file. The chunk is named after the path, so
+    // that error messages and tracebacks refer to the file and line.
+    pub fn run_lua_file<P: AsRef<Path>, R>(
+        &self,
+        path: P,
+        post_process: impl FnOnce(rlua::Value) -> R,
+    ) -> rlua::Result<R> {
+        let path = path.as_ref();
+        let code = fs::read(path).map_err(rlua::Error::external)?;
+        // a leading `@` tells Lua that the chunk name is a file name
+        let name = format!("@{}", path.display());
+        self.run_named_lua(name.as_bytes(), &code, post_process)
+    }
+
+    fn run_named_lua<R>(
+        &self,
+        name: &[u8],
+        code: &[u8],
+        post_process: impl FnOnce(rlua::Value) -> R,
     ) -> rlua::Result<R> {
         self.lua.context(|ctx| {
-            let run_fn: rlua::Function = ctx.load(code).set_name("unnamed script")?.eval()?;
+            let run_fn: rlua::Function = ctx.load(code).set_name(name)?.eval()?;
             let ctx_ref: rlua::Value = ctx.registry_value(&self.ctx_ref_key)?;
             let result: rlua::Value = run_fn.call(ctx_ref)?;
             Ok(post_process(result))
@@ -0,0 +1,250 @@
+use std::convert::TryFrom;
+use std::io;
+
+use super::decode;
+use super::encode;
+use super::error;
+
+use super::component::{ComponentArray, ComponentId, FieldName};
+use super::value::Value;
+
+/// The changes that turn one world into another, as computed by
+/// `WorldContext::diff` and applied by `WorldContext::apply_patch`.
+///
+/// Entities are identified by their index in the world, so a patch should
+/// only be applied to the world it was computed against.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorldPatch {
+    // the number of entity slots in the newer world
+    pub(crate) num_entities: u32,
+    // component arrays that only exist in the newer world
+    pub(crate) new_components: Vec<(String, ComponentId, Vec<FieldName>)>,
+    // changed fields of the global component
+    pub(crate) global: Vec<(u16, Value)>,
+    // changed entities, in increasing order of index
+    pub(crate) entities: Vec<(u32, EntityPatch)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum EntityPatch {
+    Removed,
+    Added(Vec<(u16, Vec<Value>)>),
+    Changed(Vec<ComponentPatch>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ComponentPatch {
+    Removed(u16),
+    Added(u16, Vec<Value>),
+    // a new value for the field at the given index of the component
+    Field(u16, u16, Value),
+}
+
+impl WorldPatch {
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, error::DecodeError> {
+        let mut state = decode::State::new(reader);
+        let patch = state.decode_world_patch()?;
+        state.expect_eof()?;
+        Ok(patch)
+    }
+
+    pub fn to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        encode::State::new(writer).encode_world_patch(self)
+    }
+
+    /// Whether applying the patch would leave a world unchanged, apart from
+    /// its number of entity slots.
+    pub fn is_empty(&self) -> bool {
+        self.new_components.is_empty() && self.global.is_empty() && self.entities.is_empty()
+    }
+}
+
+impl<R: io::Read> decode::State<R> {
+    pub fn decode_world_patch(&mut self) -> Result<WorldPatch, decode::Error> {
+        let header = self.decode_header_line("world patch header")?;
+
+        if header.len() != 5 {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
+                "world patch header with five fields",
+                format!("{} fields", header.len()),
+            ));
+        }
+
+        let signature = &header[0];
+        if signature != "PATCH" {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::BadSignature,
+                "world patch signature (PATCH)",
+                format!("invalid signature: {:?}", signature),
+            ));
+        }
+
+        let mut counts = [0; 4];
+        for (count, field) in counts.iter_mut().zip(&header[1..]) {
+            *count = match field.parse::<u32>() {
+                Ok(n) => n,
+                Err(_) => return Err(self.err_unexpected(
+                    decode::ErrorKind::InvalidNumber,
+                    "32-bit count",
+                    "invalid count",
+                )),
+            };
+        }
+        let [num_entities, num_new_components, num_global, num_entity_patches] = counts;
+
+        // new component arrays are written as empty arrays, one header per line
+        let mut new_components = Vec::with_capacity(decode::capacity_hint(num_new_components as usize));
+        for _ in 0..num_new_components {
+            let array = self.decode_component_array()?;
+            if !array.is_empty() {
+                return Err(self.err_unexpected(
+                    decode::ErrorKind::MalformedHeader,
+                    "empty component array",
+                    format!("{} components", array.len()),
+                ));
+            }
+            new_components.push((array.name().to_string(), array.id(), array.scheme().to_vec()));
+        }
+
+        let mut global = Vec::with_capacity(decode::capacity_hint(num_global as usize));
+        for _ in 0..num_global {
+            global.push((self.decode_u16()?, self.decode_value()?));
+        }
+
+        let mut entities = Vec::with_capacity(decode::capacity_hint(num_entity_patches as usize));
+        for _ in 0..num_entity_patches {
+            let idx = self.decode_u32()?;
+            let patch = match self.next("entity patch tag")? {
+                0x00 => EntityPatch::Removed,
+                0x01 => {
+                    let count = self.decode_u16()?;
+                    let mut components = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        components.push((self.decode_u16()?, self.decode_patch_values()?));
+                    }
+                    EntityPatch::Added(components)
+                }
+                0x02 => {
+                    let count = self.decode_u16()?;
+                    let mut changes = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        changes.push(self.decode_component_patch()?);
+                    }
+                    EntityPatch::Changed(changes)
+                }
+                b => return Err(self.err_unexpected(
+                    decode::ErrorKind::InvalidTag,
+                    "entity patch tag",
+                    format!("invalid byte ({:?})", b),
+                )),
+            };
+            entities.push((idx, patch));
+        }
+
+        Ok(WorldPatch { num_entities, new_components, global, entities })
+    }
+
+    fn decode_component_patch(&mut self) -> Result<ComponentPatch, decode::Error> {
+        Ok(match self.next("component patch tag")? {
+            0x00 => ComponentPatch::Removed(self.decode_u16()?),
+            0x01 => ComponentPatch::Added(self.decode_u16()?, self.decode_patch_values()?),
+            0x02 => ComponentPatch::Field(self.decode_u16()?, self.decode_u16()?, self.decode_value()?),
+            b => return Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
+                "component patch tag",
+                format!("invalid byte ({:?})", b),
+            )),
+        })
+    }
+
+    fn decode_patch_values(&mut self) -> Result<Vec<Value>, decode::Error> {
+        let count = self.decode_u16()?;
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            values.push(self.decode_value()?);
+        }
+        Ok(values)
+    }
+}
+
+fn len_u16(what: &str, len: usize) -> io::Result<u16> {
+    u16::try_from(len).map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is too large to encode ({})", what, len),
+    ))
+}
+
+impl<W: io::Write> encode::State<W> {
+    pub fn encode_world_patch(&mut self, patch: &WorldPatch) -> io::Result<()> {
+        self.write_fmt(format_args!(
+            "PATCH {} {} {} {}\n",
+            patch.num_entities,
+            patch.new_components.len(),
+            patch.global.len(),
+            patch.entities.len(),
+        ))?;
+
+        for (name, id, scheme) in &patch.new_components {
+            let array = ComponentArray::with_scheme(name.clone(), id.0, scheme.clone());
+            self.encode_component_array(&array, |_| {})?;
+        }
+
+        for (field, value) in &patch.global {
+            self.write(&field.to_be_bytes())?;
+            self.encode_value(value, &mut |_| {})?;
+        }
+
+        for (idx, entity) in &patch.entities {
+            self.write(&idx.to_be_bytes())?;
+            match entity {
+                EntityPatch::Removed => self.write(&[0x00])?,
+                EntityPatch::Added(components) => {
+                    self.write(&[0x01])?;
+                    self.write(&len_u16("entity component count", components.len())?.to_be_bytes())?;
+                    for (id, values) in components {
+                        self.write(&id.to_be_bytes())?;
+                        self.encode_patch_values(values)?;
+                    }
+                }
+                EntityPatch::Changed(changes) => {
+                    self.write(&[0x02])?;
+                    self.write(&len_u16("entity change count", changes.len())?.to_be_bytes())?;
+                    for change in changes {
+                        self.encode_component_patch(change)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn encode_component_patch(&mut self, change: &ComponentPatch) -> io::Result<()> {
+        match change {
+            ComponentPatch::Removed(id) => {
+                self.write(&[0x00])?;
+                self.write(&id.to_be_bytes())
+            }
+            ComponentPatch::Added(id, values) => {
+                self.write(&[0x01])?;
+                self.write(&id.to_be_bytes())?;
+                self.encode_patch_values(values)
+            }
+            ComponentPatch::Field(id, field, value) => {
+                self.write(&[0x02])?;
+                self.write(&id.to_be_bytes())?;
+                self.write(&field.to_be_bytes())?;
+                self.encode_value(value, &mut |_| {})
+            }
+        }
+    }
+
+    fn encode_patch_values(&mut self, values: &[Value]) -> io::Result<()> {
+        self.write(&len_u16("component field count", values.len())?.to_be_bytes())?;
+        for value in values {
+            self.encode_value(value, &mut |_| {})?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+// The tag bytes that begin each encoded `Value`.
+//
+// Some kinds of value have an "inline" form, where a small length or number is
+// stored in the tag byte itself: such a tag is the `*_INLINE` base plus the
+// length or number, up to the corresponding `*_INLINE_MAX`. Otherwise the
+// length or number follows the tag as a big-endian integer of the given width.
+
+// 0x00 - 0x7f: a non-negative integer less than 0x80
+pub const INT_INLINE: u8 = 0x00;
+pub const INT_INLINE_MAX: u8 = 0x7f;
+
+// 0x80 - 0x8f: a byte string of fewer than 16 bytes
+pub const BYTES_INLINE: u8 = 0x80;
+pub const BYTES_INLINE_MAX: u8 = 0x8f;
+
+// 0x90 - 0x9f: an array of fewer than 16 values
+pub const ARRAY_INLINE: u8 = 0x90;
+pub const ARRAY_INLINE_MAX: u8 = 0x9f;
+
+pub const BYTES_U8: u8 = 0xa0;
+pub const BYTES_U32: u8 = 0xa1;
+pub const ARRAY_U8: u8 = 0xa2;
+pub const ARRAY_U32: u8 = 0xa3;
+
+pub const BOOL_FALSE: u8 = 0xa4;
+pub const BOOL_TRUE: u8 = 0xa5;
+
+pub const F32: u8 = 0xa6;
+pub const F64: u8 = 0xa7;
+
+pub const I8: u8 = 0xa8;
+pub const I16: u8 = 0xa9;
+pub const I32: u8 = 0xaa;
+pub const I64: u8 = 0xab;
+
+pub const NONE: u8 = 0xac;
+// followed by the contained value
+pub const SOME: u8 = 0xad;
+
+pub const ENTITY_U8: u8 = 0xae;
+pub const ENTITY_U16: u8 = 0xaf;
+pub const ENTITY_U32: u8 = 0xb0;
+pub const ENTITY_INVALID: u8 = 0xb1;
+
+// The tags from `STR_U8` to `INT_ARRAY` were added in version 1 of the format.
+
+pub const STR_U8: u8 = 0xb2;
+pub const STR_U32: u8 = 0xb3;
+// followed by the scale (u8) and mantissa (i64)
+pub const DECIMAL: u8 = 0xb4;
+// followed by the element width in bytes (u8), the length (u32), and the elements
+pub const INT_ARRAY: u8 = 0xb5;
+
+// Added in version 5 of the format.
+pub const ENTITY_U24: u8 = 0xb6;
+
+// 0xb7 - 0xbf: not yet assigned
+pub const RESERVED: u8 = 0xb7;
+pub const RESERVED_MAX: u8 = 0xbf;
+
+// 0xc0 - 0xff: the ID of one of the first 64 entities
+pub const ENTITY_INLINE: u8 = 0xc0;
+pub const ENTITY_INLINE_MAX: u8 = 0xff;
+
+// The format version that introduced a tag, or `None` if the tag is reserved.
+// Decoders with `strict_tags` set, as it is by default, reject tags newer than
+// the version they are decoding.
+pub fn introduced_in(tag: u8) -> Option<u32> {
+    match tag {
+        STR_U8 ..= INT_ARRAY => Some(1),
+        ENTITY_U24 => Some(5),
+        RESERVED ..= RESERVED_MAX => None,
+        _ => Some(0),
+    }
+}
@@ -1,8 +1,9 @@
 use super::*;
 
 use value::{Value, EntityId};
-use component::{ComponentArray, GlobalComponent};
+use component::{ComponentArray, ComponentId, FieldName, GlobalComponent};
 use entity::{ComponentIdx, EntityData, EntityArray};
+use lua::ScriptType;
 
 /// Return an arbitrary byte vector for testing purposes, as well as its length.
 fn get_bytes() -> (u8, Vec<u8>) {
@@ -16,7 +17,7 @@ fn decode_value(b: &[u8]) -> Result<Value, decode::Error> {
 
 fn encode_value(val: &Value) -> Vec<u8> {
     let mut encoded = Vec::new();
-    encode::State::new(&mut encoded).encode_value(&val, &mut |_| {}).unwrap();
+    encode::State::new(&mut encoded).encode_value(val, &mut |_| {}).unwrap();
     encoded
 }
 
@@ -44,6 +45,20 @@ fn value_encoding() {
     check_value_round_trip(b"\x80", Value::Bytes(Vec::new()));
     check_value_round_trip(b"\x84test", Value::Bytes(b"test".to_vec()));
 
+    // UTF-8 strings
+    check_value_round_trip(b"\xb2\x00", Value::Str(String::new()));
+    check_value_round_trip(b"\xb2\x04test", Value::Str("test".to_string()));
+    check_value_decode(b"\xb3\x00\x00\x00\x04test", Value::Str("test".to_string()));
+    {
+        let s = "\u{3bb}".repeat(200);
+        let mut encoded = vec![0xb3, 0x00, 0x00, 0x01, 0x90];
+        encoded.extend_from_slice(s.as_bytes());
+        check_value_round_trip(&encoded, Value::Str(s));
+    }
+    assert_eq!(Value::Str("test".to_string()).as_str(), Some("test"));
+    assert_eq!(Value::Bytes(b"test".to_vec()).as_str(), Some("test"));
+    assert_eq!(Value::Bytes(b"\xff".to_vec()).as_str(), None);
+
     // 4-bit array literals
     check_value_round_trip(b"\x90", Value::Array(Vec::new()));
     check_value_round_trip(b"\x94\x01\x02\x03\x04", Value::Array(vec![
@@ -89,6 +104,34 @@ fn value_encoding() {
         check_value_round_trip(&encoded, Value::Array(expected_array));
     }
 
+    // packed integer arrays
+    check_value_round_trip(b"\xb5\x01\x00\x00\x00\x00", Value::IntArray(Vec::new()));
+    check_value_round_trip(
+        b"\xb5\x01\x00\x00\x00\x03\x01\xff\x7f",
+        Value::IntArray(vec![1, -1, 0x7f]),
+    );
+    check_value_round_trip(
+        b"\xb5\x02\x00\x00\x00\x02\x00\x01\x01\x00",
+        Value::IntArray(vec![1, 0x100]),
+    );
+    check_value_round_trip(
+        b"\xb5\x04\x00\x00\x00\x02\x00\x00\x00\x01\x80\x00\x00\x00",
+        Value::IntArray(vec![1, -0x80000000]),
+    );
+    check_value_round_trip(
+        b"\xb5\x08\x00\x00\x00\x01\x7f\xff\xff\xff\xff\xff\xff\xff",
+        Value::IntArray(vec![i64::MAX]),
+    );
+    {
+        // packing is much smaller than a generic array of the same numbers
+        let ints: Vec<i64> = (0..10000).map(|i| (i % 200) - 100).collect();
+        let packed = encode_value(&Value::IntArray(ints.clone()));
+        let generic = encode_value(&Value::Array(ints.iter().copied().map(Value::Int).collect()));
+        assert_eq!(packed.len(), 6 + 10000);
+        assert!(generic.len() > 15000);
+        assert_eq!(decode_value(&packed).unwrap(), Value::IntArray(ints));
+    }
+
     // booleans
     check_value_round_trip(
         b"\x92\xa4\xa5",
@@ -113,6 +156,25 @@ fn value_encoding() {
         )
     }
 
+    // decimals
+    check_value_round_trip(
+        b"\xb4\x02\x00\x00\x00\x00\x00\x00\x00\x7b",
+        Value::Decimal { mantissa: 123, scale: 2 },
+    );
+    check_value_round_trip(
+        b"\xb4\x00\xff\xff\xff\xff\xff\xff\xff\xff",
+        Value::Decimal { mantissa: -1, scale: 0 },
+    );
+    check_value_round_trip(
+        b"\xb4\xff\x7f\xff\xff\xff\xff\xff\xff\xff",
+        Value::Decimal { mantissa: i64::MAX, scale: 0xff },
+    );
+    // unlike a float, a decimal keeps the scale it was written with
+    assert_ne!(
+        decode_value(&encode_value(&Value::Decimal { mantissa: 1230, scale: 3 })).unwrap(),
+        Value::Decimal { mantissa: 123, scale: 2 },
+    );
+
     // ints
     check_value_decode    (b"\xa8\x7f", Value::Int(0x7f));
     check_value_round_trip(b"\xa8\x80", Value::Int(-0x80));
@@ -165,13 +227,118 @@ fn value_encoding() {
     assert!(decode_value(b"\xab\x00\x00\x00\x00\x00\x00\x00").is_err());
     assert!(decode_value(b"\xad").is_err());
     assert!(decode_value(b"\xad\xad\xad\xad").is_err());
-
-    // 2. invalid byte values
-    for byte in 0xb2 .. 0xc0 {
+    assert!(decode_value(b"\xb2\x05test").is_err());
+    assert!(decode_value(b"\xb4").is_err());
+    assert!(decode_value(b"\xb5\x02\x00\x00\x00\x01\x00").is_err());
+    assert!(decode_value(b"\xb5\x03\x00\x00\x00\x00").is_err());
+    assert!(decode_value(b"\xb4\x02\x00\x00\x00\x00\x00\x00\x00").is_err());
+
+    // 2. strings that are not valid UTF-8
+    assert!(decode_value(b"\xb2\x01\xff").is_err());
+    assert!(decode_value(b"\xb2\x02\xc3\x28").is_err());
+
+    // 3. invalid byte values
+    for byte in 0xb6 .. 0xc0 {
         assert!(decode_value(&[byte]).is_err());
     }
 }
 
+#[test]
+fn value_decoding_size_limit() {
+    // a three-byte string followed by another value
+    let b = b"\xb3\x00\x00\x00\x03abc\x01";
+    let string = Value::Str("abc".to_string());
+
+    // ok: exactly at and under the limit
+    assert_eq!(decode::State::new(&b[..]).decode_value_within(8).unwrap(), string);
+    assert_eq!(decode::State::new(&b[..]).decode_value_within(100).unwrap(), string);
+
+    // error: over the limit
+    let err = decode::State::new(&b[..]).decode_value_within(7).err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::SizeLimitExceeded);
+
+    // the limit only applies to the one value
+    let mut state = decode::State::new(&b[..]);
+    state.decode_value_within(8).unwrap();
+    assert_eq!(state.decode_value().unwrap(), Value::Int(1));
+    state.expect_eof().unwrap();
+}
+
+#[test]
+fn value_encoding_depth_limit() {
+    fn nested_maybe(depth: usize) -> Value {
+        let mut val = Value::Maybe(None);
+        for _ in 0..depth {
+            val = Value::Maybe(Some(Box::new(val)));
+        }
+        val
+    }
+
+    // take the value apart iteratively, since dropping it recursively
+    // could itself overflow the stack
+    fn dismantle(mut val: Value) {
+        while let Value::Maybe(Some(inner)) = val {
+            val = *inner;
+        }
+    }
+
+    let mut encoded = Vec::new();
+
+    // error: far too deep to encode with the default limit
+    let deep = nested_maybe(100_000);
+    assert!(encode::State::new(&mut encoded).encode_value(&deep, &mut |_| {}).is_err());
+    dismantle(deep);
+
+    // ok: exactly at a custom limit
+    let val = nested_maybe(9);
+    encoded.clear();
+    encode::State::with_max_depth(&mut encoded, 10).encode_value(&val, &mut |_| {}).unwrap();
+    assert_eq!(encoded, b"\xad\xad\xad\xad\xad\xad\xad\xad\xad\xac");
+
+    // error: one past a custom limit
+    let val = nested_maybe(10);
+    encoded.clear();
+    assert!(encode::State::with_max_depth(&mut encoded, 10).encode_value(&val, &mut |_| {}).is_err());
+
+    // ok: the depth is restored after an error, so the state can be reused
+    let mut state = encode::State::with_max_depth(Vec::new(), 10);
+    assert!(state.encode_value(&nested_maybe(10), &mut |_| {}).is_err());
+    assert_eq!(state.depth, 0);
+    state.encode_value(&nested_maybe(9), &mut |_| {}).unwrap();
+}
+
+#[test]
+fn little_endian_primitives() {
+    let mut encoder = codec::Encoder::new(Vec::new());
+    encoder.encode_u16_le(0x1234).unwrap();
+    encoder.encode_i16_le(-2).unwrap();
+    encoder.encode_u32_le(0x12345678).unwrap();
+    encoder.encode_i32_le(-0x12345678).unwrap();
+    encoder.encode_u64_le(0x0123456789abcdef).unwrap();
+    encoder.encode_i64_le(i64::MIN).unwrap();
+    encoder.encode_f32_le(1.5).unwrap();
+    encoder.encode_f64_le(-0.25).unwrap();
+    let encoded = encoder.into_inner();
+
+    // the bytes are actually little-endian
+    assert_eq!(&encoded[..4], b"\x34\x12\xfe\xff");
+    assert_eq!(&encoded[4..8], b"\x78\x56\x34\x12");
+
+    let mut state = codec::Decoder::new(&encoded[..]);
+    assert_eq!(state.decode_u16_le().unwrap(), 0x1234);
+    assert_eq!(state.decode_i16_le().unwrap(), -2);
+    assert_eq!(state.decode_u32_le().unwrap(), 0x12345678);
+    assert_eq!(state.decode_i32_le().unwrap(), -0x12345678);
+    assert_eq!(state.decode_u64_le().unwrap(), 0x0123456789abcdef);
+    assert_eq!(state.decode_i64_le().unwrap(), i64::MIN);
+    assert_eq!(state.decode_f32_le().unwrap(), 1.5);
+    assert_eq!(state.decode_f64_le().unwrap(), -0.25);
+    assert_eq!(state.idx(), encoded.len());
+
+    // error: too few bytes
+    assert!(codec::Decoder::new(&b"\x00\x00\x00"[..]).decode_u32_le().is_err());
+}
+
 fn decode_component_array(b: &[u8]) -> Result<ComponentArray, decode::Error> {
     decode::State::new(b).decode_component_array()
 }
@@ -207,7 +374,7 @@ fn component_array_encoding() {
         let mut empty_array = decode_component_array(b"COMPONENT foo 31415 0\n").unwrap();
         assert!(empty_array.is_marker());
         assert_eq!(empty_array.name(), "foo");
-        assert_eq!(empty_array.id(), 31415);
+        assert_eq!(empty_array.id(), ComponentId(31415));
 
         // the only valid index for marker components is zero
         assert!(empty_array.get(0).is_some());
@@ -223,17 +390,28 @@ fn component_array_encoding() {
     // ok: header with components
     let scheme = decode_component_array(b"COMPONENT foo 0 0 a b c d e f\n").unwrap();
     assert_eq!(scheme.scheme(), &[
-        "a".to_string(),
-        "b".to_string(),
-        "c".to_string(),
-        "d".to_string(),
-        "e".to_string(),
-        "f".to_string(),
+        FieldName::from("a"),
+        FieldName::from("b"),
+        FieldName::from("c"),
+        FieldName::from("d"),
+        FieldName::from("e"),
+        FieldName::from("f"),
     ]);
 
     // error: header with unicode
     assert!(decode_component_array(b"COMPONENT \xc1\xa1foo 0 0\n").is_err());
 
+    // ok: header with CRLF line ending
+    {
+        let array = decode_component_array(b"COMPONENT foo 0 0\r\n").unwrap();
+        assert_eq!(array.name(), "foo");
+        assert!(array.is_marker());
+
+        let array = decode_component_array(b"COMPONENT foo 0 1 x y\r\n\x01\x02").unwrap();
+        assert_eq!(array.scheme(), &[FieldName::from("x"), FieldName::from("y")]);
+        assert_eq!(array.get(0).unwrap().field("y"), Some(&Value::Int(2)));
+    }
+
     // ok: header with symbols
     assert_eq!(decode_component_array(b"COMPONENT foo! 0 0\n").unwrap().name(), "foo!");
 
@@ -253,8 +431,8 @@ fn component_array_encoding() {
         ).unwrap();
 
         assert_eq!(array.name(), "point");
-        assert_eq!(array.id(), 21718);
-        assert_eq!(array.scheme(), &["x".to_string(), "y".to_string()]);
+        assert_eq!(array.id(), ComponentId(21718));
+        assert_eq!(array.scheme(), &[FieldName::from("x"), FieldName::from("y")]);
 
         let comp_0 = array.get(0).unwrap();
         assert_eq!(comp_0.field("x"), Some(&Value::Int(0x1234)));
@@ -293,11 +471,169 @@ fn component_array_encoding() {
         assert_eq!(encode_component_array(&array), encoded);
     }
 
+    // ok: retaining a subset of components
+    {
+        let mut array = decode_component_array(
+            b"COMPONENT timer 0 5 t\n\x00\x01\x02\x03\x04"
+        ).unwrap();
+
+        let remap = array.retain(|comp| match comp.field("t") {
+            Some(Value::Int(t)) => t % 2 == 0,
+            _ => panic!(),
+        });
+        assert_eq!(remap, vec![Some(0), None, Some(1), None, Some(2)]);
+        assert_eq!(array.len(), 3);
+        assert_eq!(array.get(0).unwrap().field("t"), Some(&Value::Int(0)));
+        assert_eq!(array.get(1).unwrap().field("t"), Some(&Value::Int(2)));
+        assert_eq!(array.get(2).unwrap().field("t"), Some(&Value::Int(4)));
+        assert!(array.get(3).is_none());
+
+        // marker arrays have no components to retain
+        let mut marker = decode_component_array(b"COMPONENT marker 0 0\n").unwrap();
+        assert_eq!(marker.retain(|_| false), vec![]);
+        assert!(marker.get(0).is_some());
+    }
+
+    // ok: round trip through the public API
+    {
+        let encoded = b"COMPONENT point 3 2 x y\n\x01\x02\x03\xc4";
+        let array = ComponentArray::from_reader(&encoded[..]).unwrap();
+        assert_eq!(array.get(1).unwrap().field("y"), Some(&Value::EntityId(EntityId::Idx(4))));
+
+        let mut written = Vec::new();
+        array.to_writer(&mut written).unwrap();
+        assert_eq!(written, encoded);
+
+        // error: the array must make up the whole input
+        assert!(ComponentArray::from_reader(&b"COMPONENT point 3 0 x y\n\n"[..]).is_err());
+    }
+
     // ensure that various other things round-trip correctly
     check_component_array_round_trip(b"COMPONENT 2 1 0 1 2\n");
     check_component_array_round_trip(b"COMPONENT foo\x00bar 11111 1 foo bar\n\x01\x02");
 }
 
+#[test]
+fn compressed_component_array_header() {
+    // error: missing or invalid compressed length
+    assert!(decode_component_array(b"COMPONENT-DEFLATE foo 0 0\n").is_err());
+    assert!(decode_component_array(b"COMPONENT-DEFLATE foo 0 0 x\n").is_err());
+
+    // error: compressed block is truncated
+    assert!(decode_component_array(b"COMPONENT-DEFLATE foo 0 1 4 x\n\x00").is_err());
+}
+
+#[cfg(not(feature = "compression"))]
+#[test]
+fn compressed_component_array_unsupported() {
+    let err = decode_component_array(b"COMPONENT-DEFLATE foo 0 1 1 x\n\x00").err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::InvalidCompressedData);
+}
+
+#[test]
+fn bit_packed_component_array_encoding() {
+    // ok: a dense boolean component is packed into bits
+    let mut array = ComponentArray::new("alive".to_string(), 2, vec!["flag".to_string()]);
+    for i in 0..1000 {
+        array.push(vec![Value::Bool(i % 3 == 0)]);
+    }
+    let encoded = encode_component_array(&array);
+    assert!(encoded.starts_with(b"COMPONENT-BITS alive 2 1000 flag\n"));
+    assert_eq!(encoded.len(), "COMPONENT-BITS alive 2 1000 flag\n".len() + 125);
+
+    let decoded = decode_component_array(&encoded).unwrap();
+    assert_eq!(decoded.len(), 1000);
+    assert_eq!(decoded.get(3).unwrap().field("flag"), Some(&Value::Bool(true)));
+    assert_eq!(decoded.get(4).unwrap().field("flag"), Some(&Value::Bool(false)));
+    assert_eq!(encode_component_array(&decoded), encoded);
+
+    // ok: bits are least significant first, and padding is ignored
+    let decoded = decode_component_array(b"COMPONENT-BITS a 0 3 x\n\xfa").unwrap();
+    let flags: Vec<_> = (0..3).map(|i| decoded.get(i).unwrap().values[0].clone()).collect();
+    assert_eq!(flags, vec![Value::Bool(false), Value::Bool(true), Value::Bool(false)]);
+
+    // ok: small arrays, and arrays with other values, are written normally
+    let mut array = ComponentArray::new("a".to_string(), 0, vec!["x".to_string()]);
+    array.push(vec![Value::Bool(true)]);
+    assert_eq!(encode_component_array(&array), b"COMPONENT a 0 1 x\n\xa5");
+    for _ in 0..20 {
+        array.push(vec![Value::Int(0)]);
+    }
+    assert!(encode_component_array(&array).starts_with(b"COMPONENT a"));
+
+    // error: more than one field, truncated, or an older format version
+    assert!(decode_component_array(b"COMPONENT-BITS a 0 1 x y\n\x00").is_err());
+    assert!(decode_component_array(b"COMPONENT-BITS a 0 9 x\n\x00").is_err());
+    let mut state = decode::State::new(&b"COMPONENT-BITS a 0 1 x\n\x00"[..]);
+    state.set_version(2);
+    assert!(state.decode_component_array().is_err());
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_component_array_encoding() {
+    fn encode_compressed(array: &ComponentArray) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut state = encode::State::new(&mut encoded);
+        state.set_compression(true);
+        state.encode_component_array(array, |_| {}).unwrap();
+        encoded
+    }
+
+    // ok: a highly repetitive array shrinks and round-trips
+    let mut array = ComponentArray::new("pos".to_string(), 3, vec!["x".to_string(), "y".to_string()]);
+    for i in 0..1000 {
+        array.push(vec![Value::Int(i % 4), Value::Str("repeated".to_string())]);
+    }
+    let raw = encode_component_array(&array);
+    let compressed = encode_compressed(&array);
+    assert!(compressed.starts_with(b"COMPONENT-DEFLATE pos 3 1000 "));
+    assert!(compressed.len() < raw.len() / 10);
+
+    let decoded = decode_component_array(&compressed).unwrap();
+    assert_eq!(decoded.name(), "pos");
+    assert_eq!(decoded.id(), ComponentId(3));
+    assert_eq!(encode_component_array(&decoded), raw);
+
+    // ok: a small array is left uncompressed
+    let mut array = ComponentArray::new("tiny".to_string(), 0, vec!["x".to_string()]);
+    array.push(vec![Value::Int(1)]);
+    assert_eq!(encode_compressed(&array), b"COMPONENT tiny 0 1 x\n\x01");
+
+    // error: garbage in the compressed block
+    let err = decode_component_array(b"COMPONENT-DEFLATE foo 0 1 2 x\n\xff\xff").err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::InvalidCompressedData);
+
+    // error: compressed arrays didn't exist before version 1
+    let mut state = decode::State::new(&compressed[..]);
+    state.set_version(0);
+    assert_eq!(state.decode_component_array().err().unwrap().kind(), decode::ErrorKind::BadSignature);
+
+    fn deflate(raw: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(raw).unwrap();
+        encoder.finish().unwrap()
+    }
+    let array_of = |count: usize, raw: &[u8]| {
+        let block = deflate(raw);
+        let mut encoded = format!("COMPONENT-DEFLATE z 0 {} {} x\n", count, block.len()).into_bytes();
+        encoded.extend(block);
+        encoded
+    };
+
+    // error: a block which inflates to more than its values
+    let zeroes = vec![0; 1 << 20];
+    assert_eq!(decode_component_array(&array_of(1, &zeroes)).err().unwrap().kind(), decode::ErrorKind::TrailingBytes);
+
+    // the inflated values count towards the state's limits
+    let zeroes = array_of(zeroes.len(), &zeroes);
+    assert_eq!(decode_component_array(&zeroes).unwrap().len(), 1 << 20);
+    let mut state = decode::State::new(&zeroes[..]);
+    state.set_max_total_bytes(zeroes.len() * 4);
+    assert_eq!(state.decode_component_array().err().unwrap().kind(), decode::ErrorKind::LengthLimitExceeded);
+}
+
 fn decode_global_component(b: &[u8]) -> Result<GlobalComponent, decode::Error> {
     decode::State::new(b).decode_global_component()
 }
@@ -325,6 +661,19 @@ fn global_component_encoding() {
     assert!(decode_global_component(b"GLOBAL a a\n").is_err());
     assert!(decode_global_component(b"GLOBAL a b a\n").is_err());
     assert!(decode_global_component(b"GLOBAL a b c d a f\n").is_err());
+    // every path that decodes a global component checks for duplicates
+    assert_eq!(
+        GlobalComponent::from_reader(&b"GLOBAL a b a\n\x00\x00\x00"[..]).err().unwrap().kind(),
+        decode::ErrorKind::DuplicateField,
+    );
+    assert_eq!(
+        decode_world(&world_fixture(0, 0, b"GLOBAL a b a\n\x00\x00\x00\nENTITIES 0\n")).err().unwrap().kind(),
+        decode::ErrorKind::DuplicateField,
+    );
+    {
+        let (_, errors) = decode_world_lenient(&world_fixture(0, 0, b"GLOBAL a b a\n\x00\x00\x00\nENTITIES 0\n")[..]);
+        assert_eq!(errors.iter().map(decode::Error::kind).collect::<Vec<_>>(), [decode::ErrorKind::DuplicateField]);
+    }
 
     // error: too few values
     assert!(decode_global_component(b"GLOBAL a\n").is_err());
@@ -335,9 +684,9 @@ fn global_component_encoding() {
         let global = decode_global_component(b"GLOBAL x y z\n\x12\x34\x56").unwrap();
         assert!(!global.is_empty());
         assert_eq!(global.scheme(), &[
-            "x".to_string(),
-            "y".to_string(),
-            "z".to_string(),
+            FieldName::from("x"),
+            FieldName::from("y"),
+            FieldName::from("z"),
         ]);
         assert_eq!(global.field_idx("x"), Some(0));
         assert_eq!(global.field_idx("y"), Some(1));
@@ -349,6 +698,20 @@ fn global_component_encoding() {
         assert_eq!(component.field("z"), Some(&Value::Int(0x56)));
     }
 
+    // ok: round trip through the public API
+    {
+        let encoded = b"GLOBAL x y z\n\x01\x82hi\xac";
+        let global = GlobalComponent::from_reader(&encoded[..]).unwrap();
+        assert_eq!(global.get().field("y"), Some(&Value::Bytes(b"hi".to_vec())));
+
+        let mut written = Vec::new();
+        global.to_writer(&mut written).unwrap();
+        assert_eq!(written, encoded);
+
+        // error: the component must make up the whole input
+        assert!(GlobalComponent::from_reader(&b"GLOBAL x y z\n\x01\x02\x03\x04"[..]).is_err());
+    }
+
     // ok: mutating a component
     {
         let (n, bytes) = get_bytes();
@@ -495,21 +858,56 @@ fn entity_data_encoding() {
     }
 
     // error: bad u16 component idx count
-    let mut encoded = Vec::new();
-    encoded.push(0xff);
-    for _ in 0..0xff {
-        encoded.push(0xc0);
-    }
+    let mut encoded = vec![0xff];
+    encoded.resize(0x100, 0xc0);
     assert!(decode_entity_data(&encoded).is_err());
     
     // ok: correct u16 component idx count
     encoded.insert(1, 0xff);
     encoded.insert(1, 0x00);
     check_entity_data_round_trip(&encoded);
+
+    // ok: tombstone for a deleted entity
+    let data = check_entity_data_round_trip(b"\xfe");
+    assert!(data.is_deleted);
+    assert!(data.components.is_empty());
+
+    // ok: 254 component idxs are written in escaped form
+    let mut encoded = vec![0xff, 0x00, 0xfe];
+    encoded.resize(encoded.len() + 0xfe, 0xc0);
+    assert_eq!(check_entity_data_round_trip(&encoded).components.len(), 0xfe);
+
+    // ok: before version 2, 0xfe is a component idx count
+    let mut encoded = vec![0xfe];
+    encoded.resize(encoded.len() + 0xfe, 0xc0);
+    let mut state = decode::State::new(&encoded[..]);
+    state.set_version(1);
+    assert_eq!(state.decode_entity_data().unwrap().components.len(), 0xfe);
+}
+
+#[test]
+fn entity_array_tombstones() {
+    let b = b"ENTITIES 4\n\x01\xc0\xfe\x00\xfe";
+    let array = decode_entity_array(b).unwrap();
+    let deleted: Vec<_> = array.entries.iter().map(|e| e.is_deleted).collect();
+    assert_eq!(deleted, vec![false, true, false, true]);
+    assert_eq!(array.entries[0].components, vec![ComponentIdx { id: 0, idx: 0 }]);
+
+    // tombstoned entities count as deleted when resolving packed IDs
+    assert_eq!(array.packed_idxs(), Some(vec![Some(0), None, Some(1), None]));
+
+    // each tombstone round-trips on its own
+    for entry in &array.entries {
+        let encoded = encode_entity_data(entry);
+        assert_eq!(decode_entity_data(&encoded).unwrap().is_deleted, entry.is_deleted);
+    }
+
+    // the entity array encoder still leaves deleted entities out
+    assert_eq!(encode_entity_array(&array), b"ENTITIES 2\n\x01\xc0\x00");
 }
 
 fn decode_entity_array(b: &[u8]) -> Result<EntityArray, decode::Error> {
-    decode::State::new(b).decode_entity_array()
+    decode::State::new(b).decode_entity_array(&mut |_| {})
 }
 
 fn encode_entity_array(array: &EntityArray) -> Vec<u8> {
@@ -542,6 +940,11 @@ fn entity_array_encoding() {
     // error: too few entities
     assert!(decode_entity_array(b"ENTITIES 1\n").is_err());
 
+    // error: entity count wider than 32 bits
+    assert!(decode_entity_array(b"ENTITIES 4294967296\n").is_err());
+    assert_eq!(entity::entity_count(u32::MAX as usize).unwrap(), u32::MAX);
+    assert!(entity::entity_count(u32::MAX as usize + 1).is_err());
+
     // ok: correct number of entities
     {
         let array = check_entity_array_round_trip(b"ENTITIES 5\n\x00\x00\x00\x00\x00");
@@ -577,3 +980,3013 @@ fn entity_array_encoding() {
         ]);
     }
 }
+
+fn decode_world(b: &[u8]) -> Result<WorldContext, decode::Error> {
+    decode::State::new(b).decode_world()
+}
+
+fn encode_world(world: &WorldContext) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode::State::new(&mut encoded).encode_world(world).unwrap();
+    encoded
+}
+
+// An encoded world with the given header fields, written in the current format
+// version, followed by `rest`.
+fn world_fixture(num_component_arrays: u16, max_component_id: u16, rest: &[u8]) -> Vec<u8> {
+    let mut encoded = format!(
+        "WORLD {} {} {}\n",
+        num_component_arrays, max_component_id, decode::FORMAT_VERSION,
+    ).into_bytes();
+    encoded.extend_from_slice(rest);
+    encoded
+}
+
+/// A world with two entities that refer to each other through a `link` component.
+fn linked_world() -> Vec<u8> {
+    world_fixture(1, 0, b"\
+      COMPONENT link 0 2 target\n\xc1\xc0\n\
+      GLOBAL\n\n\
+      ENTITIES 2\n\
+      \x01\xc0\
+      \x01\x00\x01")
+}
+
+#[test]
+fn world_crlf_line_endings() {
+    // ok: CRLF after headers and after the binary sections
+    let crlf = world_fixture(1, 0, b"\
+      COMPONENT link 0 2 target\r\n\xc1\xc0\r\n\
+      GLOBAL\r\n\r\n\
+      ENTITIES 2\r\n\
+      \x01\xc0\
+      \x01\x00\x01");
+    assert_eq!(encode_world(&decode_world(&crlf).unwrap()), linked_world());
+
+    // error: a carriage return that isn't followed by a newline
+    let bad = world_fixture(1, 0, b"\
+      COMPONENT link 0 2 target\n\xc1\xc0\r\r\n\
+      GLOBAL\n\n\
+      ENTITIES 2\n\
+      \x01\xc0\
+      \x01\x00\x01");
+    assert_eq!(decode_world(&bad).unwrap_err().kind(), decode::ErrorKind::MissingNewline);
+}
+
+#[test]
+fn world_encoding() {
+    // ok: with nothing deleted, entity IDs are written unchanged
+    {
+        let world = decode_world(&linked_world()).unwrap();
+        assert!(world.entities.packed_idxs().is_none());
+        assert_eq!(encode_world(&world), linked_world());
+    }
+
+    // ok: with nothing deleted, out-of-range entity IDs are still written as invalid
+    {
+        let mut world = decode_world(&linked_world()).unwrap();
+        *world.components[0].get_mut(1).unwrap().field_mut("target").unwrap() = Value::EntityId(EntityId::Idx(2));
+        let world = decode_world(&encode_world(&world)).unwrap();
+        assert_eq!(
+            world.components[0].get(1).unwrap().field("target"),
+            Some(&Value::EntityId(EntityId::Invalid)),
+        );
+    }
+
+    // error: trailing bytes after the world
+    {
+        let mut padded = linked_world();
+        padded.push(0x00);
+        assert!(decode_world(&padded).is_err());
+
+        // ok: unless the world is embedded in a larger stream
+        let mut reader = &padded[..];
+        let world = decode::State::new(&mut reader).decode_embedded_world().unwrap();
+        assert_eq!(encode_world(&world), linked_world());
+        assert_eq!(reader, b"\x00");
+    }
+
+    // ok: with an entity deleted, entity IDs are remapped
+    {
+        let mut world = decode_world(&linked_world()).unwrap();
+        world.entities.entries[0].is_deleted = true;
+        assert_eq!(world.entities.packed_idxs(), Some(vec![None, Some(0)]));
+        assert_eq!(encode_world(&world), &world_fixture(1, 0, b"\
+            COMPONENT link 0 2 target\n\xc0\xb1\n\
+            GLOBAL\n\n\
+            ENTITIES 1\n\
+            \x01\x00\x01")[..]);
+    }
+}
+
+#[test]
+fn world_entity_view() {
+    let world = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n\
+          COMPONENT name 1 1 name\n\x83foo\n\
+          GLOBAL\n\n\
+          ENTITIES 2\n\
+          \x01\xc0\
+          \x02\x00\x01\xc1")
+    ).unwrap();
+
+    // ok: an entity with one component
+    let view: Vec<_> = world.entity_view(EntityId::Idx(0)).unwrap().collect();
+    assert_eq!(view.len(), 1);
+    assert_eq!(view[0].0, "pos");
+    assert_eq!(view[0].1.field("x"), Some(&Value::Int(1)));
+    assert_eq!(view[0].1.field("y"), Some(&Value::Int(2)));
+
+    // ok: an entity with two components
+    let view: Vec<_> = world.entity_view(EntityId::Idx(1)).unwrap().collect();
+    assert_eq!(view.len(), 2);
+    assert_eq!(view[0].0, "pos");
+    assert_eq!(view[0].1.field("x"), Some(&Value::Int(3)));
+    assert_eq!(view[0].1.field("y"), Some(&Value::Int(4)));
+    assert_eq!(view[1].0, "name");
+    assert_eq!(view[1].1.field("name"), Some(&Value::Bytes(b"foo".to_vec())));
+
+    // error: entities that do not exist
+    assert!(world.entity_view(EntityId::Idx(2)).is_none());
+    assert!(world.entity_view(EntityId::Invalid).is_none());
+}
+
+#[test]
+fn world_encoding_is_deterministic() {
+    // the same component arrays, but in a different order
+    let world_a = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT a 0 1 x\n\x01\n\
+          COMPONENT b 1 1 y\n\x02\n\
+          GLOBAL\n\n\
+          ENTITIES 1\n\x02\xc0\xc1")
+    ).unwrap();
+    let world_b = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT b 1 1 y\n\x02\n\
+          COMPONENT a 0 1 x\n\x01\n\
+          GLOBAL\n\n\
+          ENTITIES 1\n\x02\xc0\xc1")
+    ).unwrap();
+
+    let encoded = encode_world(&world_a);
+    assert_eq!(encoded, encode_world(&world_b));
+    assert_eq!(&encoded[..], &world_fixture(2, 1, b"\
+        COMPONENT a 0 1 x\n\x01\n\
+        COMPONENT b 1 1 y\n\x02\n\
+        GLOBAL\n\n\
+        ENTITIES 1\n\x02\xc0\xc1")[..]);
+}
+
+#[test]
+fn world_intern_components() {
+    let mut world = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT sprite 0 4 name frame\n\x84tile\x00\x84rock\x00\x84tile\x00\x84tile\x01\n\
+          COMPONENT solid 1 0\n\n\
+          GLOBAL\n\n\
+          ENTITIES 4\n\
+          \x02\xc0\xc1\
+          \x02\x00\x01\xc1\
+          \x01\x00\x02\
+          \x01\x00\x03")
+    ).unwrap();
+
+    world.intern_components();
+
+    // the duplicate row was removed
+    let sprites = &world.components[0];
+    assert_eq!(sprites.len(), 3);
+
+    // every entity still sees the same data
+    let names: Vec<_> = (0..4)
+        .map(|i| {
+            let (name, comp) = world.entity_view(EntityId::Idx(i)).unwrap().next().unwrap();
+            assert_eq!(name, "sprite");
+            (comp.field("name").unwrap(), comp.field("frame").unwrap())
+        })
+        .collect();
+    assert_eq!(names, vec![
+        (&Value::Bytes(b"tile".to_vec()), &Value::Int(0)),
+        (&Value::Bytes(b"rock".to_vec()), &Value::Int(0)),
+        (&Value::Bytes(b"tile".to_vec()), &Value::Int(0)),
+        (&Value::Bytes(b"tile".to_vec()), &Value::Int(1)),
+    ]);
+
+    // entities 0 and 2 now share a row
+    assert_eq!(world.entities.entries[0].components[0], ComponentIdx { id: 0, idx: 0 });
+    assert_eq!(world.entities.entries[2].components[0], ComponentIdx { id: 0, idx: 0 });
+    assert_eq!(world.entities.entries[3].components[0], ComponentIdx { id: 0, idx: 2 });
+
+    // marker components are unaffected
+    assert_eq!(world.entities.entries[1].components[1], ComponentIdx { id: 1, idx: 0 });
+
+    // floats are compared bitwise
+    let mut builder = WorldBuilder::new().component("f", ComponentId(0), vec!["x"]);
+    for &x in &[0.0, -0.0, f64::NAN, f64::NAN] {
+        builder = builder.spawn(vec![(ComponentId(0), vec![Value::Float(x)])]);
+    }
+    let mut world = builder.build().unwrap();
+    world.intern_components();
+    assert_eq!(world.components[0].len(), 3);
+    assert_eq!(world.entities.entries[3].components[0], ComponentIdx { id: 0, idx: 2 });
+}
+
+#[test]
+fn system_hooks() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut world = World::<&'static str, ()>::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+
+    for &id in &["a", "b"] {
+        let log = log.clone();
+        world.register_native_system(id, move |_| log.borrow_mut().push(format!("run {}", id)));
+    }
+    world.register_lua_system("c", b"function(world) end").unwrap();
+
+    let (before_log, after_log) = (log.clone(), log.clone());
+    world.set_system_hooks(
+        move |id| before_log.borrow_mut().push(format!("before {}", id)),
+        move |id| after_log.borrow_mut().push(format!("after {}", id)),
+    );
+
+    assert!(world.run_system(&"b").unwrap());
+    assert!(world.run_system(&"a").unwrap());
+    assert!(world.run_system(&"c").unwrap());
+
+    // hooks don't fire for systems that don't exist
+    assert!(!world.run_system(&"d").unwrap());
+
+    assert_eq!(*log.borrow(), vec![
+        "before b", "run b", "after b",
+        "before a", "run a", "after a",
+        "before c", "after c",
+    ]);
+
+    // hooks no longer fire once cleared
+    world.clear_system_hooks();
+    assert!(world.run_system(&"a").unwrap());
+    assert_eq!(log.borrow().len(), 9);
+}
+
+#[test]
+fn world_decode_progress() {
+    // a world with enough entities to report progress partway through the entity array
+    let mut encoded = world_fixture(1, 0, b"\
+        COMPONENT pos 0 1 x y\n\x01\x02\n\
+        GLOBAL\n\n\
+        ENTITIES 3000\n").to_vec();
+    for _ in 0..3000 {
+        encoded.extend_from_slice(b"\x01\xc0");
+    }
+
+    let mut offsets = Vec::new();
+    World::<(), ()>::from_reader_with_progress(&encoded[..], |idx| offsets.push(idx)).unwrap();
+
+    // one call for the component array, one for the global component,
+    // two for the entity batches, and one at the end
+    assert_eq!(offsets.len(), 5);
+    assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(*offsets.last().unwrap(), encoded.len());
+}
+
+#[test]
+fn world_retain_entities() {
+    let mut world = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT pos 0 4 x\n\x00\x01\x02\x03\n\
+          COMPONENT tag 1 0\n\n\
+          GLOBAL\n\n\
+          ENTITIES 4\n\
+          \x01\xc0\
+          \x02\x00\x01\xc1\
+          \x01\x00\x02\
+          \x02\x00\x03\xc1")
+    ).unwrap();
+
+    // cull the entities without a tag
+    world.retain_entities(|_, data| data.component_ids().any(|id| id == ComponentId(1)));
+
+    assert!(world.entity_view(EntityId::Idx(0)).is_none());
+    assert!(world.entity_view(EntityId::Idx(2)).is_none());
+    assert_eq!(world.components[0].len(), 2);
+
+    // the survivors still resolve to their original components
+    for &(id, x) in &[(1, 1), (3, 3)] {
+        let view: Vec<_> = world.entity_view(EntityId::Idx(id)).unwrap().collect();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view[0].0, "pos");
+        assert_eq!(view[0].1.field("x"), Some(&Value::Int(x)));
+        assert_eq!(view[1].0, "tag");
+    }
+
+    // the culled entities are not written out
+    assert_eq!(encode_world(&world), &world_fixture(2, 1, b"\
+        COMPONENT pos 0 2 x\n\x01\x03\n\
+        COMPONENT tag 1 0\n\n\
+        GLOBAL\n\n\
+        ENTITIES 2\n\
+        \x02\xc0\xc1\
+        \x02\x00\x01\xc1")[..]);
+}
+
+#[test]
+fn world_project() {
+    let encoded = world_fixture(3, 2, b"\
+        COMPONENT pos 0 2 x\n\x01\x02\n\
+        COMPONENT vel 1 1 dx\n\x03\n\
+        COMPONENT tag 2 0\n\n\
+        GLOBAL time\n\x05\n\
+        ENTITIES 3\n\
+        \x02\xc0\xc1\
+        \x02\x00\x01\xc2\
+        \x01\xc2");
+    let world = decode_world(&encoded).unwrap();
+
+    let projected = world.project(&[ComponentId(0)]);
+
+    // only the kept component array remains
+    assert_eq!(projected.components.len(), 1);
+    assert_eq!(projected.components[0].name(), "pos");
+
+    // entities only reference the kept component, and none are dropped
+    assert_eq!(projected.entities.entries.len(), 3);
+    for entity in &projected.entities.entries {
+        assert!(entity.component_ids().all(|id| id == ComponentId(0)));
+    }
+    assert!(projected.entities.entries[2].components.is_empty());
+
+    assert_eq!(encode_world(&projected), &world_fixture(1, 0, b"\
+        COMPONENT pos 0 2 x\n\x01\x02\n\
+        GLOBAL time\n\x05\n\
+        ENTITIES 3\n\
+        \x01\xc0\
+        \x01\x00\x01\
+        \x00")[..]);
+
+    // the original world is unchanged
+    assert_eq!(encode_world(&world), encoded);
+}
+
+#[test]
+fn world_decoding_filtered() {
+    use std::collections::HashSet;
+
+    let encoded = world_fixture(3, 2, b"\
+        COMPONENT pos 0 2 x\n\x01\x02\n\
+        COMPONENT vel 1 1 dx\n\x03\n\
+        COMPONENT tag 2 0\n\n\
+        GLOBAL time\n\x05\n\
+        ENTITIES 3\n\
+        \x02\xc0\xc1\
+        \x02\x00\x01\xc2\
+        \x01\xc2");
+
+    // the same as decoding everything and projecting
+    let keep: HashSet<ComponentId> = (1..2).map(ComponentId).collect();
+    let filtered = decode::State::new(&encoded[..]).decode_world_filtered(&keep).unwrap();
+    assert_eq!(filtered, decode_world(&encoded).unwrap().project(&[ComponentId(1)]));
+    assert_eq!(filtered.component_names().collect::<Vec<_>>(), vec!["vel"]);
+    assert!(filtered.entities.entries[2].components.is_empty());
+    assert_eq!(filtered.component_for_entity(EntityId::Idx(0), "vel").unwrap().field("dx"), Some(&Value::Int(3)));
+
+    // keeping everything, or IDs the world doesn't have
+    let all: HashSet<ComponentId> = (0..3).map(ComponentId).collect();
+    assert_eq!(decode::State::new(&encoded[..]).decode_world_filtered(&all).unwrap(), decode_world(&encoded).unwrap());
+    let none: HashSet<ComponentId> = (7..8).map(ComponentId).collect();
+    let filtered = decode::State::new(&encoded[..]).decode_world_filtered(&none).unwrap();
+    assert_eq!(filtered.component_ids().count(), 0);
+    assert!(filtered.entities.entries.iter().all(|entity| entity.components.is_empty()));
+
+    // error: dropped arrays are still checked
+    let err = decode::State::new(&world_fixture(2, 1, b"COMPONENT a 1 0\n\nCOMPONENT b 1 0\n\n")[..])
+        .decode_world_filtered(&keep)
+        .unwrap_err();
+    assert_eq!(err.kind(), decode::ErrorKind::DuplicateComponent);
+    let err = decode::State::new(&world_fixture(1, 0, b"COMPONENT a 0 1 x\n\xb9\nGLOBAL\n\nENTITIES 0\n")[..])
+        .decode_world_filtered(&keep)
+        .unwrap_err();
+    assert_eq!(err.kind(), decode::ErrorKind::InvalidTag);
+
+    // dropped arrays are skipped over whatever their layout
+    let mut builder = WorldBuilder::new()
+        .component("mixed", ComponentId(0), vec!["a", "b"])
+        .component("flag", ComponentId(1), vec!["on"])
+        .component("sparse", ComponentId(2), vec!["x", "y", "z"])
+        .component("kept", ComponentId(3), vec!["n"]);
+    for i in 0..100 {
+        let mixed = vec![
+            Value::Array(vec![Value::Str(format!("s{}", i)), Value::Maybe(Some(Box::new(Value::Float(i as f64 / 3.0))))]),
+            if i % 2 == 0 { Value::IntArray(vec![i, -i, 1 << 40]) } else { Value::Decimal { mantissa: i, scale: 2 } },
+        ];
+        let owner = if i % 10 == 0 { Value::EntityId(EntityId::Idx(i as u32)) } else { Value::Bytes(vec![]) };
+        builder = builder.spawn(vec![
+            (ComponentId(0), mixed),
+            (ComponentId(1), vec![Value::Bool(i % 3 == 0)]),
+            (ComponentId(2), vec![Value::Int(0), Value::Maybe(None), owner]),
+            (ComponentId(3), vec![Value::Int(i)]),
+        ]);
+    }
+    let world = builder.build().unwrap();
+    let keep: HashSet<ComponentId> = (3..4).map(ComponentId).collect();
+    let check = |encoded: &[u8]| {
+        let filtered = decode::State::new(encoded).decode_world_filtered(&keep).unwrap();
+        assert_eq!(filtered, decode_world(encoded).unwrap().project(&[ComponentId(3)]));
+    };
+
+    let mut encoded = Vec::new();
+    let mut state = encode::State::new(&mut encoded);
+    state.set_sparse(true);
+    state.encode_world(&world).unwrap();
+    assert!(encoded.windows(14).any(|w| w == b"COMPONENT-BITS"));
+    assert!(encoded.windows(16).any(|w| w == b"COMPONENT-SPARSE"));
+    check(&encoded);
+
+    #[cfg(feature = "compression")]
+    {
+        let mut encoded = Vec::new();
+        let mut state = encode::State::new(&mut encoded);
+        state.set_compression(true);
+        state.encode_world(&world).unwrap();
+        assert!(encoded.windows(17).any(|w| w == b"COMPONENT-DEFLATE"));
+        check(&encoded);
+    }
+}
+
+#[test]
+fn world_component_introspection() {
+    let world = World::<(), ()>::from_reader(
+        &world_fixture(2, 7, b"\
+           COMPONENT vel 7 0 dx dy\n\n\
+           COMPONENT pos 3 0 x y\n\n\
+           GLOBAL\n\n\
+           ENTITIES 0\n")[..]
+    ).unwrap();
+
+    world.context(|ctx| {
+        assert_eq!(ctx.component_ids().collect::<Vec<_>>(), vec![ComponentId(3), ComponentId(7)]);
+        assert_eq!(ctx.component_names().collect::<Vec<_>>(), vec!["pos", "vel"]);
+    });
+
+    World::<(), ()>::new().context(|ctx| {
+        assert_eq!(ctx.component_ids().count(), 0);
+        assert_eq!(ctx.component_names().count(), 0);
+    });
+}
+
+#[test]
+fn world_builder() {
+    use error::BuildError;
+
+    let world = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x", "y"])
+        .component("target", ComponentId(1), vec!["id"])
+        .global(vec!["time"], vec![Value::Int(5)])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1), Value::Int(2)])])
+        .spawn(vec![
+            (ComponentId(0), vec![Value::Int(3), Value::Int(4)]),
+            (ComponentId(1), vec![Value::EntityId(EntityId::Idx(0))]),
+        ])
+        .build()
+        .unwrap();
+
+    assert_eq!(encode_world(&world), &world_fixture(2, 1, b"\
+        COMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n\
+        COMPONENT target 1 1 id\n\xc0\n\
+        GLOBAL time\n\x05\n\
+        ENTITIES 2\n\
+        \x01\xc0\
+        \x02\x00\x01\xc1")[..]);
+
+    // the world can be wrapped up for running systems
+    let world = World::<(), ()>::from_context(world);
+    world.context(|ctx| assert_eq!(ctx.component_names().count(), 2));
+
+    // error: invalid names
+    assert_eq!(
+        WorldBuilder::new().component("a b", ComponentId(0), Vec::<String>::new()).build().err(),
+        Some(BuildError::InvalidName("a b".to_string())),
+    );
+    assert_eq!(
+        WorldBuilder::new().component("a", ComponentId(0), vec![""]).build().err(),
+        Some(BuildError::InvalidName(String::new())),
+    );
+
+    // error: duplicates
+    assert_eq!(
+        WorldBuilder::new()
+            .component("a", ComponentId(0), Vec::<String>::new())
+            .component("b", ComponentId(0), Vec::<String>::new())
+            .build().err(),
+        Some(BuildError::DuplicateComponentId(ComponentId(0))),
+    );
+    assert_eq!(
+        WorldBuilder::new()
+            .component("a", ComponentId(0), Vec::<String>::new())
+            .component("a", ComponentId(1), Vec::<String>::new())
+            .build().err(),
+        Some(BuildError::DuplicateComponentName("a".to_string())),
+    );
+    assert_eq!(
+        WorldBuilder::new().global(vec!["x", "x"], vec![Value::Int(0), Value::Int(0)]).build().err(),
+        Some(BuildError::DuplicateField("x".to_string())),
+    );
+
+    // error: wrong number of values
+    assert_eq!(
+        WorldBuilder::new().global(vec!["x"], vec![]).build().err(),
+        Some(BuildError::GlobalValueCount { expected: 1, got: 0 }),
+    );
+    assert_eq!(
+        WorldBuilder::new()
+            .component("a", ComponentId(0), vec!["x"])
+            .spawn(vec![(ComponentId(0), vec![])])
+            .build().err(),
+        Some(BuildError::ValueCount { id: ComponentId(0), expected: 1, got: 0 }),
+    );
+
+    // error: references to things that don't exist
+    assert_eq!(
+        WorldBuilder::new().spawn(vec![(ComponentId(0), vec![])]).build().err(),
+        Some(BuildError::UnknownComponent(ComponentId(0))),
+    );
+    assert_eq!(
+        WorldBuilder::new()
+            .component("a", ComponentId(0), vec!["x"])
+            .spawn(vec![(ComponentId(0), vec![Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(1)))))])])
+            .build().err(),
+        Some(BuildError::InvalidEntityId(1)),
+    );
+}
+
+#[test]
+fn world_decode_lenient() {
+    // ok: a well-formed world decodes without errors
+    let (world, errors) = decode_world_lenient(&linked_world()[..]);
+    assert!(errors.is_empty());
+    assert_eq!(encode_world(&world.unwrap()), linked_world());
+
+    // one bad component array (with an invalid value byte) and one good one
+    let (world, errors) = decode_world_lenient(
+        &world_fixture(2, 1, b"\
+           COMPONENT bad 0 2 x\n\x01\xb9\n\
+           COMPONENT good 1 2 y\n\x03\x04\n\
+           GLOBAL\n\n\
+           ENTITIES 1\n\x01\x01\x01")[..]
+    );
+    let world = world.unwrap();
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        decode::Error::Unexpected { idx, .. } => assert_eq!(*idx, 34),
+        err => panic!("unexpected error: {:?}", err),
+    }
+    assert_eq!(world.component_names().collect::<Vec<_>>(), vec!["good"]);
+    let view: Vec<_> = world.entity_view(EntityId::Idx(0)).unwrap().collect();
+    assert_eq!(view[0].1.field("y"), Some(&Value::Int(4)));
+
+    // a bad global component and trailing bytes are both reported
+    let (world, errors) = decode_world_lenient(
+        &world_fixture(1, 0, b"\
+           COMPONENT good 0 1 y\n\x03\n\
+           GLOBAL x x\n\x00\x00\n\
+           ENTITIES 1\n\x01\xc0\
+           garbage")[..]
+    );
+    assert_eq!(errors.len(), 2);
+    let world = world.unwrap();
+    assert!(world.global.is_empty());
+    assert_eq!(world.component_names().collect::<Vec<_>>(), vec!["good"]);
+
+    // the format version still applies after skipping ahead: version 0 had no
+    // string tag, and before version 2, 0xfe was a component count rather than
+    // a tombstone, so these entities are truncated
+    let (world, errors) = decode_world_lenient(
+        &b"WORLD 1 0\n\
+           COMPONENT bad 0 1 x\n\xb9\n\
+           GLOBAL x\n\xb2\x01a\n\
+           ENTITIES 0\n"[..]
+    );
+    assert_eq!(errors.len(), 2);
+    assert!(world.unwrap().global.is_empty());
+    let (world, errors) = decode_world_lenient(
+        &b"WORLD 0 0 1\n\
+           GLOBAL x x\n\x00\x00\n\
+           ENTITIES 1\n\xfe"[..]
+    );
+    assert!(world.is_none());
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[1].kind(), decode::ErrorKind::UnexpectedEof);
+
+    // error: the world header is unrecoverable
+    let (world, errors) = decode_world_lenient(&b"WRLD 0 0\n"[..]);
+    assert!(world.is_none());
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn world_encoded_size() {
+    let world = decode_world(&linked_world()).unwrap();
+    assert_eq!(world.encoded_size().unwrap(), linked_world().len());
+
+    // the estimate is enough for a small world, so the vector is allocated once
+    assert!(world.estimated_size() >= linked_world().len());
+    let encoded = world.to_vec().unwrap();
+    assert_eq!(encoded, linked_world());
+    assert_eq!(encoded.capacity(), world.estimated_size());
+
+    // ok: encoding into a vector reserved with the exact size doesn't grow it
+    let mut state = encode::State::with_capacity(world.encoded_size().unwrap());
+    state.encode_world(&world).unwrap();
+    let encoded = state.into_inner();
+    assert_eq!(encoded.capacity(), linked_world().len());
+    assert_eq!(encoded, linked_world());
+
+    let world = World::<(), ()>::from_reader(&linked_world()[..]).unwrap();
+    assert_eq!(world.to_vec().unwrap(), linked_world());
+}
+
+#[test]
+fn world_component_for_entity() {
+    let mut world = decode_world(
+        &world_fixture(2, 1, b"\
+          COMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n\
+          COMPONENT tag 1 0\n\n\
+          GLOBAL\n\n\
+          ENTITIES 2\n\
+          \x01\x00\x01\
+          \x02\xc0\xc1")
+    ).unwrap();
+
+    // ok: mutate entity 0's position and read it back
+    {
+        let mut pos = world.component_for_entity_mut(EntityId::Idx(0), "pos").unwrap();
+        *pos.field_mut("x").unwrap() = Value::Int(10);
+    }
+    let pos = world.component_for_entity(EntityId::Idx(0), "pos").unwrap();
+    assert_eq!(pos.field("x"), Some(&Value::Int(10)));
+    assert_eq!(pos.field("y"), Some(&Value::Int(4)));
+
+    // entity 1's position is untouched
+    let pos = world.component_for_entity(EntityId::Idx(1), "pos").unwrap();
+    assert_eq!(pos.field("x"), Some(&Value::Int(1)));
+
+    // error: missing components and entities
+    assert!(world.component_for_entity(EntityId::Idx(0), "tag").is_none());
+    assert!(world.component_for_entity(EntityId::Idx(0), "vel").is_none());
+    assert!(world.component_for_entity_mut(EntityId::Idx(2), "pos").is_none());
+    assert!(world.component_for_entity_mut(EntityId::Invalid, "pos").is_none());
+}
+
+#[test]
+fn decode_error_kinds() {
+    use decode::ErrorKind;
+
+    fn kind<T>(result: Result<T, decode::Error>) -> ErrorKind {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.kind(),
+        }
+    }
+
+    // truncated input
+    assert_eq!(kind(decode_value(b"\xa9\x00")), ErrorKind::UnexpectedEof);
+    assert_eq!(kind(decode_world(&linked_world()[..linked_world().len() - 1])), ErrorKind::UnexpectedEof);
+
+    // bad signatures
+    assert_eq!(kind(decode_world(b"WRLD 0 0\n")), ErrorKind::BadSignature);
+    assert_eq!(kind(decode_component_array(b"TNENOPMOC foo 0 0\n")), ErrorKind::BadSignature);
+    assert_eq!(kind(decode_entity_array(b"ENTITY 0\n")), ErrorKind::BadSignature);
+
+    // other kinds
+    assert_eq!(kind(decode_world(b"WORLD 0\n")), ErrorKind::MalformedHeader);
+    assert_eq!(kind(decode_world(b"WORLD 0 x\n")), ErrorKind::InvalidNumber);
+    assert_eq!(kind(decode_value(b"\xbf")), ErrorKind::InvalidTag);
+    assert_eq!(kind(decode_value(b"\xb2\x01\xff")), ErrorKind::InvalidUtf8);
+    assert_eq!(kind(decode_global_component(b"GLOBAL a a\n")), ErrorKind::DuplicateField);
+    assert_eq!(kind(decode_world(&world_fixture(1, 0, b"COMPONENT a 1 0\n\n"))), ErrorKind::ComponentIdOutOfRange);
+    assert_eq!(
+        kind(decode_world(&world_fixture(2, 1, b"COMPONENT a 0 0\n\nCOMPONENT a 1 0\n\n"))),
+        ErrorKind::DuplicateComponent,
+    );
+    assert_eq!(kind(GlobalComponent::from_reader(&b"GLOBAL\n\x00"[..])), ErrorKind::TrailingBytes);
+
+    // the human-readable message is kept for display
+    let err = decode_world(b"WRLD 0 0\n").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "at byte 0: expected world state signature (WORLD), got invalid signature: \"WRLD\"",
+    );
+}
+
+#[test]
+fn decode_error_invalid_numbers() {
+    fn message<T>(result: Result<T, decode::Error>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => {
+                assert_eq!(err.kind(), decode::ErrorKind::InvalidNumber);
+                err.to_string()
+            }
+        }
+    }
+
+    // the offending token is included in the message
+    assert!(message(decode_component_array(b"COMPONENT a foo 0\n")).contains("invalid ID: \"foo\""));
+    assert!(message(decode_component_array(b"COMPONENT a 0 -1\n")).contains("invalid component count: \"-1\""));
+    assert!(message(decode_entity_array(b"ENTITIES lots\n")).contains("invalid entity count: \"lots\""));
+    assert!(message(decode_world(b"WORLD x 0\n")).contains("invalid entity array count: \"x\""));
+    assert!(message(decode_world(b"WORLD 0 70000\n")).contains("invalid maximum component ID: \"70000\""));
+    assert!(message(decode_world(b"WORLD 0 0 v6\n")).contains("invalid format version: \"v6\""));
+}
+
+#[test]
+fn decode_max_total_bytes() {
+    // a world made of many small arrays, none of which is large on its own
+    let mut builder = WorldBuilder::new();
+    for id in 0..50 {
+        builder = builder.component(format!("c{}", id), ComponentId(id), vec!["x"]);
+    }
+    for id in 0..50 {
+        builder = builder.spawn(vec![(ComponentId(id), vec![Value::Int(id as i64)])]);
+    }
+    let encoded = encode_world(&builder.build().unwrap());
+    assert!(encoded.len() > 1000);
+
+    let mut state = decode::State::new(&encoded[..]);
+    state.set_max_total_bytes(256);
+    let err = state.decode_world().unwrap_err();
+    assert_eq!(err.kind(), decode::ErrorKind::LengthLimitExceeded);
+    assert_eq!(err.to_string(), "at byte 256: expected input of at most 256 bytes, got more bytes");
+
+    // input which ends exactly at the budget is accepted
+    let mut state = decode::State::new(&encoded[..]);
+    state.set_max_total_bytes(encoded.len());
+    assert_eq!(state.decode_world().unwrap(), decode_world(&encoded).unwrap());
+    let mut state = decode::State::new(&encoded[..]);
+    state.set_max_total_bytes(encoded.len() - 1);
+    assert_eq!(state.decode_world().unwrap_err().kind(), decode::ErrorKind::LengthLimitExceeded);
+
+    // the budget is not lifted by a smaller limit on a single value
+    let mut state = decode::State::new(&b"\x92\x01\x02"[..]);
+    state.set_max_total_bytes(2);
+    assert_eq!(state.decode_value_within(10).unwrap_err().kind(), decode::ErrorKind::LengthLimitExceeded);
+}
+
+// Regression tests for inputs which used to make decoding panic, overflow
+// the stack, or abort on a huge allocation.
+#[test]
+fn decode_hostile_input() {
+    use decode::ErrorKind;
+
+    // deeply nested values
+    let deep = vec![0xad; 100_000];
+    assert_eq!(decode_value(&deep).err().unwrap().kind(), ErrorKind::DepthLimitExceeded);
+    let deep = vec![0x91; 100_000];
+    assert_eq!(decode_value(&deep).err().unwrap().kind(), ErrorKind::DepthLimitExceeded);
+
+    // ok: nesting up to the limit
+    {
+        let mut nested = vec![0xad; encode::DEFAULT_MAX_DEPTH - 1];
+        nested.push(0xac);
+        let val = decode_value(&nested).unwrap();
+        assert_eq!(encode_value(&val), nested);
+
+        let mut state = decode::State::new(&nested[..]);
+        state.set_max_depth(10);
+        assert!(state.decode_value().is_err());
+    }
+
+    // huge lengths with no data behind them
+    assert_eq!(decode_value(b"\xa1\xff\xff\xff\xff").err().unwrap().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(decode_value(b"\xa3\xff\xff\xff\xff").err().unwrap().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(decode_value(b"\xb3\xff\xff\xff\xff").err().unwrap().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(
+        decode_value(b"\xb5\x08\xff\xff\xff\xff").err().unwrap().kind(),
+        ErrorKind::UnexpectedEof,
+    );
+    assert_eq!(
+        decode_entity_array(b"ENTITIES 4294967295\n").err().unwrap().kind(),
+        ErrorKind::UnexpectedEof,
+    );
+    assert_eq!(
+        decode_component_array(b"COMPONENT a 0 4294967295 x\n").err().unwrap().kind(),
+        ErrorKind::UnexpectedEof,
+    );
+
+    // a billion components on a short body: no huge allocation up front
+    assert_eq!(
+        decode_component_array(b"COMPONENT a 0 1000000000 w x y z\n\x01\x02").err().unwrap().kind(),
+        ErrorKind::UnexpectedEof,
+    );
+    assert_eq!(
+        decode_component_array(b"COMPONENT a 0 1000000000 v w x y z\n\x01\x02").err().unwrap().kind(),
+        ErrorKind::LengthLimitExceeded,
+    );
+
+    // component counts whose total number of values overflows
+    assert_eq!(
+        decode_component_array(b"COMPONENT a 0 4294967295 x y\n").err().unwrap().kind(),
+        ErrorKind::LengthLimitExceeded,
+    );
+
+    // a product which would wrap around to zero in 32 bits
+    assert_eq!(
+        decode_component_array(b"COMPONENT a 0 2147483648 x y\n").err().unwrap().kind(),
+        ErrorKind::LengthLimitExceeded,
+    );
+
+    // arrays too long for an 8-bit length used to be re-encoded with the wrong tag
+    {
+        let mut encoded = vec![0xa3, 0x00, 0x00, 0x01, 0x00];
+        encoded.resize(encoded.len() + 0x100, 0x00);
+        check_value_round_trip(&encoded, Value::Array(vec![Value::Int(0); 0x100]));
+    }
+}
+
+#[cfg(feature = "fuzz")]
+#[test]
+fn fuzz_decode_round_trips() {
+    fuzz::fuzz_decode(&linked_world());
+    fuzz::fuzz_decode(b"");
+    fuzz::fuzz_decode(&world_fixture(0, 0, b"GLOBAL x\n\xa7\x7f\xf8\x00\x00\x00\x00\x00\x01\nENTITIES 0\n"));
+}
+
+#[test]
+fn world_check_component_refs() {
+    use error::BuildError;
+
+    let world = decode_world(&linked_world()).unwrap();
+    assert_eq!(world.check_component_refs(), Ok(()));
+
+    // error: an entity refers to a component ID with no array
+    let mut world = decode_world(&linked_world()).unwrap();
+    world.entities.entries[1].components.push(ComponentIdx { id: 7, idx: 0 });
+    assert_eq!(world.check_component_refs(), Err(BuildError::UnknownComponent(ComponentId(7))));
+
+    // error: an entity refers to an index past the end of the array
+    let mut world = decode_world(&linked_world()).unwrap();
+    let id = ComponentId(world.entities.entries[1].components[0].id);
+    world.entities.entries[1].components[0].idx = 2;
+    assert_eq!(world.check_component_refs(), Err(BuildError::MissingComponent { id, idx: 2 }));
+
+    // the check is opt-in, so encoding doesn't perform it
+    assert!(world.to_vec().is_ok());
+
+    // ok: deleted entities aren't written, so their references don't matter
+    world.entities.entries[1].is_deleted = true;
+    assert_eq!(world.check_component_refs(), Ok(()));
+}
+
+#[test]
+fn world_format_versions() {
+    use decode::ErrorKind;
+
+    // ok: version 0 worlds have no version field
+    {
+        let mut state = decode::State::new(&b"WORLD 0 0\nGLOBAL x\n\x01\nENTITIES 0\n"[..]);
+        let world = state.decode_world().unwrap();
+        assert_eq!(state.version(), 0);
+        assert_eq!(world.global.get().field("x"), Some(&Value::Int(1)));
+
+        // re-encoding writes the current version
+        assert_eq!(encode_world(&world), &world_fixture(0, 0, b"GLOBAL x\n\x01\nENTITIES 0\n")[..]);
+    }
+
+    // ok: versioned worlds
+    {
+        let mut state = decode::State::new(&b"WORLD 0 0 1\nGLOBAL x\n\xb2\x01a\nENTITIES 0\n"[..]);
+        let world = state.decode_world().unwrap();
+        assert_eq!(state.version(), 1);
+        assert_eq!(world.global.get().field("x"), Some(&Value::Str("a".to_string())));
+    }
+
+    // error: value tags which didn't exist in version 0
+    assert_eq!(
+        decode_world(b"WORLD 0 0\nGLOBAL x\n\xb2\x01a\nENTITIES 0\n").err().unwrap().kind(),
+        ErrorKind::InvalidTag,
+    );
+
+    // error: versions newer than this library, and malformed versions
+    assert_eq!(
+        decode_world(format!("WORLD 0 0 {}\nGLOBAL\n\nENTITIES 0\n", decode::FORMAT_VERSION + 1).as_bytes()).err().unwrap().kind(),
+        ErrorKind::UnsupportedVersion,
+    );
+    assert_eq!(
+        decode_world(b"WORLD 0 0 x\nGLOBAL\n\nENTITIES 0\n").err().unwrap().kind(),
+        ErrorKind::InvalidNumber,
+    );
+    assert_eq!(
+        decode_world(b"WORLD 0 0 1 1\nGLOBAL\n\nENTITIES 0\n").err().unwrap().kind(),
+        ErrorKind::MalformedHeader,
+    );
+}
+
+#[test]
+fn world_diff_and_patch() {
+    use error::BuildError;
+
+    type Contents = Vec<(String, Vec<Value>)>;
+
+    fn contents(world: &WorldContext, num_entities: u32) -> Vec<Option<Contents>> {
+        (0..num_entities)
+            .map(|i| world.entity_view(EntityId::Idx(i)).map(|view| {
+                view.map(|(name, comp)| (name.to_string(), comp.values.to_vec())).collect()
+            }))
+            .collect()
+    }
+
+    let mut old = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x", "y"])
+        .component("tag", ComponentId(1), Vec::<String>::new())
+        .global(vec!["time"], vec![Value::Int(5)])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1), Value::Int(2)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(3), Value::Int(4)]), (ComponentId(1), vec![])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(5), Value::Int(6)])])
+        .build()
+        .unwrap();
+
+    let mut new = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x", "y"])
+        .component("tag", ComponentId(1), Vec::<String>::new())
+        .component("health", ComponentId(2), vec!["hp"])
+        .global(vec!["time"], vec![Value::Int(6)])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1), Value::Int(2)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(3), Value::Int(7)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(5), Value::Int(6)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(0), Value::Int(0)]), (ComponentId(2), vec![Value::Int(10)])])
+        .build()
+        .unwrap();
+    new.retain_entities(|id, _| id != EntityId::Idx(2));
+
+    // a world has no changes from itself
+    assert!(old.diff(&old).unwrap().is_empty());
+
+    let patch = old.diff(&new).unwrap();
+    assert!(!patch.is_empty());
+
+    // the patch round-trips through its encoding
+    let mut encoded = Vec::new();
+    patch.to_writer(&mut encoded).unwrap();
+    assert!(encoded.starts_with(b"PATCH 4 1 1 3\nCOMPONENT health 2 0 hp\n"));
+    assert_eq!(WorldPatch::from_reader(&encoded[..]).unwrap(), patch);
+
+    // applying the patch turns the old world into the new one
+    old.apply_patch(&patch).unwrap();
+    assert!(old.diff(&new).unwrap().is_empty());
+    assert_eq!(contents(&old, 4), contents(&new, 4));
+    assert_eq!(old.global.get().values, &[Value::Int(6)]);
+    assert!(old.entity_view(EntityId::Idx(2)).is_none());
+
+    // error: the patch has already been applied, so the world is left unchanged
+    assert_eq!(old.apply_patch(&patch), Err(BuildError::DuplicateComponentId(ComponentId(2))));
+    assert!(old.diff(&new).unwrap().is_empty());
+
+    // error: truncated patch
+    assert!(WorldPatch::from_reader(&encoded[..encoded.len() - 1]).is_err());
+
+    // floats are compared by their bits: NaN is unchanged, but -0.0 isn't 0.0
+    let floats = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x"])
+        .global(vec!["g"], vec![Value::Float(f64::NAN)])
+        .spawn(vec![(ComponentId(0), vec![Value::Float(f64::NAN)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Float(0.0)])])
+        .build()
+        .unwrap();
+    assert!(floats.diff(&floats.deep_clone()).unwrap().is_empty());
+    let mut negated = floats.deep_clone();
+    negated.component_for_entity_mut(EntityId::Idx(1), "pos").unwrap().set_field("x", Value::Float(-0.0));
+    let patch = floats.diff(&negated).unwrap();
+    assert_eq!(patch.entities.len(), 1);
+    let mut patched = floats.deep_clone();
+    patched.apply_patch(&patch).unwrap();
+    match patched.component_for_entity(EntityId::Idx(1), "pos").unwrap().field("x") {
+        Some(Value::Float(x)) => assert_eq!(x.to_bits(), (-0.0f64).to_bits()),
+        other => panic!("unexpected field: {:?}", other),
+    }
+
+    // error: a changed field whose index doesn't fit in a patch
+    let names: Vec<String> = (0..0x10001).map(|i| format!("f{}", i)).collect();
+    let values = vec![Value::Int(0); names.len()];
+    let wide = WorldContext { global: GlobalComponent::new(names.clone(), values.clone()), ..Default::default() };
+    let mut changed = values;
+    changed[0x10000] = Value::Int(1);
+    let wider = WorldContext { global: GlobalComponent::new(names, changed), ..Default::default() };
+    assert_eq!(wide.diff(&wider), Err(BuildError::TooManyFields { id: None, count: 0x10001 }));
+}
+
+#[test]
+fn world_resolve_packed() {
+    let mut world = WorldBuilder::new()
+        .component("tag", ComponentId(0), Vec::<String>::new())
+        .spawn(vec![(ComponentId(0), vec![])])
+        .spawn(vec![(ComponentId(0), vec![])])
+        .spawn(vec![(ComponentId(0), vec![])])
+        .build()
+        .unwrap();
+
+    // no deletions: IDs are unchanged, unless they are out of range
+    assert_eq!(world.resolve_packed(EntityId::Idx(1)), EntityId::Idx(1));
+    assert_eq!(world.resolve_packed(EntityId::Idx(3)), EntityId::Invalid);
+    assert_eq!(world.resolve_packed(EntityId::Invalid), EntityId::Invalid);
+
+    world.retain_entities(|id, _| id != EntityId::Idx(1));
+
+    // live entities move down to fill the gaps
+    assert_eq!(world.resolve_packed(EntityId::Idx(0)), EntityId::Idx(0));
+    assert_eq!(world.resolve_packed(EntityId::Idx(2)), EntityId::Idx(1));
+
+    // deleted, out-of-range, and invalid IDs become invalid
+    assert_eq!(world.resolve_packed(EntityId::Idx(1)), EntityId::Invalid);
+    assert_eq!(world.resolve_packed(EntityId::Idx(3)), EntityId::Invalid);
+    assert_eq!(world.resolve_packed(EntityId::Invalid), EntityId::Invalid);
+}
+
+#[test]
+fn component_fields() {
+    let mut array = decode_component_array(b"COMPONENT point 0 1 x y\n\x01\x02").unwrap();
+
+    let fields: Vec<_> = array.get(0).unwrap().fields().collect();
+    assert_eq!(fields, vec![("x", &Value::Int(1)), ("y", &Value::Int(2))]);
+
+    for (name, value) in array.get_mut(0).unwrap().fields_mut() {
+        if name == "y" {
+            *value = Value::Int(3);
+        }
+    }
+    let fields: Vec<_> = array.get(0).unwrap().fields().collect();
+    assert_eq!(fields, vec![("x", &Value::Int(1)), ("y", &Value::Int(3))]);
+
+    // marker components have no fields
+    let marker = decode_component_array(b"COMPONENT tag 0 0\n").unwrap();
+    assert_eq!(marker.get(0).unwrap().fields().count(), 0);
+}
+
+#[test]
+fn component_get_out_of_range() {
+    let mut array = decode_component_array(b"COMPONENT point 0 2 x y\n\x01\x02\x03\x04").unwrap();
+    assert_eq!(array.get(1).unwrap().values, &[Value::Int(3), Value::Int(4)]);
+    assert!(array.get(2).is_none());
+
+    // indices whose offset overflows a u32 are out of range rather than wrapping
+    for &idx in &[u32::MAX, u32::MAX / 2 + 1, u32::MAX / 2] {
+        assert!(array.get(idx).is_none());
+        assert!(array.get_mut(idx).is_none());
+    }
+
+    let mut single = decode_component_array(b"COMPONENT n 0 1 n\n\x05").unwrap();
+    assert!(single.get(u32::MAX).is_none());
+    assert!(single.get_mut(u32::MAX).is_none());
+
+    // marker components only have index zero
+    let marker = decode_component_array(b"COMPONENT tag 1 3\n").unwrap();
+    assert_eq!(marker.get(0).unwrap().values, &[]);
+    assert!(marker.get(1).is_none());
+    assert!(marker.get(u32::MAX).is_none());
+}
+
+#[test]
+fn component_set_values() {
+    use error::BuildError;
+
+    let mut array = decode_component_array(b"COMPONENT point 0 1 x y\n\x01\x02").unwrap();
+    assert!(!array.is_dirty());
+
+    array.set_values((10..16).map(Value::Int).collect()).unwrap();
+    assert_eq!(array.len(), 3);
+    assert!(array.is_dirty());
+    assert_eq!(array.get(0).unwrap().values, &[Value::Int(10), Value::Int(11)]);
+    assert_eq!(array.get(2).unwrap().values, &[Value::Int(14), Value::Int(15)]);
+    assert!(array.get(3).is_none());
+    assert_eq!(encode_component_array(&array), &b"COMPONENT point 0 3 x y\n\x0a\x0b\x0c\x0d\x0e\x0f"[..]);
+
+    // error: an incomplete last row, which leaves the array unchanged
+    assert_eq!(
+        array.set_values(vec![Value::Int(1); 5]),
+        Err(BuildError::ValueCount { id: ComponentId(0), expected: 2, got: 1 }),
+    );
+    assert_eq!(array.len(), 3);
+
+    // marker components can only be given no values
+    let mut marker = decode_component_array(b"COMPONENT tag 1 0\n").unwrap();
+    marker.set_values(vec![]).unwrap();
+    assert_eq!(
+        marker.set_values(vec![Value::Int(1)]),
+        Err(BuildError::ValueCount { id: ComponentId(1), expected: 0, got: 1 }),
+    );
+
+    array.set_values(vec![]).unwrap();
+    assert!(array.is_empty());
+}
+
+#[test]
+fn entity_id_lua_conversion() {
+    assert!(EntityId::Idx(0).is_valid());
+    assert!(!EntityId::Invalid.is_valid());
+    assert_eq!(Value::EntityId(EntityId::Invalid).as_entity_id(), Some(EntityId::Invalid));
+    assert_eq!(Value::EntityId(EntityId::Idx(3)).as_entity_id(), Some(EntityId::Idx(3)));
+    assert_eq!(Value::Int(3).as_entity_id(), None);
+
+    rlua::Lua::new().context(|ctx| {
+        let globals = ctx.globals();
+
+        // invalid IDs become nil, and back
+        globals.set("id", EntityId::Invalid).unwrap();
+        assert!(ctx.load("id == nil").eval::<bool>().unwrap());
+        assert_eq!(globals.get::<_, EntityId>("id").unwrap(), EntityId::Invalid);
+
+        // valid IDs become integers, and back
+        globals.set("id", EntityId::Idx(7)).unwrap();
+        assert!(ctx.load("id == 7").eval::<bool>().unwrap());
+        assert_eq!(globals.get::<_, EntityId>("id").unwrap(), EntityId::Idx(7));
+
+        // error: not an entity ID
+        assert!(ctx.load("-1").eval::<EntityId>().is_err());
+        assert!(ctx.load("'foo'").eval::<EntityId>().is_err());
+    });
+}
+
+#[test]
+fn validate_lua_system() {
+    let mut world = World::<u32, ()>::new();
+
+    // ok: evaluates to a function
+    assert!(world.validate_lua_system(b"return function(ctx) end").is_ok());
+    assert!(world.validate_lua_system(b"local fmt = string.format; return function(ctx) end").is_ok());
+
+    // error: syntax error, or not a function
+    assert!(world.validate_lua_system(b"return function(ctx").is_err());
+    assert!(world.validate_lua_system(b"return 5").is_err());
+
+    // validation has no side effects on globals or the registered systems
+    assert!(world.validate_lua_system(b"x = 1; return function() end").is_ok());
+    assert!(world.validate_lua_system(b"_G.y = 1; rawset(_G, 'z', 1); return function() end").is_ok());
+    assert!(world.validate_lua_system(b"string.format = nil; math = nil; return function() end").is_ok());
+    assert!(matches!(world.system_info(&0), ScriptType::None));
+    world.register_lua_system(0, b"return function(ctx)
+        assert(x == nil and y == nil and z == nil)
+        assert(string.format and math.floor)
+    end").unwrap();
+    assert!(world.run_system(&0).unwrap());
+}
+
+#[test]
+fn value_encoding_float_policy() {
+    use encode::FloatPolicy;
+
+    fn encode_with(policy: FloatPolicy, val: f64) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut state = encode::State::new(&mut encoded);
+        state.set_float_policy(policy);
+        state.encode_value(&Value::Float(val), &mut |_| {}).unwrap();
+        encoded
+    }
+
+    let pi = std::f64::consts::PI;
+
+    // auto: 64 bits unless the value fits exactly in 32
+    assert_eq!(encode_with(FloatPolicy::Auto, pi)[0], 0xa7);
+    assert_eq!(encode_with(FloatPolicy::Auto, 0.5)[0], 0xa6);
+
+    // always 32 bits, losing precision
+    let encoded = encode_with(FloatPolicy::AlwaysF32, pi);
+    assert_eq!(encoded[0], 0xa6);
+    assert_eq!(decode_value(&encoded).unwrap(), Value::Float(pi as f32 as f64));
+
+    // always 64 bits
+    assert_eq!(encode_with(FloatPolicy::AlwaysF64, pi)[0], 0xa7);
+    assert_eq!(encode_with(FloatPolicy::AlwaysF64, 0.5), b"\xa7\x3f\xe0\0\0\0\0\0\0");
+}
+
+#[test]
+fn world_deep_clone() {
+    let world = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x"])
+        .global(vec!["time"], vec![Value::Int(5)])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(2)])])
+        .build()
+        .unwrap();
+    let original = encode_world(&world);
+
+    let mut clone = world.deep_clone();
+    assert_eq!(encode_world(&clone), original);
+
+    // mutating the clone leaves the original unchanged
+    *clone.component_for_entity_mut(EntityId::Idx(0), "pos").unwrap().field_mut("x").unwrap() = Value::Int(3);
+    clone.global.get_mut().values[0] = Value::Int(6);
+    clone.retain_entities(|id, _| id != EntityId::Idx(1));
+    assert_ne!(encode_world(&clone), original);
+    assert_eq!(encode_world(&world), original);
+}
+
+#[test]
+fn world_component_observers() {
+    use std::sync::{Arc, Mutex};
+
+    let mut world = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x", "y"])
+        .component("vel", ComponentId(1), vec!["dx"])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1), Value::Int(2)]), (ComponentId(1), vec![Value::Int(0)])])
+        .build()
+        .unwrap();
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let log = changes.clone();
+    world.on_component_changed(ComponentId(0), move |id, field, value| {
+        log.lock().unwrap().push((id, field.to_string(), value.clone()));
+    });
+
+    // setting a field fires the callback
+    let mut pos = world.component_for_entity_mut(EntityId::Idx(0), "pos").unwrap();
+    assert_eq!(pos.set_field("y", Value::Int(5)), Some(Value::Int(2)));
+    assert_eq!(pos.set_field("z", Value::Int(5)), None);
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![(EntityId::Idx(0), "y".to_string(), Value::Int(5))],
+    );
+
+    // direct mutation and other components don't
+    pos.values[0] = Value::Int(7);
+    let mut vel = world.component_for_entity_mut(EntityId::Idx(0), "vel").unwrap();
+    vel.set_field("dx", Value::Int(1));
+    assert_eq!(changes.lock().unwrap().len(), 1);
+    assert_eq!(world.component_for_entity(EntityId::Idx(0), "pos").unwrap().field("x"), Some(&Value::Int(7)));
+}
+
+#[test]
+fn world_component_observers_shared() {
+    use std::sync::{Arc, Mutex};
+
+    let mut world = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x"])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(2)])])
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1)])])
+        .build()
+        .unwrap();
+    world.intern_components();
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let log = changes.clone();
+    world.on_component_changed(ComponentId(0), move |id, _, value| {
+        log.lock().unwrap().push((id, value.clone()));
+    });
+
+    // entities 0 and 2 share a component, so setting it changes and reports both
+    let mut pos = world.component_for_entity_mut(EntityId::Idx(2), "pos").unwrap();
+    pos.set_field("x", Value::Int(3));
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![(EntityId::Idx(0), Value::Int(3)), (EntityId::Idx(2), Value::Int(3))],
+    );
+    assert_eq!(world.component_for_entity(EntityId::Idx(0), "pos").unwrap().field("x"), Some(&Value::Int(3)));
+    assert_eq!(world.component_for_entity(EntityId::Idx(1), "pos").unwrap().field("x"), Some(&Value::Int(2)));
+}
+
+#[test]
+fn world_component_counts() {
+    let world = WorldBuilder::new()
+        .component("vel", ComponentId(4), vec!["dx"])
+        .component("pos", ComponentId(1), vec!["x", "y"])
+        .component("tag", ComponentId(2), Vec::<String>::new())
+        .spawn(vec![(ComponentId(1), vec![Value::Int(0), Value::Int(0)]), (ComponentId(2), vec![])])
+        .spawn(vec![(ComponentId(1), vec![Value::Int(1), Value::Int(1)]), (ComponentId(4), vec![Value::Int(1)])])
+        .spawn(vec![(ComponentId(1), vec![Value::Int(2), Value::Int(2)])])
+        .build()
+        .unwrap();
+
+    assert_eq!(world.component_counts(), vec![(ComponentId(1), "pos", 3), (ComponentId(2), "tag", 0), (ComponentId(4), "vel", 1)]);
+    assert!(WorldContext::default().component_counts().is_empty());
+}
+
+#[test]
+fn world_intern_field_names() {
+    use std::sync::Arc;
+
+    let b = world_fixture(2, 1, b"\
+        COMPONENT pos 0 1 x y\n\x01\x02\n\
+        COMPONENT vel 1 1 x y\n\x03\x04\n\
+        GLOBAL x\n\x05\n\
+        ENTITIES 0\n");
+
+    // by default, every field name is a separate allocation
+    let world = decode_world(&b).unwrap();
+    let (pos, vel) = (&world.components[0], &world.components[1]);
+    assert!(!Arc::ptr_eq(&pos.scheme()[0], &vel.scheme()[0]));
+
+    // when interning, identical names share one
+    let mut state = decode::State::new(&b[..]);
+    state.set_intern_field_names(true);
+    let world = state.decode_world().unwrap();
+    let (pos, vel) = (&world.components[0], &world.components[1]);
+    assert_eq!(pos.scheme(), &[FieldName::from("x"), FieldName::from("y")]);
+    assert_eq!(vel.scheme(), &[FieldName::from("x"), FieldName::from("y")]);
+    assert!(Arc::ptr_eq(&pos.scheme()[0], &vel.scheme()[0]));
+    assert!(Arc::ptr_eq(&pos.scheme()[1], &vel.scheme()[1]));
+    assert!(Arc::ptr_eq(&pos.scheme()[0], &world.global.scheme()[0]));
+
+    // the decoded values are unaffected
+    assert_eq!(vel.get(0).unwrap().field("y"), Some(&Value::Int(4)));
+    assert_eq!(encode_world(&world), &b[..]);
+}
+
+#[test]
+fn world_load_and_save() {
+    let path = std::env::temp_dir().join(format!("serial_ecs_save_{}.world", std::process::id()));
+
+    let world = decode_world(&linked_world()).unwrap();
+    world.save(&path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), linked_world());
+    assert_eq!(WorldContext::load(&path).unwrap(), world);
+
+    // the same through a Lua world, overwriting the file
+    let lua_world = World::<(), ()>::load(&path).unwrap();
+    lua_world.context_mut(|ctx| ctx.spawn(vec![]).unwrap());
+    lua_world.save(&path).unwrap();
+    let reloaded = World::<(), ()>::load(&path).unwrap();
+    assert_eq!(reloaded, lua_world);
+    assert!(reloaded != World::from_context(world));
+
+    // error: trailing bytes, or no file
+    std::fs::write(&path, [&linked_world()[..], b"x"].concat()).unwrap();
+    match WorldContext::load(&path) {
+        Err(error::Error::Decode(err)) => assert_eq!(err.kind(), decode::ErrorKind::TrailingBytes),
+        other => panic!("{:?}", other),
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(WorldContext::load(&path), Err(error::Error::Decode(decode::Error::Io(_)))));
+}
+
+#[test]
+fn world_raw_component() {
+    // precompute the bytes of one component array
+    let mut array = ComponentArray::new("vel".to_string(), 1, vec!["dx".to_string()]);
+    array.push(vec![Value::Int(3)]);
+    let mut raw = encode_component_array(&array);
+    raw.push(b'\n');
+
+    let mut encoded = Vec::new();
+    let mut state = encode::State::new(&mut encoded);
+    state.write(&world_fixture(2, 1, b"")).unwrap();
+    state.write(b"COMPONENT pos 0 1 x\n\x01\n").unwrap();
+    state.write_raw_component(&raw).unwrap();
+    state.write(b"GLOBAL\n\nENTITIES 1\n\x02\xc0\xc1").unwrap();
+
+    let world = decode_world(&encoded).unwrap();
+    let vel = world.component_for_entity(EntityId::Idx(0), "vel").unwrap();
+    assert_eq!(vel.field("dx"), Some(&Value::Int(3)));
+
+    // error: malformed, missing the trailing newline, or followed by more data
+    let mut state = encode::State::new(Vec::new());
+    assert!(state.write_raw_component(b"COMPONENT vel 1 1 dx\n").is_err());
+    assert!(state.write_raw_component(&raw[..raw.len() - 1]).is_err());
+    assert!(state.write_raw_component(&[&raw[..], b"GLOBAL\n"].concat()).is_err());
+    assert!(state.into_inner().is_empty());
+}
+
+#[test]
+fn world_max_component_id() {
+    let b = world_fixture(2, 7, b"\
+        COMPONENT vel 7 0 dx dy\n\n\
+        COMPONENT pos 3 0 x y\n\n\
+        GLOBAL\n\n\
+        ENTITIES 0\n");
+
+    // the header can be read on its own
+    let mut state = decode::State::new(&b[..]);
+    assert_eq!(state.decode_world_header().unwrap(), (2, ComponentId(7)));
+
+    let world = decode_world(&b).unwrap();
+    assert_eq!(world.max_component_id(), ComponentId(7));
+    assert_eq!(WorldContext::default().max_component_id(), ComponentId(0));
+
+    // use it to pick a fresh ID for a new component
+    let new_id = world.max_component_id().0 + 1;
+    assert!(world.component_ids().all(|id| id.0 != new_id));
+    let mut patched = world.deep_clone();
+    let mut patch = WorldPatch::default();
+    patch.new_components.push(("health".to_string(), ComponentId(new_id), vec![FieldName::from("hp")]));
+    patched.apply_patch(&patch).unwrap();
+    assert_eq!(patched.max_component_id(), ComponentId(8));
+    assert!(encode_world(&patched).starts_with(&world_fixture(3, 8, b"")));
+}
+
+#[test]
+fn world_component_by_name() {
+    let mut world = WorldBuilder::new()
+        .component("pos", ComponentId(3), vec!["x"])
+        .component("tag", ComponentId(7), Vec::<String>::new())
+        .build()
+        .unwrap();
+
+    let pos = world.component_by_name("pos").unwrap();
+    assert_eq!(pos, ComponentId(3));
+    assert_eq!(world.component_by_name("tag"), Some(ComponentId(7)));
+    assert_eq!(world.component_by_name("vel"), None);
+
+    // the ID can be passed straight back to the world
+    let e = world.spawn(vec![(pos, vec![Value::Int(1)])]).unwrap();
+    let tag = world.component_by_name("tag").unwrap();
+    world.add_component(e, tag, vec![]).unwrap();
+    assert_eq!(world.query_entities(&[pos, tag]).collect::<Vec<_>>(), vec![e]);
+    assert!(world.component_ids().eq(vec![pos, tag]));
+
+    // IDs order by their number, and the number is still available
+    assert!(pos < tag);
+    assert_eq!(world.max_component_id().0, 7);
+}
+
+#[test]
+fn world_free_component_ids() {
+    fn ids(ids: &[u16]) -> Vec<ComponentId> {
+        ids.iter().copied().map(ComponentId).collect()
+    }
+
+    let mut world = WorldBuilder::new()
+        .component("a", ComponentId(0), Vec::<String>::new())
+        .component("c", ComponentId(2), Vec::<String>::new())
+        .component("f", ComponentId(5), Vec::<String>::new())
+        .build()
+        .unwrap();
+
+    assert_eq!(world.free_component_ids(ComponentId(7)).collect::<Vec<_>>(), ids(&[1, 3, 4, 6, 7]));
+    assert_eq!(world.free_component_ids(ComponentId(5)).collect::<Vec<_>>(), ids(&[1, 3, 4]));
+    assert_eq!(world.free_component_ids(ComponentId(0)).count(), 0);
+    assert_eq!(world.free_component_ids(ComponentId(u16::MAX)).count(), 65536 - 3);
+    assert_eq!(WorldContext::default().free_component_ids(ComponentId(2)).collect::<Vec<_>>(), ids(&[0, 1, 2]));
+
+    // new arrays take the lowest free ID
+    assert_eq!(world.add_component_array("b".to_string(), vec![]), Ok(ComponentId(1)));
+    assert_eq!(world.free_component_ids(ComponentId(7)).collect::<Vec<_>>(), ids(&[3, 4, 6, 7]));
+}
+
+#[test]
+fn world_remap_component_id() {
+    use error::BuildError;
+
+    let mut world = WorldBuilder::new()
+        .component("a", ComponentId(0), vec!["x"])
+        .component("b", ComponentId(1), Vec::<String>::new())
+        .component("c", ComponentId(2), Vec::<String>::new())
+        .spawn(vec![(ComponentId(0), vec![Value::Int(1)]), (ComponentId(2), vec![])])
+        .build()
+        .unwrap();
+    let id = EntityId::Idx(0);
+
+    // ok: the entity keeps its components, and they stay sorted by ID
+    world.remap_component_id(ComponentId(0), ComponentId(5)).unwrap();
+    assert_eq!(world.component_by_name("a"), Some(ComponentId(5)));
+    assert_eq!(world.max_component_id(), ComponentId(5));
+    assert_eq!(world.entities.entries[0].component_ids().collect::<Vec<_>>(), vec![ComponentId(2), ComponentId(5)]);
+    assert_eq!(world.component_for_entity(id, "a").unwrap().field("x"), Some(&Value::Int(1)));
+    assert_eq!(world.query_entities(&[ComponentId(5)]).collect::<Vec<_>>(), vec![id]);
+
+    // ok: the new ID is written out
+    let decoded = decode_world(&world.to_vec().unwrap()).unwrap();
+    assert_eq!(decoded.component_by_name("a"), Some(ComponentId(5)));
+    assert_eq!(world.remap_component_id(ComponentId(5), ComponentId(5)), Ok(()));
+
+    // error: no array with the old ID, or one already has the new ID
+    assert_eq!(world.remap_component_id(ComponentId(0), ComponentId(6)), Err(BuildError::UnknownComponent(ComponentId(0))));
+    assert_eq!(world.remap_component_id(ComponentId(5), ComponentId(1)), Err(BuildError::DuplicateComponentId(ComponentId(1))));
+    assert_eq!(world.component_by_name("a"), Some(ComponentId(5)));
+}
+
+#[test]
+fn world_table_to_row() {
+    let ctx = WorldBuilder::new()
+        .component("point", ComponentId(0), vec!["x", "y"])
+        .build()
+        .unwrap();
+    let world = World::<(), ()>::from_context(ctx);
+
+    rlua::Lua::new().context(|lua| {
+        let table = lua.load("{x=1, y=2, z=3}").eval::<rlua::Table>().unwrap();
+        assert_eq!(world.table_to_row("point", table).unwrap(), vec![Value::Int(1), Value::Int(2)]);
+
+        // nested tables become arrays
+        let table = lua.load("{x={true, 'a'}, y=0.5}").eval::<rlua::Table>().unwrap();
+        assert_eq!(world.table_to_row("point", table).unwrap(), vec![
+            Value::Array(vec![Value::Bool(true), Value::Str("a".to_string())]),
+            Value::Float(0.5),
+        ]);
+
+        // error: missing field, or unknown component
+        let table = lua.load("{x=1}").eval::<rlua::Table>().unwrap();
+        assert!(world.table_to_row("point", table.clone()).is_err());
+        assert!(world.table_to_row("line", table).is_err());
+
+        // error: a table containing itself
+        let table = lua.load("local t = {1}; t[2] = t; return {x=t, y=0}").eval::<rlua::Table>().unwrap();
+        match world.table_to_row("point", table) {
+            Err(rlua::Error::FromLuaConversionError { from: "table", .. }) => (),
+            res => panic!("expected a conversion error, got {:?}", res),
+        }
+        let table = lua.load("local t = {}; t[1] = t; return t").eval::<rlua::Table>().unwrap();
+        assert!(lua.unpack::<Value>(rlua::Value::Table(table)).is_err());
+    });
+}
+
+#[test]
+fn value_tags() {
+    let decode = |bytes: &[u8]| decode_value(bytes).unwrap();
+
+    assert_eq!(decode(&[tags::BOOL_FALSE]), Value::Bool(false));
+    assert_eq!(decode(&[tags::BOOL_TRUE]), Value::Bool(true));
+    assert_eq!(decode(&[tags::NONE]), Value::Maybe(None));
+    assert_eq!(decode(&[tags::ENTITY_INVALID]), Value::EntityId(EntityId::Invalid));
+    assert_eq!(decode(&[tags::INT_INLINE_MAX]), Value::Int(0x7f));
+    assert_eq!(decode(&[tags::I8, 0xff]), Value::Int(-1));
+    assert_eq!(decode(&[tags::BYTES_INLINE + 1, b'a']), Value::Bytes(vec![b'a']));
+    assert_eq!(decode(&[tags::STR_U8, 1, b'a']), Value::Str("a".to_string()));
+    assert_eq!(decode(&[tags::ARRAY_INLINE]), Value::Array(vec![]));
+    assert_eq!(decode(&[tags::ENTITY_INLINE_MAX]), Value::EntityId(EntityId::Idx(0x3f)));
+
+    // the inline forms are used up to their maximums
+    assert_eq!(encode_value(&Value::Bytes(vec![0; 15]))[0], tags::BYTES_INLINE_MAX);
+    assert_eq!(encode_value(&Value::Bytes(vec![0; 16]))[0], tags::BYTES_U8);
+    assert_eq!(encode_value(&Value::Array(vec![Value::Int(0); 15]))[0], tags::ARRAY_INLINE_MAX);
+    assert_eq!(encode_value(&Value::EntityId(EntityId::Idx(0x40)))[0], tags::ENTITY_U8);
+
+    // error: reserved tags
+    for tag in tags::RESERVED ..= tags::RESERVED_MAX {
+        assert!(decode_value(&[tag]).is_err());
+    }
+}
+
+#[test]
+fn entity_components_sorted() {
+    let world = WorldBuilder::new()
+        .component("a", ComponentId(0), vec!["x"])
+        .component("b", ComponentId(1), vec!["x"])
+        .component("c", ComponentId(2), vec!["x"])
+        .spawn(vec![(ComponentId(2), vec![Value::Int(2)]), (ComponentId(0), vec![Value::Int(0)]), (ComponentId(1), vec![Value::Int(1)])])
+        .build()
+        .unwrap();
+    let entity = &world.entities.entries[0];
+    assert_eq!(entity.component_ids().collect::<Vec<_>>(), vec![ComponentId(0), ComponentId(1), ComponentId(2)]);
+    for id in 0..3 {
+        assert_eq!(entity.component_idx(id), Some(id as usize));
+    }
+    assert_eq!(entity.component_idx(3), None);
+    assert_eq!(
+        world.component_for_entity(EntityId::Idx(0), "c").unwrap().field("x"),
+        Some(&Value::Int(2)),
+    );
+
+    // insertions keep the order
+    let mut entity = entity.clone();
+    entity.components.remove(1);
+    entity.insert_component(ComponentIdx { id: 1, idx: 5 });
+    entity.insert_component(ComponentIdx { id: 4, idx: 0 });
+    assert_eq!(entity.component_ids().collect::<Vec<_>>(), vec![ComponentId(0), ComponentId(1), ComponentId(2), ComponentId(4)]);
+    assert_eq!(entity.component_idx(1), Some(1));
+    assert_eq!(entity.components[1].idx, 5);
+
+    // components listed out of order in a file are sorted when decoded
+    let world = decode_world(
+        &world_fixture(2, 1, b"\
+        COMPONENT a 0 1 x\n\x00\n\
+        COMPONENT b 1 1 x\n\x01\n\
+        GLOBAL\n\n\
+        ENTITIES 1\n\
+        \x02\xc1\xc0")
+    ).unwrap();
+    assert_eq!(world.entities.entries[0].component_ids().collect::<Vec<_>>(), vec![ComponentId(0), ComponentId(1)]);
+    assert_eq!(
+        world.component_for_entity(EntityId::Idx(0), "b").unwrap().field("x"),
+        Some(&Value::Int(1)),
+    );
+}
+
+#[test]
+fn world_incremental_save() {
+    use decode::ErrorKind;
+
+    let saved = world_fixture(2, 1, b"\
+        COMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n\
+        COMPONENT target 1 1 id\n\xc0\n\
+        GLOBAL\n\n\
+        ENTITIES 2\n\
+        \x01\xc0\
+        \x02\x00\x01\xc1");
+    let mut world = decode_world(&saved).unwrap();
+    let mut base = world.deep_clone();
+    assert_eq!(world.dirty_component_ids().count(), 0);
+
+    world.component_for_entity_mut(EntityId::Idx(1), "pos").unwrap().set_field("y", Value::Int(9));
+    assert_eq!(world.dirty_component_ids().collect::<Vec<_>>(), vec![ComponentId(0)]);
+
+    // only the modified array is written
+    let mut delta = Vec::new();
+    world.encode_dirty(&mut delta).unwrap();
+    assert_eq!(delta, b"DIRTY 0\nCOMPONENT pos 0 2 x y\n\x01\x02\x03\x09\n");
+
+    base.apply_incremental(&delta[..]).unwrap();
+    assert_eq!(encode_world(&base), encode_world(&world));
+    assert_eq!(base.dirty_component_ids().count(), 0);
+
+    world.clear_dirty();
+    let mut delta = Vec::new();
+    world.encode_dirty(&mut delta).unwrap();
+    assert_eq!(delta, b"DIRTY\n");
+
+    // error: the array doesn't match the one it replaces
+    let kind = |delta: &[u8]| base.deep_clone().apply_incremental(delta).unwrap_err().kind();
+    assert_eq!(kind(b"DIRTY 2\nCOMPONENT pos 2 2 x y\n\x01\x02\x03\x04\n"), ErrorKind::MismatchedComponent);
+    assert_eq!(kind(b"DIRTY 0\nCOMPONENT pos 0 1 x\n\x01\n"), ErrorKind::MismatchedComponent);
+    assert_eq!(kind(b"DIRTY 0\nCOMPONENT pos 0 1 x y\n\x01\x02\n"), ErrorKind::MismatchedComponent);
+    assert_eq!(kind(b"DIRTY 1\nCOMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n"), ErrorKind::MismatchedComponent);
+    // error: a reference to an entity this world doesn't have, unlike this one
+    base.deep_clone().apply_incremental(&b"DIRTY 1\nCOMPONENT target 1 1 id\n\xc1\n"[..]).unwrap();
+    assert_eq!(kind(b"DIRTY 1\nCOMPONENT target 1 1 id\n\xc2\n"), ErrorKind::MismatchedComponent);
+    assert_eq!(kind(b"DIRTY 1\nCOMPONENT target 1 1 id\n\x91\xc5\n"), ErrorKind::MismatchedComponent);
+    assert_eq!(kind(b"DIRTY 0\nCOMPONENT pos 0 2 x y\n\x01\x02\x03\x04"), ErrorKind::UnexpectedEof);
+    assert_eq!(kind(b"CHANGED 0\n"), ErrorKind::BadSignature);
+}
+
+#[test]
+fn value_encoding_float_edge_cases() {
+    fn round_trip_bits(x: f64) -> (u8, u64) {
+        let encoded = encode_value(&Value::Float(x));
+        match decode_value(&encoded).unwrap() {
+            Value::Float(y) => (encoded[0], y.to_bits()),
+            v => panic!("decoded {:?}", v),
+        }
+    }
+
+    // negative zero keeps its sign, and fits in 32 bits
+    assert_eq!(round_trip_bits(-0.0), (tags::F32, (-0.0f64).to_bits()));
+    assert_eq!(round_trip_bits(0.0), (tags::F32, 0));
+
+    // the smallest subnormal double would become zero as a float
+    let tiny = f64::from_bits(1);
+    assert_eq!(round_trip_bits(tiny), (tags::F64, 1));
+    assert_eq!(round_trip_bits(-tiny), (tags::F64, (-tiny).to_bits()));
+
+    // a subnormal float is a normal double, and narrows exactly
+    let subnormal = f32::from_bits(1) as f64;
+    assert_eq!(round_trip_bits(subnormal), (tags::F32, subnormal.to_bits()));
+    let subnormal = f32::from_bits(0x0040_0000) as f64;
+    assert_eq!(round_trip_bits(subnormal), (tags::F32, subnormal.to_bits()));
+
+    // infinities fit in 32 bits
+    assert_eq!(round_trip_bits(f64::NEG_INFINITY), (tags::F32, f64::NEG_INFINITY.to_bits()));
+}
+
+#[test]
+fn world_decoding_hashed() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let mut streamed = DefaultHasher::new();
+    let mut consumed = 0;
+    let world = decode::State::new_hashing(&linked_world()[..], |bytes: &[u8]| {
+        streamed.write(bytes);
+        consumed += bytes.len();
+    }).decode_world().unwrap();
+    assert_eq!(encode_world(&world), linked_world());
+    assert_eq!(consumed, linked_world().len());
+
+    let mut expected = DefaultHasher::new();
+    expected.write(&linked_world());
+    assert_eq!(streamed.finish(), expected.finish());
+
+    // only the bytes that were decoded are hashed
+    let mut hashed = Vec::new();
+    let mut state = decode::State::new_hashing(&b"\x01\x02\x03"[..], |bytes: &[u8]| hashed.extend_from_slice(bytes));
+    assert_eq!(state.decode_value().unwrap(), Value::Int(1));
+    drop(state);
+    assert_eq!(hashed, b"\x01");
+}
+
+#[test]
+fn world_shrink_to_fit() {
+    let mut builder = WorldBuilder::new().component("hp", ComponentId(0), vec!["hp"]);
+    for i in 0..1000 {
+        builder = builder.spawn(vec![(ComponentId(0), vec![Value::Int(i)])]);
+    }
+    let mut world = builder.build().unwrap();
+    assert!(world.components[0].capacity() >= 1000);
+
+    world.retain_entities(|id, _| id == EntityId::Idx(0) || id == EntityId::Idx(999));
+    assert_eq!(world.components[0].len(), 2);
+    assert!(world.components[0].capacity() >= 1000);
+
+    world.shrink_to_fit();
+    assert!(world.components[0].capacity() < 10);
+    assert_eq!(world.component_for_entity(EntityId::Idx(999), "hp").unwrap().field("hp"), Some(&Value::Int(999)));
+
+    // marker arrays never have any capacity
+    let mut array = ComponentArray::new("marker".to_string(), 0, vec![]);
+    array.shrink_to_fit();
+    assert_eq!(array.capacity(), 0);
+}
+
+#[test]
+fn world_empty_global() {
+    let encoded = world_fixture(0, 0, b"GLOBAL\n\nENTITIES 1\n\x00");
+    let world = decode_world(&encoded).unwrap();
+    assert!(world.global.is_empty());
+    assert_eq!(world.global.get().values.len(), 0);
+    assert_eq!(encode_world(&world), &encoded[..]);
+
+    // a built world without a global component encodes it the same way
+    let world = WorldBuilder::new().spawn(vec![]).build().unwrap();
+    assert!(world.global.is_empty());
+    assert_eq!(encode_world(&world), &encoded[..]);
+
+    let mut standalone = Vec::new();
+    GlobalComponent::empty().to_writer(&mut standalone).unwrap();
+    assert_eq!(standalone, b"GLOBAL\n");
+    assert!(GlobalComponent::from_reader(&standalone[..]).unwrap().is_empty());
+}
+
+#[test]
+fn world_referrers() {
+    let world = WorldBuilder::new()
+        .component("target", ComponentId(0), vec!["id"])
+        .component("targets", ComponentId(1), vec!["ids"])
+        .global(vec!["player"], vec![Value::EntityId(EntityId::Idx(2))])
+        .spawn(vec![(ComponentId(0), vec![Value::EntityId(EntityId::Idx(1))])])
+        .spawn(vec![])
+        .spawn(vec![(ComponentId(1), vec![Value::Array(vec![
+            Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(1))))),
+            Value::EntityId(EntityId::Idx(2)),
+        ])])])
+        .build()
+        .unwrap();
+
+    let entity = |idx| Owner::Entity(EntityId::Idx(idx));
+
+    // references nested in arrays and optionals are found
+    assert_eq!(world.referrers(EntityId::Idx(1)), vec![entity(0), entity(2)]);
+    // the global component comes first
+    assert_eq!(world.referrers(EntityId::Idx(2)), vec![Owner::Global, entity(2)]);
+    assert_eq!(world.referrers(EntityId::Idx(0)), vec![]);
+    assert_eq!(world.referrers(EntityId::Invalid), vec![]);
+
+    // deleted entities don't count
+    let mut world = world;
+    world.retain_entities(|id, _| id != EntityId::Idx(0));
+    assert_eq!(world.referrers(EntityId::Idx(1)), vec![entity(2)]);
+}
+
+#[test]
+fn world_all_entity_references() {
+    let idx = EntityId::Idx;
+    let mut world = WorldBuilder::new()
+        .component("target", ComponentId(0), vec!["id"])
+        .component("targets", ComponentId(1), vec!["ids", "backup"])
+        .global(vec!["player", "none"], vec![Value::EntityId(idx(2)), Value::EntityId(EntityId::Invalid)])
+        .spawn(vec![(ComponentId(0), vec![Value::EntityId(idx(1))])])
+        .spawn(vec![])
+        .spawn(vec![
+            (ComponentId(0), vec![Value::EntityId(idx(2))]),
+            (ComponentId(1), vec![
+                Value::Array(vec![
+                    Value::Maybe(Some(Box::new(Value::EntityId(idx(1))))),
+                    Value::EntityId(idx(0)),
+                    Value::Int(3),
+                ]),
+                Value::EntityId(idx(1)),
+            ]),
+        ])
+        .build()
+        .unwrap();
+
+    let entity = |i| Owner::Entity(idx(i));
+    assert_eq!(world.all_entity_references(), vec![
+        (Owner::Global, idx(2)),
+        (entity(0), idx(1)),
+        (entity(2), idx(2)),
+        (entity(2), idx(1)),
+        (entity(2), idx(0)),
+        (entity(2), idx(1)),
+    ]);
+
+    // consistent with `referrers`
+    for target in 0..3 {
+        let mut owners: Vec<Owner> = world.all_entity_references().into_iter()
+            .filter(|&(_, referenced)| referenced == idx(target))
+            .map(|(owner, _)| owner)
+            .collect();
+        owners.dedup();
+        assert_eq!(owners, world.referrers(idx(target)));
+    }
+
+    // references from deleted entities are left out
+    world.retain_entities(|id, _| id != idx(2));
+    assert_eq!(world.all_entity_references(), vec![(Owner::Global, idx(2)), (entity(0), idx(1))]);
+    assert!(WorldContext::default().all_entity_references().is_empty());
+}
+
+#[test]
+fn world_find_reference_cycle() {
+    let parent = |id: Option<u32>| vec![Value::Maybe(id.map(|i| Box::new(Value::EntityId(EntityId::Idx(i)))))];
+    let mut world = WorldBuilder::new()
+        .component("node", ComponentId(0), vec!["parent"])
+        .component("tag", ComponentId(1), vec!["other"])
+        .spawn(vec![(ComponentId(0), parent(None))])
+        .spawn(vec![(ComponentId(0), parent(Some(0))), (ComponentId(1), vec![Value::EntityId(EntityId::Idx(1))])])
+        .spawn(vec![(ComponentId(0), parent(Some(3)))])
+        .spawn(vec![(ComponentId(0), parent(Some(2)))])
+        .spawn(vec![(ComponentId(0), parent(Some(1)))])
+        .build()
+        .unwrap();
+
+    // 2 -> 3 -> 2
+    assert_eq!(world.find_reference_cycle("node", "parent"), Some(vec![EntityId::Idx(2), EntityId::Idx(3)]));
+
+    // self-references are cycles, but only the given field is followed
+    assert_eq!(world.find_reference_cycle("tag", "other"), Some(vec![EntityId::Idx(1)]));
+    assert_eq!(world.find_reference_cycle("node", "other"), None);
+    assert_eq!(world.find_reference_cycle("none", "parent"), None);
+
+    // breaking the cycle leaves a chain 4 -> 1 -> 0 and 3 -> 2
+    *world.component_for_entity_mut(EntityId::Idx(2), "node").unwrap().field_mut("parent").unwrap() =
+        Value::Maybe(None);
+    assert_eq!(world.find_reference_cycle("node", "parent"), None);
+
+    // deleted entities are not part of any cycle
+    *world.component_for_entity_mut(EntityId::Idx(0), "node").unwrap().field_mut("parent").unwrap() =
+        Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(4)))));
+    assert_eq!(
+        world.find_reference_cycle("node", "parent"),
+        Some(vec![EntityId::Idx(0), EntityId::Idx(4), EntityId::Idx(1)]),
+    );
+    world.retain_entities(|id, _| id != EntityId::Idx(4));
+    assert_eq!(world.find_reference_cycle("node", "parent"), None);
+}
+
+#[test]
+fn world_spawn() {
+    use error::BuildError;
+
+    let mut world = WorldBuilder::new()
+        .component("pos", ComponentId(0), vec!["x", "y"])
+        .component("target", ComponentId(1), vec!["id"])
+        .build()
+        .unwrap();
+
+    let a = world.spawn(vec![
+        (ComponentId(1), vec![Value::EntityId(EntityId::Idx(0))]),
+        (ComponentId(0), vec![Value::Int(1), Value::Int(2)]),
+    ]).unwrap();
+    assert_eq!(a, EntityId::Idx(0));
+    let b = world.spawn(vec![]).unwrap();
+    world.add_component(b, ComponentId(0), vec![Value::Int(3), Value::Int(4)]).unwrap();
+
+    assert_eq!(world.entities.entries[0].component_ids().collect::<Vec<_>>(), vec![ComponentId(0), ComponentId(1)]);
+    assert_eq!(encode_world(&world), &world_fixture(2, 1, b"\
+        COMPONENT pos 0 2 x y\n\x01\x02\x03\x04\n\
+        COMPONENT target 1 1 id\n\xc0\n\
+        GLOBAL\n\n\
+        ENTITIES 2\n\
+        \x02\xc0\xc1\
+        \x01\x00\x01")[..]);
+
+    // error: the world is left unchanged
+    assert_eq!(world.spawn(vec![(ComponentId(2), vec![])]), Err(BuildError::UnknownComponent(ComponentId(2))));
+    assert_eq!(
+        world.spawn(vec![(ComponentId(0), vec![Value::Int(0)])]),
+        Err(BuildError::ValueCount { id: ComponentId(0), expected: 2, got: 1 }),
+    );
+    assert_eq!(
+        world.spawn(vec![(ComponentId(1), vec![Value::EntityId(EntityId::Idx(3))])]),
+        Err(BuildError::InvalidEntityId(3)),
+    );
+    assert_eq!(
+        world.spawn(vec![(ComponentId(1), vec![Value::EntityId(a)]), (ComponentId(1), vec![Value::EntityId(b)])]),
+        Err(BuildError::ComponentAlreadyPresent { entity: 2, id: ComponentId(1) }),
+    );
+    assert_eq!(
+        world.add_component(a, ComponentId(1), vec![Value::EntityId(b)]),
+        Err(BuildError::ComponentAlreadyPresent { entity: 0, id: ComponentId(1) }),
+    );
+    assert_eq!(
+        world.add_component(EntityId::Idx(5), ComponentId(1), vec![Value::EntityId(b)]),
+        Err(BuildError::InvalidEntityId(5)),
+    );
+    assert_eq!(world.entities.entries.len(), 2);
+    assert_eq!(world.components[1].len(), 1);
+}
+
+#[test]
+fn header_error_offsets() {
+    fn idx(err: decode::Error) -> usize {
+        match err {
+            decode::Error::Unexpected { idx, .. } => idx,
+            decode::Error::Io(err) => panic!("I/O error: {}", err),
+        }
+    }
+
+    // errors point at the start of the offending field
+    let header = b"COMPONENT stats 0 0 strength agility  stamina wisdom agility charm\n";
+    let err = ComponentArray::from_reader(&header[..]).err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::DuplicateField);
+    assert_eq!(idx(err), 53);
+    assert_eq!(&header[53..60], b"agility");
+
+    let err = ComponentArray::from_reader(&b"COMPONENT stats x 0 a\n"[..]).err().unwrap();
+    assert_eq!(idx(err), 16);
+    let err = ComponentArray::from_reader(&b"COMPONENT stats 0 -1 a\n"[..]).err().unwrap();
+    assert_eq!(idx(err), 18);
+    let err = GlobalComponent::from_reader(&b"GLOBAL a b\ta\n"[..]).err().unwrap();
+    assert_eq!(idx(err), 11);
+
+    // offsets are relative to the start of the input
+    let err = decode_world(&world_fixture(1, 0, b"COMPONENT  a 0 0 x x\n")).err().unwrap();
+    assert_eq!(idx(err), 31);
+    let err = decode_world(b"WORLD 1 0 99\n").err().unwrap();
+    assert_eq!(idx(err), 10);
+
+    let mut state = decode::State::new(&b"  A bb\r\nC\n"[..]);
+    assert_eq!(
+        state.decode_header_line_with_offsets("header").unwrap(),
+        vec![("A".to_string(), 2), ("bb".to_string(), 4)],
+    );
+    assert_eq!(state.decode_header_line_with_offsets("header").unwrap(), vec![("C".to_string(), 8)]);
+}
+
+#[test]
+fn decode_header_line_exact() {
+    fn fields(state: &mut decode::State<&[u8]>) -> Vec<(String, usize)> {
+        state.decode_header_line_exact("header").unwrap()
+    }
+    let field = |s: &str, at| (s.to_string(), at);
+
+    // empty fields are kept, including ones at either end of the line
+    let mut state = decode::State::new(&b"A  b\n c \r\n# comment\n\nx\ty\n"[..]);
+    assert_eq!(fields(&mut state), vec![field("A", 0), field("", 2), field("b", 3)]);
+    assert_eq!(fields(&mut state), vec![field("", 5), field("c", 6), field("", 8)]);
+    assert_eq!(fields(&mut state), vec![field("", 20)]);
+    assert_eq!(fields(&mut state), vec![field("x\ty", 21)]);
+
+    // the same input split on whitespace
+    let mut state = decode::State::new(&b"A  b\n c \r\n"[..]);
+    assert_eq!(state.decode_header_line("header").unwrap(), vec!["A", "b"]);
+    assert_eq!(state.decode_header_line("header").unwrap(), vec!["c"]);
+
+    // a line read ahead while looking for metadata is still split exactly
+    let encoded = world_fixture(1, 0, b"COMPONENT  a 0 0 x\n");
+    let mut state = decode::State::new(&encoded[..]);
+    state.decode_world_header().unwrap();
+    assert!(state.decode_world_metadata().unwrap().is_empty());
+    assert_eq!(fields(&mut state), vec![
+        field("COMPONENT", 12), field("", 22), field("a", 23), field("0", 25), field("0", 27), field("x", 29),
+    ]);
+
+    // error: the line must still be ASCII and end with a newline
+    let mut state = decode::State::new(&b"A \xff\n"[..]);
+    assert_eq!(state.decode_header_line_exact("header").unwrap_err().kind(), decode::ErrorKind::MalformedHeader);
+    let mut state = decode::State::new(&b"A "[..]);
+    assert_eq!(state.decode_header_line_exact("header").unwrap_err().kind(), decode::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn world_run_lua_file() {
+    let world = World::<(), ()>::new();
+    let dir = std::env::temp_dir();
+
+    let path = dir.join(format!("serial_ecs_ok_{}.lua", std::process::id()));
+    std::fs::write(&path, "return function(ctx)\n  return 6 * 7\nend\n").unwrap();
+    let result = world.run_lua_file(&path, |v| matches!(v, rlua::Value::Integer(42)));
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.unwrap());
+
+    // runtime errors refer to the file and line
+    let path = dir.join(format!("serial_ecs_err_{}.lua", std::process::id()));
+    std::fs::write(&path, "return function(ctx)\n  error('oops')\nend\n").unwrap();
+    let result = world.run_lua_file(&path, |_| ());
+    std::fs::remove_file(&path).unwrap();
+    let message = result.unwrap_err().to_string();
+    assert!(message.contains(&format!("{}:2: oops", path.display())), "{}", message);
+
+    // error: the file doesn't exist
+    assert!(world.run_lua_file(&path, |_| ()).is_err());
+}
+
+#[test]
+fn value_tags_by_version() {
+    use decode::ErrorKind;
+
+    assert_eq!(tags::introduced_in(tags::BOOL_TRUE), Some(0));
+    assert_eq!(tags::introduced_in(tags::ENTITY_INLINE_MAX), Some(0));
+    assert_eq!(tags::introduced_in(tags::STR_U8), Some(1));
+    assert_eq!(tags::introduced_in(tags::INT_ARRAY), Some(1));
+    assert_eq!(tags::introduced_in(tags::RESERVED), None);
+
+    let decode_at = |version: u32, strict: bool, bytes: &[u8]| {
+        let mut state = decode::State::new(bytes);
+        state.set_version(version);
+        state.set_strict_tags(strict);
+        state.decode_value()
+    };
+    let str_a = [tags::STR_U8, 1, b'a'];
+    let entity = [tags::ENTITY_U24, 0, 1, 0];
+
+    // a strict decoder rejects tags newer than the version being decoded
+    assert_eq!(decode_at(0, true, &str_a).unwrap_err().kind(), ErrorKind::InvalidTag);
+    assert_eq!(decode_at(4, true, &entity).unwrap_err().kind(), ErrorKind::InvalidTag);
+    assert_eq!(decode_at(1, true, &str_a).unwrap(), Value::Str("a".to_string()));
+    assert_eq!(decode_at(5, true, &entity).unwrap(), Value::EntityId(EntityId::Idx(256)));
+
+    // otherwise, they are decoded as in the version that added them
+    assert_eq!(decode_at(0, false, &str_a).unwrap(), Value::Str("a".to_string()));
+    assert_eq!(decode_at(4, false, &entity).unwrap(), Value::EntityId(EntityId::Idx(256)));
+
+    // decoders are strict by default
+    let mut state = decode::State::new(&str_a[..]);
+    state.set_version(0);
+    assert_eq!(state.decode_value().unwrap_err().kind(), ErrorKind::InvalidTag);
+
+    // tags reserved for future versions are rejected either way, even if the
+    // decoder is told the input comes from one
+    let future = decode::FORMAT_VERSION + 1;
+    for strict in [false, true] {
+        assert_eq!(decode_at(future, strict, &[tags::RESERVED, 0]).unwrap_err().kind(), ErrorKind::InvalidTag);
+        assert_eq!(decode_at(future, strict, &[tags::RESERVED_MAX]).unwrap_err().kind(), ErrorKind::InvalidTag);
+    }
+}
+
+#[test]
+fn value_decoding_small_ints() {
+    let ints = [0, 1, 0x7f, 0x80, -1, -0x80, 300, -70000, i64::MAX, i64::MIN];
+    let mut encoded = Vec::new();
+    for &i in &ints {
+        encoded.extend(encode_value(&Value::Int(i)));
+    }
+
+    // the same as decoding each value generically
+    let mut generic = Vec::new();
+    let mut state = decode::State::new(&encoded[..]);
+    for _ in 0..ints.len() {
+        match state.decode_value().unwrap() {
+            Value::Int(i) => generic.push(i),
+            v => panic!("decoded {:?}", v),
+        }
+    }
+
+    let mut out = vec![5];
+    let mut state = decode::State::new(&encoded[..]);
+    state.decode_small_ints_into(&mut out, ints.len()).unwrap();
+    state.expect_eof().unwrap();
+    assert_eq!(out[0], 5);
+    assert_eq!(out[1..], generic[..]);
+    assert_eq!(out[1..], ints[..]);
+
+    // error: not an integer, or not enough input
+    let mut out = Vec::new();
+    let err = decode::State::new(&b"\x01\x02\xa5"[..]).decode_small_ints_into(&mut out, 3).unwrap_err();
+    assert_eq!(err.kind(), decode::ErrorKind::InvalidTag);
+    assert_eq!(out, vec![1, 2]);
+    let err = decode::State::new(&b"\x01"[..]).decode_small_ints_into(&mut out, 2).unwrap_err();
+    assert_eq!(err.kind(), decode::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn value_decoding_small_int_array() {
+    let elems: Vec<Value> = (0..256).map(|i| Value::Int(i % 0x80)).collect();
+    let encoded = encode_value(&Value::Array(elems.clone()));
+    assert_eq!(encoded[..2], [tags::ARRAY_U32, 0]);
+
+    // the same as decoding each element generically
+    let body = &encoded[5..];
+    let mut state = decode::State::new(body);
+    let generic: Vec<Value> = (0..256).map(|_| state.decode_value().unwrap()).collect();
+    assert_eq!(decode_value(&encoded).unwrap(), Value::Array(generic));
+    assert_eq!(decode_value(&encoded).unwrap(), Value::Array(elems));
+
+    // small ints mixed with other values
+    let mixed = Value::Array(vec![Value::Int(1), Value::Int(1000), Value::Int(2), Value::Bool(true), Value::Int(3)]);
+    assert_eq!(decode_value(&encode_value(&mixed)).unwrap(), mixed);
+
+    // the depth and size limits still apply to the elements
+    let mut state = decode::State::new(&b"\x92\x01\x02"[..]);
+    state.set_max_depth(1);
+    assert_eq!(state.decode_value().unwrap_err().kind(), decode::ErrorKind::DepthLimitExceeded);
+    let mut state = decode::State::new(&b"\x92\x01\x02"[..]);
+    assert_eq!(state.decode_value_within(2).unwrap_err().kind(), decode::ErrorKind::SizeLimitExceeded);
+    assert_eq!(decode_value(b"\x92\x01").unwrap_err().kind(), decode::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn debug_formatting() {
+    let ctx = decode_world(&linked_world()).unwrap();
+    let debug = format!("{:?}", ctx);
+    assert_eq!(debug, "WorldContext { components: [(0, \"link\", 2)], global: [], entities: 2 }");
+
+    let array = ComponentArray::from_reader(&b"COMPONENT point 0 1 x y\n\x01\x02"[..]).unwrap();
+    let debug = format!("{:?}", array);
+    assert!(debug.contains("\"point\"") && debug.contains("Int(2)"), "{}", debug);
+    assert!(format!("{:?}", GlobalComponent::empty()).starts_with("GlobalComponent"));
+
+    // the world only summarizes its contents
+    let mut world = World::<u32, ()>::from_context(ctx);
+    world.register_lua_system(0, b"return function(ctx) end").unwrap();
+    assert_eq!(
+        format!("{:?}", world),
+        "World { context: WorldContext { components: [(0, \"link\", 2)], global: [], entities: 2 }, \
+         systems: 1, queries: 0 }",
+    );
+}
+
+#[test]
+fn world_equality() {
+    let world = decode_world(&linked_world()).unwrap();
+    let round_tripped = decode_world(&encode_world(&world)).unwrap();
+    assert_eq!(world, round_tripped);
+    assert_eq!(World::<(), ()>::from_context(world.deep_clone()), World::from_context(round_tripped));
+
+    // arrays compare by contents, not by whether they've been modified
+    let mut modified = world.deep_clone();
+    modified.component_for_entity_mut(EntityId::Idx(0), "link").unwrap();
+    assert_eq!(world, modified);
+    assert_eq!(world.components[0], modified.components[0]);
+
+    modified.component_for_entity_mut(EntityId::Idx(0), "link").unwrap()
+        .set_field("target", Value::EntityId(EntityId::Invalid));
+    assert_ne!(world, modified);
+    assert_ne!(world.components[0], modified.components[0]);
+
+    // deleted entities are equal regardless of their components
+    let mut a = world.deep_clone();
+    let mut b = world.deep_clone();
+    a.retain_entities(|id, _| id != EntityId::Idx(1));
+    b.entities.entries[1].is_deleted = true;
+    assert_ne!(a, world);
+    b.components = a.components.clone();
+    assert_eq!(a, b);
+
+    let global = GlobalComponent::from_reader(&b"GLOBAL x\n\x01"[..]).unwrap();
+    assert_eq!(global, global.clone());
+    assert_ne!(global, GlobalComponent::empty());
+}
+
+#[test]
+fn world_swap_entities() {
+    use error::BuildError;
+
+    let mut world = WorldBuilder::new()
+        .component("name", ComponentId(0), vec!["name"])
+        .component("target", ComponentId(1), vec!["ids"])
+        .global(vec!["player"], vec![Value::EntityId(EntityId::Idx(0))])
+        .spawn(vec![
+            (ComponentId(0), vec![Value::Str("a".to_string())]),
+            (ComponentId(1), vec![Value::Array(vec![Value::EntityId(EntityId::Idx(2)), Value::EntityId(EntityId::Idx(1))])]),
+        ])
+        .spawn(vec![(ComponentId(0), vec![Value::Str("b".to_string())])])
+        .spawn(vec![
+            (ComponentId(0), vec![Value::Str("c".to_string())]),
+            (ComponentId(1), vec![Value::Array(vec![Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(0)))))])]),
+        ])
+        .build()
+        .unwrap();
+
+    fn name(world: &WorldContext, id: EntityId) -> String {
+        world.component_for_entity(id, "name").unwrap().field("name").unwrap().as_str().unwrap().to_string()
+    }
+    fn targets(world: &WorldContext, id: EntityId) -> Vec<String> {
+        let mut ids = Vec::new();
+        world.component_for_entity(id, "target").unwrap().values[0]
+            .visit_entity_ids(&mut |id| ids.push(name(world, id)));
+        ids
+    }
+
+    world.clear_dirty();
+    world.swap_entities(EntityId::Idx(0), EntityId::Idx(2)).unwrap();
+    assert_eq!(name(&world, EntityId::Idx(0)), "c");
+    assert_eq!(name(&world, EntityId::Idx(2)), "a");
+    // references still resolve to the same entities
+    assert_eq!(targets(&world, EntityId::Idx(2)), vec!["c", "b"]);
+    assert_eq!(targets(&world, EntityId::Idx(0)), vec!["a"]);
+    assert_eq!(world.global.get().values[0], Value::EntityId(EntityId::Idx(2)));
+    // only the array with references in it was rewritten
+    assert_eq!(world.dirty_component_ids().collect::<Vec<_>>(), vec![ComponentId(1)]);
+
+    // error: not a live entity
+    world.retain_entities(|id, _| id != EntityId::Idx(1));
+    assert_eq!(world.swap_entities(EntityId::Idx(0), EntityId::Idx(1)), Err(BuildError::InvalidEntityId(1)));
+    assert_eq!(world.swap_entities(EntityId::Idx(0), EntityId::Idx(7)), Err(BuildError::InvalidEntityId(7)));
+    assert_eq!(name(&world, EntityId::Idx(0)), "c");
+}
+
+#[test]
+fn world_add_component_array() {
+    use error::BuildError;
+
+    let mut world = WorldBuilder::new().component("b", ComponentId(1), vec!["x"]).build().unwrap();
+
+    // the lowest free IDs are chosen
+    assert_eq!(world.add_component_array("a".to_string(), vec!["x".to_string()]), Ok(ComponentId(0)));
+    assert_eq!(world.add_component_array("c".to_string(), vec![]), Ok(ComponentId(2)));
+    assert_eq!(world.add_component_array("d".to_string(), vec!["x".to_string(), "y".to_string()]), Ok(ComponentId(3)));
+    assert_eq!(world.component_names().collect::<Vec<_>>(), vec!["a", "b", "c", "d"]);
+
+    let e = world.spawn(vec![(ComponentId(3), vec![Value::Int(1), Value::Int(2)])]).unwrap();
+    assert_eq!(world.component_for_entity(e, "d").unwrap().field("y"), Some(&Value::Int(2)));
+
+    // error: invalid or duplicate names
+    assert_eq!(
+        world.add_component_array("a".to_string(), vec![]),
+        Err(BuildError::DuplicateComponentName("a".to_string())),
+    );
+    assert_eq!(
+        world.add_component_array("e f".to_string(), vec![]),
+        Err(BuildError::InvalidName("e f".to_string())),
+    );
+    assert_eq!(
+        world.add_component_array("e".to_string(), vec!["x".to_string(), "x".to_string()]),
+        Err(BuildError::DuplicateField("x".to_string())),
+    );
+    assert_eq!(world.max_component_id(), ComponentId(3));
+
+    // every ID is in use
+    let (_, global, entities) = WorldContext::default().into_parts();
+    let arrays = (0..=u16::MAX).map(|id| (id as usize, ComponentArray::new(format!("c{}", id), id, vec![]))).collect();
+    let mut world = WorldContext::from_parts(arrays, global, entities).unwrap();
+    assert_eq!(world.add_component_array("e".to_string(), vec![]), Err(BuildError::ComponentIdsExhausted));
+}
+
+#[test]
+fn world_encoding_nested_deleted_references() {
+    let mut builder = WorldBuilder::new().component("refs", ComponentId(0), vec!["ids", "maybe"]);
+    builder = builder.spawn(vec![(ComponentId(0), vec![
+        Value::Array(vec![Value::EntityId(EntityId::Idx(5)), Value::Array(vec![Value::EntityId(EntityId::Idx(3))])]),
+        Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(5))))),
+    ])]);
+    for _ in 1..6 {
+        builder = builder.spawn(vec![]);
+    }
+    let mut world = builder.build().unwrap();
+    world.retain_entities(|id, _| id != EntityId::Idx(2) && id != EntityId::Idx(5));
+
+    // references nested in arrays and optionals are remapped like top-level ones
+    let decoded = decode_world(&encode_world(&world)).unwrap();
+    assert_eq!(decoded.component_for_entity(EntityId::Idx(0), "refs").unwrap().values, &[
+        Value::Array(vec![Value::EntityId(EntityId::Invalid), Value::Array(vec![Value::EntityId(EntityId::Idx(2))])]),
+        Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Invalid)))),
+    ]);
+}
+
+#[test]
+fn world_snapshots() {
+    let mut world = decode_world(&linked_world()).unwrap();
+    world.spawn(vec![(ComponentId(0), vec![Value::EntityId(EntityId::Idx(2))])]).unwrap();
+    let before = world.deep_clone();
+
+    for snapshot in [world.snapshot_clone(), world.snapshot_bytes().unwrap()] {
+        let encoded = snapshot.encoded_len().is_some();
+        world.component_for_entity_mut(EntityId::Idx(0), "link").unwrap()
+            .set_field("target", Value::EntityId(EntityId::Invalid));
+        world.spawn(vec![]).unwrap();
+        assert_ne!(world, before);
+
+        world.restore(snapshot).unwrap();
+        assert_eq!(world, before, "encoded: {}", encoded);
+    }
+
+    // encoded snapshots are compact
+    assert_eq!(world.snapshot_bytes().unwrap().encoded_len(), Some(world.encoded_size().unwrap()));
+    assert_eq!(world.snapshot_clone().encoded_len(), None);
+
+    // observers are kept
+    let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = count.clone();
+    world.on_component_changed(ComponentId(0), move |_, _, _| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    });
+    world.restore(before.snapshot_clone()).unwrap();
+    world.component_for_entity_mut(EntityId::Idx(0), "link").unwrap()
+        .set_field("target", Value::EntityId(EntityId::Invalid));
+    assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn world_metadata() {
+    use error::BuildError;
+
+    let encoded = [
+        &b"# saved by a tool\n"[..],
+        &world_fixture(0, 0, b"# caf\xc3\xa9\nMETA author  someone else\nMETA tool editor\n\
+            # between sections\nGLOBAL x\n\x01\nENTITIES 0\n"),
+    ].concat();
+    let mut world = decode_world(&encoded).unwrap();
+    assert_eq!(world.metadata().get("author").map(String::as_str), Some("someone else"));
+    assert_eq!(world.metadata().get("tool").map(String::as_str), Some("editor"));
+
+    // metadata is written in key order, and comments are not kept
+    assert_eq!(
+        encode_world(&world),
+        &world_fixture(0, 0, b"META author someone else\nMETA tool editor\nGLOBAL x\n\x01\nENTITIES 0\n")[..],
+    );
+
+    assert_eq!(world.set_metadata("tool".to_string(), "other".to_string()), Ok(Some("editor".to_string())));
+    assert_eq!(world.set_metadata("bad key".to_string(), String::new()), Err(BuildError::InvalidName("bad key".to_string())));
+    assert_eq!(
+        world.set_metadata("key".to_string(), "two\nlines".to_string()),
+        Err(BuildError::InvalidMetadataValue("two\nlines".to_string())),
+    );
+    assert_eq!(decode_world(&encode_world(&world)).unwrap(), world);
+
+    // duplicate keys are rejected
+    let err = decode_world(&world_fixture(0, 0, b"META a 1\nMETA a 2\nGLOBAL\n\nENTITIES 0\n")).err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::MalformedHeader);
+
+    // version 3 files have no metadata
+    assert!(decode_world(b"WORLD 0 0 3\nMETA a 1\nGLOBAL\n\nENTITIES 0\n").is_err());
+}
+
+#[test]
+fn value_narrow_ints() {
+    assert_eq!(Value::Int(0).as_u8(), Some(0));
+    assert_eq!(Value::Int(255).as_u8(), Some(255));
+    assert_eq!(Value::Int(256).as_u8(), None);
+    assert_eq!(Value::Int(-1).as_u8(), None);
+
+    assert_eq!(Value::Int(65535).as_u16(), Some(65535));
+    assert_eq!(Value::Int(65536).as_u16(), None);
+    assert_eq!(Value::Int(-1).as_u16(), None);
+
+    assert_eq!(Value::Int(u32::MAX as i64).as_u32(), Some(u32::MAX));
+    assert_eq!(Value::Int(u32::MAX as i64 + 1).as_u32(), None);
+    assert_eq!(Value::Int(-1).as_u32(), None);
+
+    assert_eq!(Value::Int(i32::MIN as i64).as_i32(), Some(i32::MIN));
+    assert_eq!(Value::Int(i32::MAX as i64).as_i32(), Some(i32::MAX));
+    assert_eq!(Value::Int(i32::MIN as i64 - 1).as_i32(), None);
+    assert_eq!(Value::Int(i32::MAX as i64 + 1).as_i32(), None);
+
+    // only `Int`s are converted
+    assert_eq!(Value::Float(1.0).as_u8(), None);
+    assert_eq!(Value::Bool(true).as_i32(), None);
+}
+
+#[test]
+fn value_walk() {
+    let value = Value::Array(vec![
+        Value::Int(1),
+        Value::Maybe(Some(Box::new(Value::Array(vec![Value::Int(2), Value::Str("x".to_string())])))),
+        Value::Maybe(None),
+        Value::Array(vec![Value::Array(vec![Value::Int(3)]), Value::EntityId(EntityId::Idx(4))]),
+    ]);
+
+    let mut ints = 0;
+    let mut sum = 0;
+    value.walk(&mut |v| if let Value::Int(i) = v {
+        ints += 1;
+        sum += i;
+    });
+    assert_eq!((ints, sum), (3, 6));
+
+    // every node is visited once, parents before their contents
+    let mut nodes = Vec::new();
+    value.walk(&mut |v| nodes.push(v.clone()));
+    assert_eq!(nodes.len(), 11);
+    assert_eq!(nodes[0], value);
+    assert_eq!(nodes[10], Value::EntityId(EntityId::Idx(4)));
+
+    let mut count = 0;
+    Value::Int(5).walk(&mut |_| count += 1);
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn world_streaming_entities() {
+    let mut world = decode_world(&linked_world()).unwrap();
+    for _ in 0..3000 {
+        world.spawn(vec![(ComponentId(0), vec![Value::EntityId(EntityId::Idx(0))])]).unwrap();
+    }
+    world.retain_entities(|id, _| id != EntityId::Idx(1));
+    let encoded = encode_world(&world);
+
+    let mut state = decode::State::new(&encoded[..]);
+    let (num_component_arrays, _) = state.decode_world_header().unwrap();
+    state.decode_world_metadata().unwrap();
+    for _ in 0..num_component_arrays {
+        state.decode_component_array().unwrap();
+        state.expect_newline().unwrap();
+    }
+    state.decode_global_component().unwrap();
+    state.expect_newline().unwrap();
+
+    let mut count = 0;
+    let mut with_link = 0;
+    for entity in state.entity_array_iter().unwrap() {
+        let entity = entity.unwrap();
+        count += 1;
+        if entity.component_ids().any(|id| id == ComponentId(0)) {
+            with_link += 1;
+        }
+    }
+    assert_eq!(count, 3001);
+    assert_eq!(with_link, 3001);
+    state.expect_eof().unwrap();
+
+    // the iterator stops after an error
+    let mut state = decode::State::new(&b"ENTITIES 3\n\x01\xc0\x01\x8a\x00"[..]);
+    let results: Vec<_> = state.entity_array_iter().unwrap().map(|entity| entity.is_ok()).collect();
+    assert_eq!(results, [true, false]);
+}
+
+#[test]
+fn world_into_parts() {
+    use error::BuildError;
+
+    let mut world = decode_world(&linked_world()).unwrap();
+    world.spawn(vec![(ComponentId(0), vec![Value::EntityId(EntityId::Idx(1))])]).unwrap();
+    let before = world.deep_clone();
+
+    let (components, global, entities) = world.into_parts();
+    assert_eq!(entities.entries().len(), 3);
+    let world = WorldContext::from_parts(components, global, entities).unwrap();
+    assert_eq!(world, before);
+    assert_eq!(decode_world(&encode_world(&world)).unwrap(), before);
+
+    // dropping an entity that is still referred to is caught
+    let (components, global, mut entities) = before.deep_clone().into_parts();
+    entities.entries_mut().pop();
+    entities.entries_mut().pop();
+    assert_eq!(
+        WorldContext::from_parts(components, global, entities).err(),
+        Some(BuildError::InvalidEntityId(1)),
+    );
+
+    // as is dropping a component that an entity has
+    let (_, global, entities) = before.deep_clone().into_parts();
+    assert_eq!(
+        WorldContext::from_parts(vec_map::VecMap::new(), global, entities).err(),
+        Some(BuildError::UnknownComponent(ComponentId(0))),
+    );
+
+    // arrays are stored by their own IDs
+    let (components, global, entities) = before.deep_clone().into_parts();
+    let mut rekeyed = vec_map::VecMap::new();
+    rekeyed.insert(5, components[0].clone());
+    let world = WorldContext::from_parts(rekeyed, global, entities).unwrap();
+    assert_eq!(world.component_ids().collect::<Vec<_>>(), [ComponentId(0)]);
+}
+
+#[test]
+fn value_into_vecs() {
+    let ints = || Value::Array(vec![Value::Int(1), Value::Int(-2), Value::Int(300)]);
+    let floats = || Value::Array(vec![Value::Float(1.5), Value::Float(-2.0)]);
+    let mixed = || Value::Array(vec![Value::Int(1), Value::Float(2.0)]);
+
+    assert_eq!(ints().into_int_vec(), Some(vec![1, -2, 300]));
+    assert_eq!(Value::IntArray(vec![4, 5]).into_int_vec(), Some(vec![4, 5]));
+    assert_eq!(Value::Array(Vec::new()).into_int_vec(), Some(Vec::new()));
+    assert_eq!(floats().into_int_vec(), None);
+    assert_eq!(mixed().into_int_vec(), None);
+    assert_eq!(Value::Int(1).into_int_vec(), None);
+
+    assert_eq!(floats().into_float_vec(), Some(vec![1.5, -2.0]));
+    assert_eq!(ints().into_float_vec(), None);
+    assert_eq!(mixed().into_float_vec(), None);
+
+    assert_eq!(Value::Bytes(b"abc".to_vec()).into_bytes(), Some(b"abc".to_vec()));
+    assert_eq!(Value::Array(vec![Value::Int(0), Value::Int(255)]).into_bytes(), Some(vec![0, 255]));
+    assert_eq!(Value::IntArray(vec![7, 8]).into_bytes(), Some(vec![7, 8]));
+    assert_eq!(ints().into_bytes(), None);
+    assert_eq!(Value::IntArray(vec![-1]).into_bytes(), None);
+    assert_eq!(mixed().into_bytes(), None);
+    assert_eq!(Value::Str("abc".to_string()).into_bytes(), None);
+}
+
+#[test]
+fn world_encoding_high_component_id() {
+    let world = WorldBuilder::new()
+        .component("low", ComponentId(3), vec!["x"])
+        .component("high", ComponentId(4000), Vec::<String>::new())
+        .spawn(vec![(ComponentId(4000), vec![]), (ComponentId(3), vec![Value::Int(1)])])
+        .build()
+        .unwrap();
+    assert_eq!(world.max_component_id(), ComponentId(4000));
+
+    let encoded = encode_world(&world);
+    let header = decode::State::new(&encoded[..]).decode_world_header().unwrap();
+    assert_eq!(header, (2, ComponentId(4000)));
+    assert_eq!(decode_world(&encoded).unwrap(), world);
+}
+
+#[test]
+fn world_lua_global() {
+    let mut world = World::<u32, ()>::from_reader(
+        &world_fixture(0, 0, b"GLOBAL turn name\n\x05\x83abc\nENTITIES 0\n")[..],
+    ).unwrap();
+
+    world.register_lua_system(0, b"return function(ctx) ctx:set_global('turn', ctx:global('turn') + 1) end").unwrap();
+    assert!(world.run_system(&0).unwrap());
+    assert!(world.run_system(&0).unwrap());
+    world.context(|ctx| assert_eq!(ctx.global.get().field("turn"), Some(&Value::Int(7))));
+
+    // other values are converted too
+    assert!(world.run_lua(b"return function(ctx) return ctx:global('name') end", |v| {
+        matches!(v, rlua::Value::String(s) if s.as_bytes() == b"abc")
+    }).unwrap());
+
+    // missing fields read as nil, but can't be set
+    assert!(world.run_lua(b"return function(ctx) return ctx:global('missing') end", |v| {
+        matches!(v, rlua::Value::Nil)
+    }).unwrap());
+    assert!(world.run_lua(b"return function(ctx) ctx:set_global('missing', 1) end", |_| ()).is_err());
+}
+
+#[test]
+fn world_query_entities() {
+    // a simple linear congruential generator, so the test is reproducible
+    let mut state = 12345u32;
+    let mut next = move || {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        state >> 16
+    };
+
+    let mut builder = WorldBuilder::new();
+    for id in 0..8 {
+        builder = builder.component(format!("c{}", id), ComponentId(id), Vec::<String>::new());
+    }
+    let mut component_sets = Vec::new();
+    for _ in 0..200 {
+        let ids: Vec<u16> = (0..8).filter(|_| next() % 2 == 0).collect();
+        builder = builder.spawn(ids.iter().map(|&id| (ComponentId(id), vec![])));
+        component_sets.push(ids);
+    }
+    let world = builder.build().unwrap();
+
+    for _ in 0..100 {
+        let query: Vec<u16> = (0..3).map(|_| (next() % 8) as u16).collect();
+        let expected: Vec<EntityId> = component_sets.iter().enumerate()
+            .filter(|(_, ids)| query.iter().all(|id| ids.contains(id)))
+            .map(|(idx, _)| EntityId::Idx(idx as u32))
+            .collect();
+        assert_eq!(world.query_entities(&query.iter().copied().map(ComponentId).collect::<Vec<_>>()).collect::<Vec<_>>(), expected, "query: {:?}", query);
+
+        let entity = &world.entities.entries[(next() % 200) as usize];
+        let mut sorted = query.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(
+            entity.has_all_sorted(&sorted),
+            sorted.iter().all(|&id| entity.component_ids().any(|c| c == ComponentId(id))),
+        );
+    }
+
+    // the empty query matches every live entity
+    assert_eq!(world.query_entities(&[]).count(), 200);
+}
+
+#[test]
+fn value_codec_without_io() {
+    // a source and sink that don't implement `Read` or `Write`
+    struct Bytes<'a>(&'a [u8]);
+    impl decode::Source for Bytes<'_> {
+        fn next_byte(&mut self) -> Result<Option<u8>, codec::StreamError> {
+            let (&first, rest) = match self.0.split_first() {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+            self.0 = rest;
+            Ok(Some(first))
+        }
+    }
+
+    #[derive(Default)]
+    struct Buffer(Vec<u8>);
+    impl encode::Sink for Buffer {
+        fn write_bytes(&mut self, buf: &[u8]) -> Result<(), codec::StreamError> {
+            self.0.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    // a sink that always fails
+    struct Full;
+    impl encode::Sink for Full {
+        fn write_bytes(&mut self, _: &[u8]) -> Result<(), codec::StreamError> {
+            Err(codec::StreamError::Other("buffer is full".into()))
+        }
+    }
+
+    let value = Value::Array(vec![
+        Value::Int(-300),
+        Value::Float(0.5),
+        Value::Str("text".to_string()),
+        Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(70))))),
+        Value::IntArray(vec![1, 2, 3]),
+    ]);
+
+    let mut state = encode::State::new(Buffer::default());
+    state.encode_value(&value, &mut |_| {}).unwrap();
+    let encoded = state.into_inner().0;
+    assert_eq!(encoded, encode_value(&value));
+
+    let mut state = decode::State::new(Bytes(&encoded));
+    assert_eq!(state.decode_value().unwrap(), value);
+    state.expect_eof().unwrap();
+
+    let err = decode::State::new(Bytes(&encoded[..4])).decode_value().err().unwrap();
+    assert_eq!(err.kind(), decode::ErrorKind::UnexpectedEof);
+
+    let err = encode::State::new(Full).encode_value(&value, &mut |_| {}).unwrap_err();
+    assert_eq!(err.to_string(), "buffer is full");
+}
+
+#[test]
+fn world_sort_component_by_entity() {
+    let mut builder = WorldBuilder::new().component("n", ComponentId(0), vec!["n"]);
+    for n in 0..6 {
+        builder = builder.spawn(vec![(ComponentId(0), vec![Value::Int(n)])]);
+    }
+    let mut world = builder.build().unwrap();
+
+    // shuffle the entities, leaving the components where they were
+    for (a, b) in [(0, 4), (1, 5), (2, 3), (0, 2)] {
+        world.swap_entities(EntityId::Idx(a), EntityId::Idx(b)).unwrap();
+    }
+    let values_by_entity = |world: &WorldContext| -> Vec<Value> {
+        (0..world.entities.entries.len() as u32)
+            .map(|i| world.component_for_entity(EntityId::Idx(i), "n").unwrap().values[0].clone())
+            .collect()
+    };
+    let before = values_by_entity(&world);
+    let idxs = |world: &WorldContext| -> Vec<u32> {
+        world.entities.entries.iter().map(|entity| entity.components[0].idx).collect()
+    };
+    assert_ne!(idxs(&world), [0, 1, 2, 3, 4, 5]);
+
+    assert!(world.sort_component_by_entity(ComponentId(0)));
+    assert_eq!(idxs(&world), [0, 1, 2, 3, 4, 5]);
+    assert_eq!(values_by_entity(&world), before);
+
+    // shared components stay shared, and unused ones go at the end
+    let (components, global, mut entities) = world.into_parts();
+    entities.entries_mut()[0].components[0].idx = 3;
+    entities.entries_mut()[1].components[0].idx = 5;
+    entities.entries_mut()[2].components[0].idx = 3;
+    entities.entries_mut().truncate(3);
+    let mut world = WorldContext::from_parts(components, global, entities).unwrap();
+    assert!(world.sort_component_by_entity(ComponentId(0)));
+    assert_eq!(idxs(&world), [0, 1, 0]);
+    assert_eq!(values_by_entity(&world), [before[3].clone(), before[5].clone(), before[3].clone()]);
+    assert_eq!(world.components[0].get(2).unwrap().values, &[before[0].clone()]);
+
+    assert!(!world.sort_component_by_entity(ComponentId(1)));
+}
+
+#[test]
+fn value_encoding_nan() {
+    use encode::FloatPolicy;
+
+    fn encode_with(policy: FloatPolicy, bits: u64) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut state = encode::State::new(&mut encoded);
+        state.set_float_policy(policy);
+        state.encode_value(&Value::Float(f64::from_bits(bits)), &mut |_| {}).unwrap();
+        encoded
+    }
+    fn decoded_bits(encoded: &[u8]) -> u64 {
+        match decode_value(encoded).unwrap() {
+            Value::Float(x) => x.to_bits(),
+            other => panic!("not a float: {:?}", other),
+        }
+    }
+
+    // quiet, signaling, and negative NaNs keep their exact bits as f64, even
+    // when their payload would fit in an f32
+    for bits in [0x7ff8_0000_0000_0000, 0x7ff8_0000_0000_0001, 0x7ff0_0000_0000_0001, 0xfff8_0000_2000_0000] {
+        let encoded = encode_with(FloatPolicy::Auto, bits);
+        assert_eq!(encoded[0], tags::F64, "{:x}", bits);
+        assert_eq!(decoded_bits(&encoded), bits, "{:x}", bits);
+    }
+
+    // forcing f32 writes the canonical quiet NaN
+    assert_eq!(encode_with(FloatPolicy::AlwaysF32, 0x7ff0_0000_0000_0001), b"\xa6\x7f\xc0\x00\x00");
+
+    // f32 NaNs, quiet or signaling, decode as the canonical quiet NaN
+    assert_eq!(decoded_bits(b"\xa6\x7f\xc0\x00\x00"), f64::NAN.to_bits());
+    assert_eq!(decoded_bits(b"\xa6\x7f\x80\x00\x01"), f64::NAN.to_bits());
+    assert_eq!(decoded_bits(b"\xa6\xff\xc0\x00\x01"), f64::NAN.to_bits());
+
+    // infinities are not NaNs, and still narrow
+    assert_eq!(encode_with(FloatPolicy::Auto, f64::INFINITY.to_bits()), b"\xa6\x7f\x80\x00\x00");
+}
+
+#[test]
+fn world_transcoding() {
+    let mut world = decode_world(&linked_world()).unwrap();
+    world.add_component_array("name".to_string(), vec!["name".to_string()]).unwrap();
+    world.add_component(EntityId::Idx(1), ComponentId(1), vec![Value::Str("second".to_string())]).unwrap();
+    world.set_metadata("tool".to_string(), "test".to_string()).unwrap();
+    let encoded = encode_world(&world);
+
+    // the identity transform reproduces the input exactly
+    let mut transcoded = Vec::new();
+    let mut names = Vec::new();
+    transcode_world(&encoded[..], &mut transcoded, |array| names.push(array.name().to_string())).unwrap();
+    assert_eq!(transcoded, encoded);
+    assert_eq!(names, ["link", "name"]);
+
+    // deleted entities are copied as they are
+    let tombstoned = world_fixture(1, 0, b"COMPONENT link 0 2 target\n\xc1\xc0\nGLOBAL\n\nENTITIES 3\n\x01\xc0\xfe\x01\x00\x01");
+    let mut transcoded = Vec::new();
+    transcode_world(&tombstoned[..], &mut transcoded, |_| {}).unwrap();
+    assert_eq!(transcoded, &tombstoned[..]);
+
+    // transforms are applied
+    let mut transcoded = Vec::new();
+    transcode_world(&encoded[..], &mut transcoded, |array| {
+        if array.name() == "name" {
+            array.get_mut(0).unwrap().set_field("name", Value::Str("renamed".to_string()));
+        }
+    }).unwrap();
+    let decoded = decode_world(&transcoded).unwrap();
+    assert_eq!(
+        decoded.component_for_entity(EntityId::Idx(1), "name").unwrap().field("name"),
+        Some(&Value::Str("renamed".to_string())),
+    );
+
+    // errors in the input are reported
+    assert!(matches!(
+        transcode_world(&encoded[..encoded.len() - 1], &mut Vec::new(), |_| {}),
+        Err(error::Error::Decode(_)),
+    ));
+}
+
+#[test]
+fn value_stream_eof() {
+    let encoded = [encode_value(&Value::Int(300)), encode_value(&Value::Str("abc".to_string()))].concat();
+
+    // ending exactly at a value boundary
+    let mut state = decode::State::new(&encoded[..]);
+    assert!(!state.at_eof().unwrap());
+    assert_eq!(state.decode_value().unwrap(), Value::Int(300));
+    assert!(!state.at_eof().unwrap());
+    assert!(!state.at_eof().unwrap());
+    assert_eq!(state.idx(), 3);
+    assert_eq!(state.decode_value().unwrap(), Value::Str("abc".to_string()));
+    assert!(state.at_eof().unwrap());
+
+    let values: Vec<Value> = decode::State::new(&encoded[..]).values().collect::<Result<_, _>>().unwrap();
+    assert_eq!(values, [Value::Int(300), Value::Str("abc".to_string())]);
+    assert_eq!(decode::State::new(&b""[..]).values().count(), 0);
+
+    // ending in the middle of a value
+    let mut state = decode::State::new(&encoded[..encoded.len() - 1]);
+    let results: Vec<_> = state.values().collect();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert_eq!(results[1].as_ref().err().unwrap().kind(), decode::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn value_encoding_entity_id_widths() {
+    let check = |idx: u32, tag: u8, len: usize| {
+        let value = Value::EntityId(EntityId::Idx(idx));
+        let encoded = encode_value(&value);
+        assert_eq!((encoded[0], encoded.len()), (tag, len), "{:x}", idx);
+        assert_eq!(decode_value(&encoded).unwrap(), value, "{:x}", idx);
+    };
+
+    check(0xffff, tags::ENTITY_U16, 3);
+    check(0x1_0000, tags::ENTITY_U24, 4);
+    check(0xff_ffff, tags::ENTITY_U24, 4);
+    check(0x100_0000, tags::ENTITY_U32, 5);
+    check(u32::MAX, tags::ENTITY_U32, 5);
+    assert_eq!(encode_value(&Value::EntityId(EntityId::Idx(0x12_3456))), b"\xb6\x12\x34\x56");
+
+    // the 24-bit form didn't exist before version 5
+    let mut state = decode::State::new(&b"\xb6\x01\x00\x00"[..]);
+    state.set_version(4);
+    assert_eq!(state.decode_value().unwrap_err().kind(), decode::ErrorKind::InvalidTag);
+}
+
+#[test]
+fn world_run_system_returning() {
+    let mut world = World::<u32, ()>::new();
+    world.register_lua_system(0, b"local n = 0; return function(ctx) n = n + 1; return n >= 2 end").unwrap();
+    world.register_native_system(1, |_| {});
+
+    assert_eq!(world.run_system_returning::<bool>(&0).unwrap(), Some(false));
+    assert_eq!(world.run_system_returning::<bool>(&0).unwrap(), Some(true));
+
+    // native systems return nil, and missing systems return nothing
+    assert_eq!(world.run_system_returning::<Option<bool>>(&1).unwrap(), Some(None));
+    assert_eq!(world.run_system_returning::<bool>(&2).unwrap(), None);
+
+    // the return value must convert to the requested type
+    assert!(world.run_system_returning::<String>(&0).is_err());
+}
+
+#[test]
+fn world_fallible_native_system() {
+    #[derive(Debug, PartialEq)]
+    struct Invariant(&'static str);
+    impl std::fmt::Display for Invariant {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "invariant violated: {}", self.0)
+        }
+    }
+    impl std::error::Error for Invariant {}
+
+    let mut world = World::<u32, ()>::new();
+    world.register_fallible_native_system(0, |ctx| {
+        if ctx.global.is_empty() {
+            Err(Invariant("no global component"))
+        } else {
+            Ok(())
+        }
+    });
+    assert!(matches!(world.system_info(&0), ScriptType::Native));
+
+    match world.run_system(&0) {
+        Err(rlua::Error::ExternalError(err)) => {
+            assert_eq!(err.downcast_ref::<Invariant>(), Some(&Invariant("no global component")));
+        }
+        other => panic!("expected an external error, got {:?}", other),
+    }
+
+    // the world can still be used afterward
+    world.register_fallible_native_system(1, |_| Ok::<_, String>(()));
+    assert!(world.run_system(&1).unwrap());
+    world.context_mut(|ctx| ctx.spawn(vec![]).unwrap());
+}
+
+#[test]
+fn world_save_and_load_scripts() {
+    let mut world = World::<u32, ()>::new();
+    world.register_lua_system(5, b"return function(ctx) return 5 end").unwrap();
+    world.register_named_lua_system(2, b"two", b"return function(ctx) return 1 end").unwrap();
+    world.register_lua_system(9, b"return function(ctx) return 9 end").unwrap();
+    world.register_native_system(7, |_| {});
+    // replacing a system moves it to the end
+    world.register_named_lua_system(2, b"two", b"return function(ctx) return 2 end").unwrap();
+
+    let mut saved = Vec::new();
+    world.save_scripts(&mut saved, |&id| Value::Int(id as i64)).unwrap();
+
+    // systems are saved in the order they were registered, without native ones
+    let mut state = decode::State::new(&saved[..]);
+    let ids: Vec<Value> = state.values()
+        .map(|entry| match entry.unwrap() {
+            Value::Array(fields) => fields[0].clone(),
+            v => panic!("decoded {:?}", v),
+        })
+        .collect();
+    assert_eq!(ids, vec![Value::Int(5), Value::Int(9), Value::Int(2)]);
+
+    // reload into a fresh world and run them
+    let mut reloaded = World::<u32, ()>::new();
+    reloaded.load_scripts(&saved[..], |id| id.as_u32()).unwrap();
+    for &id in &[5, 9, 2] {
+        assert!(matches!(reloaded.system_info(&id), ScriptType::Lua));
+        assert_eq!(reloaded.run_system_returning::<u32>(&id).unwrap(), Some(id));
+    }
+    assert!(matches!(reloaded.system_info(&7), ScriptType::None));
+
+    // saving the reloaded systems gives the same result
+    let mut resaved = Vec::new();
+    reloaded.save_scripts(&mut resaved, |&id| Value::Int(id as i64)).unwrap();
+    assert_eq!(resaved, saved);
+
+    // error: invalid IDs, malformed entries, or code that doesn't compile
+    let mut fresh = World::<u32, ()>::new();
+    assert!(fresh.load_scripts(&saved[..], |_| None).is_err());
+    assert!(fresh.load_scripts(&encode_value(&Value::Int(1))[..], |id| id.as_u32()).is_err());
+    let bad = Value::Array(vec![Value::Int(1), Value::Bytes(b"x".to_vec()), Value::Bytes(b"return (".to_vec())]);
+    assert!(fresh.load_scripts(&encode_value(&bad)[..], |id| id.as_u32()).is_err());
+    assert!(matches!(fresh.system_info(&1), ScriptType::None));
+}
+
+#[test]
+fn world_framing() {
+    let first = decode_world(&linked_world()).unwrap();
+    let mut second = first.deep_clone();
+    second.spawn(vec![]).unwrap();
+
+    let mut encoded = Vec::new();
+    let mut state = encode::State::new(&mut encoded);
+    state.encode_world_framed(&first).unwrap();
+    state.encode_world_framed(&second).unwrap();
+    encoded.push(0xff);
+    assert_eq!(&encoded[..8], &(linked_world().len() as u64).to_be_bytes());
+
+    let mut state = decode::State::new(&encoded[..]);
+    assert_eq!(state.decode_world_framed().unwrap(), first);
+    assert_eq!(state.decode_world_framed().unwrap(), second);
+    assert_eq!(state.next("trailing byte").unwrap(), 0xff);
+    state.expect_eof().unwrap();
+
+    // the world must fill its frame exactly
+    let framed = |len: u64| [&len.to_be_bytes()[..], &linked_world()[..], b"\x00"].concat();
+    let len = linked_world().len() as u64;
+    assert!(decode::State::new(&framed(len)[..]).decode_world_framed().is_ok());
+    assert_eq!(
+        decode::State::new(&framed(len + 1)[..]).decode_world_framed().err().unwrap().kind(),
+        decode::ErrorKind::TrailingBytes,
+    );
+    assert_eq!(
+        decode::State::new(&framed(len - 1)[..]).decode_world_framed().err().unwrap().kind(),
+        decode::ErrorKind::SizeLimitExceeded,
+    );
+
+    // the frame is written with the state's own settings
+    let floats = WorldBuilder::new().global(vec!["x"], vec![Value::Float(0.5)]).build().unwrap();
+    let mut expected = Vec::new();
+    let mut state = encode::State::new(&mut expected);
+    state.set_float_policy(encode::FloatPolicy::AlwaysF64);
+    state.encode_world(&floats).unwrap();
+    let mut encoded = Vec::new();
+    let mut state = encode::State::new(&mut encoded);
+    state.set_float_policy(encode::FloatPolicy::AlwaysF64);
+    state.encode_world_framed(&floats).unwrap();
+    assert_eq!(&encoded[..8], &(expected.len() as u64).to_be_bytes());
+    assert_eq!(&encoded[8..], &expected[..]);
+    assert_ne!(expected, encode_world(&floats));
+}
+
+#[test]
+fn component_encoding_sparse() {
+    fn encode_sparse(array: &ComponentArray) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut state = encode::State::new(&mut encoded);
+        state.set_sparse(true);
+        state.encode_component_array(array, |_| {}).unwrap();
+        encoded
+    }
+
+    // 90% of the rows are the default row
+    let scheme = vec!["owner".to_string(), "label".to_string(), "count".to_string()];
+    let mut array = ComponentArray::new("item".to_string(), 0, scheme);
+    for i in 0..100 {
+        let owner = if i % 10 == 0 { Value::Maybe(Some(Box::new(Value::Int(i)))) } else { Value::Maybe(None) };
+        array.push(vec![owner, Value::Str("unlabeled".to_string()), Value::Int(1)]);
+    }
+
+    let mut dense = b"COMPONENT item 0 100 owner label count\n".to_vec();
+    for i in 0..100 {
+        for value in array.get(i).unwrap().values {
+            dense.extend(encode_value(value));
+        }
+    }
+
+    let sparse = encode_sparse(&array);
+    assert!(sparse.starts_with(b"COMPONENT-SPARSE item 0 100 owner label count\n\xac\xb2\x09unlabeled\x01"));
+    assert!(sparse.len() < dense.len() / 4, "{} vs {}", sparse.len(), dense.len());
+    assert_eq!(ComponentArray::from_reader(&sparse[..]).unwrap(), array);
+
+    // round trip within a world
+    let mut builder = WorldBuilder::new().component("item", ComponentId(0), vec!["owner", "label"]);
+    for i in 0..20 {
+        let owner = if i == 5 { Value::Maybe(Some(Box::new(Value::EntityId(EntityId::Idx(0))))) } else { Value::Maybe(None) };
+        builder = builder.spawn(vec![(ComponentId(0), vec![owner, Value::Str("unlabeled".to_string())])]);
+    }
+    let world = builder.build().unwrap();
+    let mut encoded = Vec::new();
+    let mut state = encode::State::new(&mut encoded);
+    state.set_sparse(true);
+    state.encode_world(&world).unwrap();
+    assert!(encoded.windows(16).any(|w| w == b"COMPONENT-SPARSE"));
+    assert_eq!(decode_world(&encoded).unwrap(), world);
+
+    // -0.0 isn't taken for a default of 0.0, and a default of NaN matches itself
+    let mut floats = ComponentArray::new("f".to_string(), 0, vec!["x".to_string(), "y".to_string()]);
+    for i in 0..20 {
+        let x = if i == 3 { -0.0 } else { 0.0 };
+        floats.push(vec![Value::Float(x), Value::Float(f64::NAN)]);
+    }
+    let encoded = encode_sparse(&floats);
+    assert!(encoded.starts_with(b"COMPONENT-SPARSE"));
+    let decoded = ComponentArray::from_reader(&encoded[..]).unwrap();
+    for i in 0..20 {
+        let row = decoded.get(i).unwrap();
+        match (row.field("x"), row.field("y")) {
+            (Some(&Value::Float(x)), Some(&Value::Float(y))) => {
+                assert_eq!(x.to_bits(), if i == 3 { (-0.0f64).to_bits() } else { 0 });
+                assert!(y.is_nan());
+            }
+            other => panic!("unexpected row: {:?}", other),
+        }
+    }
+
+    // arrays without a common value are written normally
+    let mut varied = ComponentArray::new("n".to_string(), 0, vec!["n".to_string()]);
+    for i in 0..10 {
+        varied.push(vec![Value::Int(i)]);
+    }
+    assert!(encode_sparse(&varied).starts_with(b"COMPONENT n"));
+
+    // sparse encoding is opt-in
+    let mut encoded = Vec::new();
+    array.to_writer(&mut encoded).unwrap();
+    assert!(encoded.starts_with(b"COMPONENT item"));
+    assert!(!encode_world(&world).windows(16).any(|w| w == b"COMPONENT-SPARSE"));
+
+    // sparse arrays didn't exist before version 6
+    let mut state = decode::State::new(&sparse[..]);
+    state.set_version(5);
+    assert_eq!(state.decode_component_array().err().unwrap().kind(), decode::ErrorKind::BadSignature);
+}
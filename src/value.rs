@@ -1,163 +1,680 @@
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::mem;
 
 use super::encode;
 use super::decode;
+use super::tags;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum Value {
     Bool(bool),
     Int(i64),
     Float(f64),
+    // An exact decimal number, equal to `mantissa * 10^(-scale)`.
+    // This is for storage only: no arithmetic is provided.
+    Decimal { mantissa: i64, scale: u8 },
     Bytes(Vec<u8>),
+    Str(String),
     Array(Vec<Value>),
+    // An array of integers, stored packed at the smallest width that fits all of them.
+    IntArray(Vec<i64>),
     Maybe(Option<Box<Value>>),
     EntityId(EntityId),
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum EntityId {
     Invalid,
     Idx(u32),
 }
 
-impl<R: io::Read> decode::State<R> {
+impl Value {
+    // Call `f` on every `EntityId` contained in the value.
+    pub(crate) fn visit_entity_ids<F: FnMut(EntityId)>(&self, f: &mut F) {
+        self.walk(&mut |v| if let Value::EntityId(id) = v {
+            f(*id)
+        });
+    }
+
+    /// Call `f` on the value and then on every value nested inside it, in
+    /// order: the elements of an array, and the contents of a `Maybe`.
+    pub fn walk<F: FnMut(&Value)>(&self, f: &mut F) {
+        f(self);
+        match self {
+            Value::Array(vs) => vs.iter().for_each(|v| v.walk(f)),
+            Value::Maybe(Some(v)) => v.walk(f),
+            _ => {}
+        }
+    }
+
+    // Whether two values are identical, comparing floats by their bit pattern
+    // rather than numerically: unlike `==`, this tells 0.0 apart from -0.0 and
+    // finds a NaN equal to itself. Use this rather than `==` wherever a value
+    // that matches another is left out or replaced by it.
+    pub(crate) fn bitwise_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Array(a), Value::Array(b)) =>
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.bitwise_eq(b)),
+            (Value::Maybe(Some(a)), Value::Maybe(Some(b))) => a.bitwise_eq(b),
+            _ => self == other,
+        }
+    }
+
+    // Call `f` on every `EntityId` contained in the value, allowing it to change them.
+    pub(crate) fn mutate_entity_ids<F: FnMut(&mut EntityId)>(&mut self, f: &mut F) {
+        match self {
+            Value::EntityId(id) => f(id),
+            Value::Array(vs) => vs.iter_mut().for_each(|v| v.mutate_entity_ids(f)),
+            Value::Maybe(Some(v)) => v.mutate_entity_ids(f),
+            _ => {}
+        }
+    }
+
+    /// View the value as text: either a `Str`, or a `Bytes` containing valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            Value::Bytes(bs) => std::str::from_utf8(bs).ok(),
+            _ => None,
+        }
+    }
+
+    /// View the value as an entity reference. Unlike `EntityId::Idx`, a dangling
+    /// `EntityId::Invalid` is still returned, so check it with `EntityId::is_valid`.
+    pub fn as_entity_id(&self) -> Option<EntityId> {
+        match self {
+            Value::EntityId(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    // View the value as an `Int` converted to a narrower type, if it fits.
+    fn as_int<T: TryFrom<i64>>(&self) -> Option<T> {
+        match self {
+            Value::Int(i) => T::try_from(*i).ok(),
+            _ => None,
+        }
+    }
+
+    /// View the value as a `u8`, if it is an `Int` in range.
+    pub fn as_u8(&self) -> Option<u8> {
+        self.as_int()
+    }
+
+    /// View the value as a `u16`, if it is an `Int` in range.
+    pub fn as_u16(&self) -> Option<u16> {
+        self.as_int()
+    }
+
+    /// View the value as a `u32`, if it is an `Int` in range.
+    pub fn as_u32(&self) -> Option<u32> {
+        self.as_int()
+    }
+
+    /// View the value as an `i32`, if it is an `Int` in range.
+    pub fn as_i32(&self) -> Option<i32> {
+        self.as_int()
+    }
+
+    /// Convert an `IntArray`, or an `Array` containing only `Int`s, into a vector.
+    pub fn into_int_vec(self) -> Option<Vec<i64>> {
+        match self {
+            Value::IntArray(is) => Some(is),
+            Value::Array(vs) => vs.into_iter().map(|v| match v {
+                Value::Int(i) => Some(i),
+                _ => None,
+            }).collect(),
+            _ => None,
+        }
+    }
+
+    /// Convert an `Array` containing only `Float`s into a vector.
+    pub fn into_float_vec(self) -> Option<Vec<f64>> {
+        match self {
+            Value::Array(vs) => vs.into_iter().map(|v| match v {
+                Value::Float(x) => Some(x),
+                _ => None,
+            }).collect(),
+            _ => None,
+        }
+    }
+
+    /// Convert `Bytes`, or an `Array` or `IntArray` containing only integers
+    /// that fit in a byte, into a vector of bytes.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Value::Bytes(bs) => Some(bs),
+            Value::IntArray(is) => is.into_iter().map(|i| u8::try_from(i).ok()).collect(),
+            Value::Array(vs) => vs.into_iter().map(|v| v.as_u8()).collect(),
+            _ => None,
+        }
+    }
+
+    // A rough estimate of the number of bytes the value is encoded as, for
+    // reserving space up front. Nested values aren't visited: each is assumed
+    // to take a couple of bytes.
+    pub(crate) fn estimated_len(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len() + 5,
+            Value::Bytes(bs) => bs.len() + 5,
+            Value::Array(vs) => vs.len() * 2 + 5,
+            Value::IntArray(is) => is.len() * 2 + 6,
+            _ => 3,
+        }
+    }
+}
+
+impl EntityId {
+    /// Whether the ID refers to an entity (which may since have been deleted).
+    pub fn is_valid(&self) -> bool {
+        matches!(self, EntityId::Idx(_))
+    }
+}
+
+// Floats are hashed by their bit pattern (with both zeroes treated alike),
+// so this is consistent with the derived `PartialEq`: values that compare
+// equal always hash equally, and NaN never compares equal to anything.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Value::Bool(b) => b.hash(state),
+            Value::Int(i) => i.hash(state),
+            Value::Float(x) => {
+                let bits = if *x == 0.0 { 0 } else { x.to_bits() };
+                bits.hash(state)
+            }
+            Value::Decimal { mantissa, scale } => {
+                mantissa.hash(state);
+                scale.hash(state);
+            }
+            Value::Bytes(bs) => bs.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Array(vs) => vs.hash(state),
+            Value::IntArray(is) => is.hash(state),
+            Value::Maybe(v) => v.hash(state),
+            Value::EntityId(id) => id.hash(state),
+        }
+    }
+}
+
+impl<R: decode::Source> decode::State<R> {
+    pub(crate) fn decode_raw_bytes(&mut self, len: usize, ex: &'static str) -> Result<Vec<u8>, decode::Error> {
+        let mut bytes = Vec::with_capacity(decode::capacity_hint(len));
+        for _ in 0..len {
+            bytes.push(self.next(ex)?);
+        }
+        Ok(bytes)
+    }
+
+    pub(crate) fn skip_raw_bytes(&mut self, len: usize, ex: &'static str) -> Result<(), decode::Error> {
+        for _ in 0..len {
+            self.next(ex)?;
+        }
+        Ok(())
+    }
+
     fn decode_bytes(&mut self, len: usize) -> Result<Value, decode::Error> {
-        let mut bytes = Vec::with_capacity(len);
+        Ok(Value::Bytes(self.decode_raw_bytes(len, "byte string")?))
+    }
+
+    fn decode_str(&mut self, len: usize) -> Result<Value, decode::Error> {
+        let bytes = self.decode_raw_bytes(len, "UTF-8 string")?;
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(Value::Str(s)),
+            Err(_) => Err(self.err_unexpected(
+                decode::ErrorKind::InvalidUtf8,
+                "UTF-8 string",
+                "invalid UTF-8",
+            )),
+        }
+    }
+
+    fn decode_int_width(&mut self) -> Result<u8, decode::Error> {
+        let width = self.decode_u8()?;
+        if ![1, 2, 4, 8].contains(&width) {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
+                "packed integer width (1, 2, 4, or 8)",
+                format!("width {}", width),
+            ));
+        }
+        Ok(width)
+    }
+
+    fn decode_int_array(&mut self) -> Result<Value, decode::Error> {
+        let width = self.decode_int_width()?;
+        let len = self.decode_u32()?;
+        let mut ints = Vec::with_capacity(decode::capacity_hint(len as usize));
         for _ in 0..len {
-            bytes.push(self.next("byte string")?);
+            ints.push(match width {
+                1 => self.decode_i8()? as i64,
+                2 => self.decode_i16()? as i64,
+                4 => self.decode_i32()? as i64,
+                _ => self.decode_i64()?,
+            });
         }
-        Ok(Value::Bytes(bytes))
+        Ok(Value::IntArray(ints))
+    }
+
+    // Decode `count` values which must all be integers, appending them to `out`.
+    // This skips constructing a `Value` for each of them, which adds up when
+    // scanning large numeric component arrays. Any other kind of value is an
+    // error, in which case the integers before it have still been appended.
+    pub fn decode_small_ints_into(&mut self, out: &mut Vec<i64>, count: usize) -> Result<(), decode::Error> {
+        out.reserve(decode::capacity_hint(count));
+        for _ in 0..count {
+            let b = self.next("integer")?;
+            out.push(match b {
+                tags::INT_INLINE ..= tags::INT_INLINE_MAX => b as i64,
+                tags::I8 => self.decode_i8()? as i64,
+                tags::I16 => self.decode_i16()? as i64,
+                tags::I32 => self.decode_i32()? as i64,
+                tags::I64 => self.decode_i64()?,
+                _ => return Err(self.err_unexpected(
+                    decode::ErrorKind::InvalidTag,
+                    "integer",
+                    format!("non-integer tag ({:02x})", b),
+                )),
+            });
+        }
+        Ok(())
     }
 
     fn decode_array(&mut self, len: usize) -> Result<Value, decode::Error> {
-        let mut vals = Vec::with_capacity(len);
+        let mut vals = Vec::with_capacity(decode::capacity_hint(len));
         for _ in 0..len {
-            vals.push(self.decode_value()?);
+            // arrays of small ints are common, so their elements are read here
+            // directly rather than going through the full dispatch
+            match self.peek()? {
+                Some(b) if b <= tags::INT_INLINE_MAX && self.depth < self.max_depth => {
+                    self.try_next()?;
+                    vals.push(Value::Int(b as i64));
+                }
+                _ => vals.push(self.decode_value()?),
+            }
         }
         Ok(Value::Array(vals))
     }
     
     pub fn decode_value(&mut self) -> Result<Value, decode::Error> {
+        // guard against overflowing the stack on deeply nested values
+        if self.depth >= self.max_depth {
+            return Err(self.err_too_deep());
+        }
+
+        self.depth += 1;
+        let result = self.decode_value_unchecked();
+        self.depth -= 1;
+        result
+    }
+
+    // Decode values until the input ends. The input must end between values:
+    // if it ends partway through one, the iterator yields an error.
+    pub fn values(&mut self) -> Values<'_, R> {
+        Values { state: self, done: false }
+    }
+
+    // Decode a value, failing if it takes up more than `max_bytes` bytes of input.
+    // This does not affect any limit already in place for the enclosing value.
+    pub fn decode_value_within(&mut self, max_bytes: usize) -> Result<Value, decode::Error> {
+        let outer_limit = self.limit;
+        self.limit = self.idx().saturating_add(max_bytes).min(outer_limit);
+        let result = self.decode_value();
+        self.limit = outer_limit;
+        result
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn err_too_deep(&self) -> decode::Error {
+        self.err_unexpected(
+            decode::ErrorKind::DepthLimitExceeded,
+            format!("value nested at most {} levels deep", self.max_depth),
+            "more deeply nested value",
+        )
+    }
+
+    // Only the tags for nested values are handled here, which keeps the stack
+    // frame of this function (and so the cost of each level of recursion) small.
+    fn decode_value_unchecked(&mut self) -> Result<Value, decode::Error> {
         let b = self.next("value")?;
         match b {
-            0x00 ..= 0x7f => Ok(Value::Int(b as i64)),
-            0x80 ..= 0x8f => self.decode_bytes((b - 0x80) as usize),
-            0x90 ..= 0x9f => self.decode_array((b - 0x90) as usize),
-            0xa0 => { let len = self.decode_u8()?; self.decode_bytes(len as usize) }
-            0xa1 => { let len = self.decode_u32()?; self.decode_bytes(len as usize) }
-            0xa2 => { let len = self.decode_u8()?; self.decode_array(len as usize) }
-            0xa3 => { let len = self.decode_u32()?; self.decode_array(len as usize) }
-            0xa4 => Ok(Value::Bool(false)),
-            0xa5 => Ok(Value::Bool(true)),
-            0xa6 => Ok(Value::Float(self.decode_f32()? as f64)),
-            0xa7 => Ok(Value::Float(self.decode_f64()?)),
-            0xa8 => Ok(Value::Int(self.decode_i8()? as i64)),
-            0xa9 => Ok(Value::Int(self.decode_i16()? as i64)),
-            0xaa => Ok(Value::Int(self.decode_i32()? as i64)),
-            0xab => Ok(Value::Int(self.decode_i64()?)),
-            0xac => Ok(Value::Maybe(None)),
-            0xad => Ok(Value::Maybe(Some(Box::new(self.decode_value()?)))),
-            0xae => Ok(Value::EntityId(EntityId::Idx(self.decode_u8()? as u32))),
-            0xaf => Ok(Value::EntityId(EntityId::Idx(self.decode_u16()? as u32))),
-            0xb0 => Ok(Value::EntityId(EntityId::Idx(self.decode_u32()?))),
-            0xb1 => Ok(Value::EntityId(EntityId::Invalid)),
-
-            0xb2 ..= 0xbf => Err(self.err_unexpected(
+            tags::ARRAY_INLINE ..= tags::ARRAY_INLINE_MAX => self.decode_array((b - tags::ARRAY_INLINE) as usize),
+            tags::ARRAY_U8 => { let len = self.decode_u8()?; self.decode_array(len as usize) }
+            tags::ARRAY_U32 => { let len = self.decode_u32()?; self.decode_array(len as usize) }
+            tags::SOME => Ok(Value::Maybe(Some(Box::new(self.decode_value()?)))),
+            _ => self.decode_scalar(b),
+        }
+    }
+
+    // With `strict_tags`, reject tags that were added after the version being decoded.
+    fn check_tag_version(&self, b: u8) -> Result<(), decode::Error> {
+        match tags::introduced_in(b) {
+            Some(version) if self.strict_tags && version > self.version() => Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
+                "value",
+                format!("invalid byte for format version {} ({:02x})", self.version(), b),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    #[inline(never)]
+    fn decode_scalar(&mut self, b: u8) -> Result<Value, decode::Error> {
+        self.check_tag_version(b)?;
+
+        match b {
+            tags::INT_INLINE ..= tags::INT_INLINE_MAX => Ok(Value::Int(b as i64)),
+            tags::BYTES_INLINE ..= tags::BYTES_INLINE_MAX => self.decode_bytes((b - tags::BYTES_INLINE) as usize),
+            tags::BYTES_U8 => { let len = self.decode_u8()?; self.decode_bytes(len as usize) }
+            tags::BYTES_U32 => { let len = self.decode_u32()?; self.decode_bytes(len as usize) }
+            tags::BOOL_FALSE => Ok(Value::Bool(false)),
+            tags::BOOL_TRUE => Ok(Value::Bool(true)),
+            // f64 NaNs are decoded with their bits intact, but f32 NaNs (which this
+            // encoder only writes when f32 is forced) become the canonical quiet
+            // NaN, since widening one would depend on the platform
+            tags::F32 => {
+                let x = self.decode_f32()?;
+                Ok(Value::Float(if x.is_nan() { f64::NAN } else { x as f64 }))
+            }
+            tags::F64 => Ok(Value::Float(self.decode_f64()?)),
+            tags::I8 => Ok(Value::Int(self.decode_i8()? as i64)),
+            tags::I16 => Ok(Value::Int(self.decode_i16()? as i64)),
+            tags::I32 => Ok(Value::Int(self.decode_i32()? as i64)),
+            tags::I64 => Ok(Value::Int(self.decode_i64()?)),
+            tags::NONE => Ok(Value::Maybe(None)),
+            tags::ENTITY_U8 => Ok(Value::EntityId(EntityId::Idx(self.decode_u8()? as u32))),
+            tags::ENTITY_U16 => Ok(Value::EntityId(EntityId::Idx(self.decode_u16()? as u32))),
+            tags::ENTITY_U24 => Ok(Value::EntityId(EntityId::Idx(self.decode_u24()?))),
+            tags::ENTITY_U32 => Ok(Value::EntityId(EntityId::Idx(self.decode_u32()?))),
+            tags::ENTITY_INVALID => Ok(Value::EntityId(EntityId::Invalid)),
+            tags::STR_U8 => { let len = self.decode_u8()?; self.decode_str(len as usize) }
+            tags::STR_U32 => { let len = self.decode_u32()?; self.decode_str(len as usize) }
+
+            tags::DECIMAL => {
+                let scale = self.decode_u8()?;
+                let mantissa = self.decode_i64()?;
+                Ok(Value::Decimal { mantissa, scale })
+            }
+
+            tags::INT_ARRAY => self.decode_int_array(),
+
+            tags::ARRAY_INLINE ..= tags::ARRAY_INLINE_MAX | tags::ARRAY_U8 | tags::ARRAY_U32 | tags::SOME => unreachable!("nested value tag"),
+
+            tags::RESERVED ..= tags::RESERVED_MAX => Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
+                "value",
+                format!("invalid byte ({:02x})", b),
+            )),
+
+            tags::ENTITY_INLINE ..= tags::ENTITY_INLINE_MAX => Ok(Value::EntityId(EntityId::Idx((b - tags::ENTITY_INLINE) as u32))),
+        }
+    }
+
+    // Read past a value without constructing it, checking its tags, lengths and
+    // nesting depth as `decode_value` does. Strings aren't checked to be valid
+    // UTF-8, since that would mean keeping them.
+    pub(crate) fn skip_value(&mut self) -> Result<(), decode::Error> {
+        if self.depth >= self.max_depth {
+            return Err(self.err_too_deep());
+        }
+
+        self.depth += 1;
+        let result = self.skip_value_unchecked();
+        self.depth -= 1;
+        result
+    }
+
+    fn skip_value_unchecked(&mut self) -> Result<(), decode::Error> {
+        let b = self.next("value")?;
+        self.check_tag_version(b)?;
+
+        // the number of bytes following the tag (and any length)
+        let len = match b {
+            tags::ARRAY_INLINE ..= tags::ARRAY_INLINE_MAX => return self.skip_values((b - tags::ARRAY_INLINE) as usize),
+            tags::ARRAY_U8 => { let len = self.decode_u8()?; return self.skip_values(len as usize) }
+            tags::ARRAY_U32 => { let len = self.decode_u32()?; return self.skip_values(len as usize) }
+            tags::SOME => return self.skip_value(),
+
+            tags::BYTES_INLINE ..= tags::BYTES_INLINE_MAX => (b - tags::BYTES_INLINE) as usize,
+            tags::BYTES_U8 | tags::STR_U8 => self.decode_u8()? as usize,
+            tags::BYTES_U32 | tags::STR_U32 => self.decode_u32()? as usize,
+            tags::I8 | tags::ENTITY_U8 => 1,
+            tags::I16 | tags::ENTITY_U16 => 2,
+            tags::ENTITY_U24 => 3,
+            tags::F32 | tags::I32 | tags::ENTITY_U32 => 4,
+            tags::F64 | tags::I64 => 8,
+            tags::DECIMAL => 9,
+            tags::INT_ARRAY => {
+                let width = self.decode_int_width()?;
+                let len = self.decode_u32()?;
+                (len as usize).saturating_mul(width as usize)
+            }
+
+            tags::RESERVED ..= tags::RESERVED_MAX => return Err(self.err_unexpected(
+                decode::ErrorKind::InvalidTag,
                 "value",
                 format!("invalid byte ({:02x})", b),
             )),
 
-            0xc0 ..= 0xff => Ok(Value::EntityId(EntityId::Idx((b - 0xc0) as u32))),
+            // the tag is the whole value
+            _ => 0,
+        };
+        self.skip_raw_bytes(len, "value")
+    }
+
+    fn skip_values(&mut self, len: usize) -> Result<(), decode::Error> {
+        for _ in 0..len {
+            self.skip_value()?;
         }
+        Ok(())
     }
 }
 
-impl<W: io::Write> encode::State<W> {
+// Decodes a stream of values, returned by `decode::State::values`. After an
+// error is returned, the iterator yields nothing more.
+pub struct Values<'a, R: decode::Source> {
+    state: &'a mut decode::State<R>,
+    done: bool,
+}
+
+impl<'a, R: decode::Source> Iterator for Values<'a, R> {
+    type Item = Result<Value, decode::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.state.at_eof() {
+            Ok(true) => return None,
+            Ok(false) => self.state.decode_value(),
+            Err(err) => Err(err),
+        };
+        self.done = result.is_err();
+        Some(result)
+    }
+}
+
+fn too_large(what: &str, len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is too large to encode ({})", what, len),
+    )
+}
+
+impl<W: encode::Sink> encode::State<W> {
     pub fn encode_value<ET: FnMut(&mut EntityId)>(
         &mut self,
         val: &Value,
         e_id_transform: &mut ET
+    ) -> io::Result<()> {
+        // guard against overflowing the stack on deeply nested values
+        if self.depth >= self.max_depth {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("value is nested more than {} levels deep", self.max_depth),
+            ));
+        }
+
+        self.depth += 1;
+        let result = self.encode_value_unchecked(val, e_id_transform);
+        self.depth -= 1;
+        result
+    }
+
+    fn encode_value_unchecked<ET: FnMut(&mut EntityId)>(
+        &mut self,
+        val: &Value,
+        e_id_transform: &mut ET
     ) -> io::Result<()> {
         match val {
-            Value::Bool(false) => self.write(&[0xa4]),
-            Value::Bool(true) => self.write(&[0xa5]),
+            Value::Bool(false) => self.write(&[tags::BOOL_FALSE]),
+            Value::Bool(true) => self.write(&[tags::BOOL_TRUE]),
 
             Value::Int(i) => {
                 let i = *i;
                 // fit the number into as small a representation as possible
-                if (0..0x80).contains(&i) {
+                if (0..=tags::INT_INLINE_MAX as i64).contains(&i) {
                     self.write(&[i as u8])
                 } else if let Ok(i) = i8::try_from(i) {
-                    self.write(&[0xa8])?;
+                    self.write(&[tags::I8])?;
                     self.write(&i.to_be_bytes())
                 } else if let Ok(i) = i16::try_from(i) {
-                    self.write(&[0xa9])?;
+                    self.write(&[tags::I16])?;
                     self.write(&i.to_be_bytes())
                 } else if let Ok(i) = i32::try_from(i) {
-                    self.write(&[0xaa])?;
+                    self.write(&[tags::I32])?;
                     self.write(&i.to_be_bytes())
                 } else {
-                    self.write(&[0xab])?;
+                    self.write(&[tags::I64])?;
                     self.write(&i.to_be_bytes())
                 }
             }
 
             Value::Float(x) => {
-                // represent the float with only 32 bits if possible
-                let x_f32 = *x as f32;
-                if x_f32 as f64 == *x {
-                    self.write(&[0xa6])?;
+                // represent the float with only 32 bits if possible (or allowed).
+                // Compare the bits rather than the values so that the narrowing is
+                // exact: `-0.0 == 0.0` and NaNs never compare equal.
+                //
+                // Converting a NaN between widths may quiet it or drop its payload,
+                // depending on the platform, so NaNs are always written as f64 to
+                // keep their exact bits, unless f32 is forced. In that case they
+                // are written as the canonical quiet NaN.
+                let x_f32 = if x.is_nan() { f32::NAN } else { *x as f32 };
+                let use_f32 = match self.float_policy {
+                    encode::FloatPolicy::Auto => !x.is_nan() && (x_f32 as f64).to_bits() == x.to_bits(),
+                    encode::FloatPolicy::AlwaysF32 => true,
+                    encode::FloatPolicy::AlwaysF64 => false,
+                };
+                if use_f32 {
+                    self.write(&[tags::F32])?;
                     self.write(&x_f32.to_be_bytes())
                 } else {
-                    self.write(&[0xa7])?;
+                    self.write(&[tags::F64])?;
                     self.write(&x.to_be_bytes())
                 }
             }
 
+            Value::Decimal { mantissa, scale } => {
+                self.write(&[tags::DECIMAL, *scale])?;
+                self.write(&mantissa.to_be_bytes())
+            }
+
             Value::Bytes(bs) => {
                 let len = bs.len();
                 // fit the length header into as small a representation as possible
                 if let Ok(len) = u8::try_from(len) {
-                    if len < 0x10 {
-                        self.write(&[0x80 + len])?;
+                    if len <= tags::BYTES_INLINE_MAX - tags::BYTES_INLINE {
+                        self.write(&[tags::BYTES_INLINE + len])?;
                     } else {
-                        self.write(&[0xa0, len])?;
+                        self.write(&[tags::BYTES_U8, len])?;
                     }
                 } else if let Ok(len) = u32::try_from(len) {
-                    self.write(&[0xa1])?;
+                    self.write(&[tags::BYTES_U32])?;
                     self.write(&len.to_be_bytes())?;
                 } else {
-                    panic!("byte string is too large ({})", len);
+                    return Err(too_large("byte string", len));
                 }
-                self.write(&bs)
+                self.write(bs)
+            }
+
+            Value::Str(s) => {
+                let len = s.len();
+                // strings have no inline-length form, since that range is taken by `Bytes`
+                if let Ok(len) = u8::try_from(len) {
+                    self.write(&[tags::STR_U8, len])?;
+                } else if let Ok(len) = u32::try_from(len) {
+                    self.write(&[tags::STR_U32])?;
+                    self.write(&len.to_be_bytes())?;
+                } else {
+                    return Err(too_large("string", len));
+                }
+                self.write(s.as_bytes())
             }
 
             Value::Array(vs) => {
                 let len = vs.len();
                 // fit the length header into as small a representation as possible
                 if let Ok(len) = u8::try_from(len) {
-                    if len < 0x10 {
-                        self.write(&[0x90 + len])?;
+                    if len <= tags::ARRAY_INLINE_MAX - tags::ARRAY_INLINE {
+                        self.write(&[tags::ARRAY_INLINE + len])?;
                     } else {
-                        self.write(&[0xa2, len])?;
+                        self.write(&[tags::ARRAY_U8, len])?;
                     }
                 } else if let Ok(len) = u32::try_from(len) {
-                    self.write(&[0xa2])?;
+                    self.write(&[tags::ARRAY_U32])?;
                     self.write(&len.to_be_bytes())?;
                 } else {
-                    panic!("array is too large ({})", len);
+                    return Err(too_large("array", len));
                 }
                 for v in vs {
-                    self.encode_value(&v, e_id_transform)?;
+                    self.encode_value(v, e_id_transform)?;
+                }
+                Ok(())
+            }
+
+            Value::IntArray(is) => {
+                // pick the smallest width that fits every element
+                let width = is.iter()
+                    .map(|&i| {
+                        if i8::try_from(i).is_ok() {
+                            1
+                        } else if i16::try_from(i).is_ok() {
+                            2
+                        } else if i32::try_from(i).is_ok() {
+                            4
+                        } else {
+                            8
+                        }
+                    })
+                    .max()
+                    .unwrap_or(1);
+                let len = match u32::try_from(is.len()) {
+                    Ok(len) => len,
+                    Err(_) => return Err(too_large("integer array", is.len())),
+                };
+
+                self.write(&[tags::INT_ARRAY, width])?;
+                self.write(&len.to_be_bytes())?;
+                for &i in is {
+                    match width {
+                        1 => self.write(&(i as i8).to_be_bytes())?,
+                        2 => self.write(&(i as i16).to_be_bytes())?,
+                        4 => self.write(&(i as i32).to_be_bytes())?,
+                        _ => self.write(&i.to_be_bytes())?,
+                    }
                 }
                 Ok(())
             }
 
-            Value::Maybe(None) => self.write(&[0xac]),
+            Value::Maybe(None) => self.write(&[tags::NONE]),
             Value::Maybe(Some(v)) => {
-                self.write(&[0xad])?;
-                self.encode_value(&v, e_id_transform)
+                self.write(&[tags::SOME])?;
+                self.encode_value(v, e_id_transform)
             }
 
             Value::EntityId(mut id) => {
@@ -165,20 +682,23 @@ impl<W: io::Write> encode::State<W> {
                 match id {
                     EntityId::Idx(i) => {
                         if let Ok(i) = u8::try_from(i) {
-                            if i < 0x40 {
-                                self.write(&[0xc0 + i])
+                            if i <= tags::ENTITY_INLINE_MAX - tags::ENTITY_INLINE {
+                                self.write(&[tags::ENTITY_INLINE + i])
                             } else {
-                                self.write(&[0xae, i])
+                                self.write(&[tags::ENTITY_U8, i])
                             }
                         } else if let Ok(i) = u16::try_from(i) {
-                            self.write(&[0xaf])?;
+                            self.write(&[tags::ENTITY_U16])?;
                             self.write(&i.to_be_bytes())
+                        } else if i < 0x100_0000 {
+                            self.write(&[tags::ENTITY_U24])?;
+                            self.write(&i.to_be_bytes()[1..])
                         } else {
-                            self.write(&[0xb0])?;
+                            self.write(&[tags::ENTITY_U32])?;
                             self.write(&i.to_be_bytes())
                         }
                     }
-                    EntityId::Invalid => self.write(&[0xb1]),
+                    EntityId::Invalid => self.write(&[tags::ENTITY_INVALID]),
                 }
             }
         }
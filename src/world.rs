@@ -1,20 +1,62 @@
 use vec_map::VecMap;
 
-use std::collections::HashSet;
-use std::io;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
 
 use super::decode;
 use super::encode;
 
-use super::value::EntityId;
+use super::error::{self, BuildError};
+use super::value::{EntityId, Value};
 
-use super::component::{ComponentArray, GlobalComponent};
-use super::entity::EntityArray;
+use super::component::{
+    self, ChangeCallback, ChangeObserver, ComponentArray, ComponentId, ComponentMut, ComponentRef,
+    GlobalComponent,
+};
+use super::entity::{ComponentIdx, EntityArray, EntityData, Owner};
+use super::patch::{ComponentPatch, EntityPatch, WorldPatch};
 
 pub struct WorldData {
-    components: VecMap<ComponentArray>,
-    global: GlobalComponent,
-    entities: EntityArray,
+    pub(crate) components: VecMap<ComponentArray>,
+    pub(crate) global: GlobalComponent,
+    pub(crate) entities: EntityArray,
+    // the `META` lines of the world, sorted so that they're encoded in a stable order
+    pub(crate) metadata: BTreeMap<String, String>,
+    // callbacks for changes to each type of component
+    pub(crate) observers: VecMap<Vec<ChangeCallback>>,
+}
+
+// Only a summary is printed, since a world can hold a lot of data.
+impl fmt::Debug for WorldData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let live = self.entities.entries.iter().filter(|e| !e.is_deleted).count();
+        f.debug_struct("WorldContext")
+            .field("components", &self.component_counts().into_iter()
+                .map(|(id, name, count)| (id.0, name, count))
+                .collect::<Vec<_>>())
+            .field("global", &self.global.scheme())
+            .field("entities", &live)
+            .finish()
+    }
+}
+
+// Worlds are equal if they have the same component arrays, global component,
+// metadata, and live entities. Entities are compared by the indices of their components, so
+// worlds whose arrays store the same components in different orders are unequal.
+impl PartialEq for WorldData {
+    fn eq(&self, other: &Self) -> bool {
+        self.components == other.components
+            && self.global == other.global
+            && self.metadata == other.metadata
+            && self.entities.entries.len() == other.entities.entries.len()
+            && self.entities.entries.iter().zip(&other.entities.entries).all(|(a, b)| {
+                a.is_deleted == b.is_deleted && (a.is_deleted || a.components == b.components)
+            })
+    }
 }
 
 impl Default for WorldData {
@@ -23,122 +65,1723 @@ impl Default for WorldData {
             components: VecMap::new(),
             global: GlobalComponent::empty(),
             entities: EntityArray::empty(),
+            metadata: BTreeMap::new(),
+            observers: VecMap::new(),
+        }
+    }
+}
+
+/// A saved state of a world, created by `WorldContext::snapshot_clone` or
+/// `snapshot_bytes` and passed to `WorldContext::restore`, for example to
+/// implement undo.
+pub struct WorldSnapshot(Snapshot);
+
+enum Snapshot {
+    Bytes(Vec<u8>),
+    Clone(WorldData),
+}
+
+impl WorldSnapshot {
+    /// The number of bytes the snapshot takes up, if it is encoded.
+    pub fn encoded_len(&self) -> Option<usize> {
+        match &self.0 {
+            Snapshot::Bytes(bytes) => Some(bytes.len()),
+            Snapshot::Clone(_) => None,
+        }
+    }
+}
+
+/// Constructs a `WorldContext` programmatically, validating it before it is built.
+///
+/// Entities are given IDs in the order they are spawned, starting from zero.
+#[derive(Default)]
+pub struct WorldBuilder {
+    components: Vec<(String, ComponentId, Vec<String>)>,
+    global: Option<(Vec<String>, Vec<Value>)>,
+    entities: Vec<Vec<(ComponentId, Vec<Value>)>>,
+}
+
+impl WorldBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a component array with the given name, ID, and field names.
+    pub fn component(
+        mut self,
+        name: impl Into<String>,
+        id: ComponentId,
+        scheme: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        let scheme = scheme.into_iter().map(Into::into).collect();
+        self.components.push((name.into(), id, scheme));
+        self
+    }
+
+    /// Set the field names and values of the global component.
+    pub fn global(
+        mut self,
+        scheme: impl IntoIterator<Item = impl Into<String>>,
+        values: Vec<Value>,
+    ) -> Self {
+        let scheme = scheme.into_iter().map(Into::into).collect();
+        self.global = Some((scheme, values));
+        self
+    }
+
+    /// Add an entity with the given components, each of which is specified
+    /// by its ID and the values of its fields.
+    pub fn spawn(mut self, components: impl IntoIterator<Item = (ComponentId, Vec<Value>)>) -> Self {
+        self.entities.push(components.into_iter().collect());
+        self
+    }
+
+    pub fn build(self) -> Result<WorldData, BuildError> {
+        let num_entities = self.entities.len();
+        let check_values = |values: &[Value]| check_entity_ids(values, num_entities);
+
+        let mut components = VecMap::with_capacity(self.components.len());
+        let mut names = HashSet::with_capacity(self.components.len());
+        for (name, id, scheme) in self.components {
+            if !component::is_valid_name(&name) {
+                return Err(BuildError::InvalidName(name));
+            }
+            check_scheme(&scheme)?;
+            if components.contains_key(id.0 as usize) {
+                return Err(BuildError::DuplicateComponentId(id));
+            }
+            if !names.insert(name.clone()) {
+                return Err(BuildError::DuplicateComponentName(name));
+            }
+            components.insert(id.0 as usize, ComponentArray::new(name, id.0, scheme));
+        }
+
+        let global = match self.global {
+            None => GlobalComponent::empty(),
+            Some((scheme, values)) => {
+                check_scheme(&scheme)?;
+                if values.len() != scheme.len() {
+                    return Err(BuildError::GlobalValueCount {
+                        expected: scheme.len(),
+                        got: values.len(),
+                    });
+                }
+                check_values(&values)?;
+                GlobalComponent::new(scheme, values)
+            }
+        };
+
+        let mut entries = Vec::with_capacity(num_entities);
+        for entity in self.entities {
+            let mut data = EntityData { is_deleted: false, components: Vec::with_capacity(entity.len()) };
+            for (ComponentId(id), values) in entity {
+                let array: &mut ComponentArray = components.get_mut(id as usize)
+                    .ok_or(BuildError::UnknownComponent(ComponentId(id)))?;
+                if values.len() != array.scheme().len() {
+                    return Err(BuildError::ValueCount {
+                        id: ComponentId(id),
+                        expected: array.scheme().len(),
+                        got: values.len(),
+                    });
+                }
+                check_values(&values)?;
+                let idx = array.push(values);
+                data.insert_component(ComponentIdx { id, idx });
+            }
+            entries.push(data);
+        }
+
+        Ok(WorldData {
+            components,
+            global,
+            entities: EntityArray { entries },
+            metadata: BTreeMap::new(),
+            observers: VecMap::new(),
+        })
+    }
+}
+
+fn check_scheme(scheme: &[String]) -> Result<(), BuildError> {
+    if let Some(name) = scheme.iter().find(|n| !component::is_valid_name(n)) {
+        return Err(BuildError::InvalidName(name.clone()));
+    }
+    if let Some(dup) = component::find_duplicate_quadratic(scheme) {
+        return Err(BuildError::DuplicateField(dup.clone()));
+    }
+    Ok(())
+}
+
+// Check that every `EntityId` in `values` refers to one of the first `num_entities` entities.
+fn check_entity_ids(values: &[Value], num_entities: usize) -> Result<(), BuildError> {
+    let mut result = Ok(());
+    for value in values {
+        value.visit_entity_ids(&mut |id| {
+            if let EntityId::Idx(idx) = id {
+                if idx as usize >= num_entities && result.is_ok() {
+                    result = Err(BuildError::InvalidEntityId(idx));
+                }
+            }
+        });
+    }
+    result
+}
+
+impl WorldData {
+    /// Add an empty component array with the given name and scheme, and return
+    /// the ID it was given: the lowest one not already in use. If every ID is in
+    /// use, `ComponentIdsExhausted` is returned.
+    pub fn add_component_array(&mut self, name: String, scheme: Vec<String>) -> Result<ComponentId, BuildError> {
+        if !component::is_valid_name(&name) {
+            return Err(BuildError::InvalidName(name));
+        }
+        check_scheme(&scheme)?;
+        if self.components.values().any(|array| array.name() == name) {
+            return Err(BuildError::DuplicateComponentName(name));
+        }
+
+        let id = self.free_component_ids(ComponentId(u16::MAX))
+            .next()
+            .ok_or(BuildError::ComponentIdsExhausted)?;
+        self.components.insert(id.0 as usize, ComponentArray::new(name, id.0, scheme));
+        Ok(id)
+    }
+
+    /// Give the component array with ID `from` the ID `to` instead, updating the
+    /// entities that have it and moving any observers registered for it. Fails
+    /// if there is no array with ID `from`, or if another array already has `to`.
+    pub fn remap_component_id(&mut self, from: ComponentId, to: ComponentId) -> Result<(), BuildError> {
+        if !self.components.contains_key(from.0 as usize) {
+            return Err(BuildError::UnknownComponent(from));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if self.components.contains_key(to.0 as usize) {
+            return Err(BuildError::DuplicateComponentId(to));
+        }
+
+        let mut array = self.components.remove(from.0 as usize).unwrap();
+        array.set_id(to);
+        self.components.insert(to.0 as usize, array);
+        if let Some(callbacks) = self.observers.remove(from.0 as usize) {
+            self.observers.insert(to.0 as usize, callbacks);
+        }
+
+        for entity in &mut self.entities.entries {
+            let mut changed = false;
+            for comp_idx in &mut entity.components {
+                if comp_idx.id == from.0 {
+                    comp_idx.id = to.0;
+                    changed = true;
+                }
+            }
+            // the components of each entity are kept sorted by ID
+            if changed {
+                entity.components.sort_by_key(|comp_idx| comp_idx.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an entity with the given components, each given by its ID and the
+    /// values of its fields, and return its ID. This is the same as
+    /// `WorldBuilder::spawn`, but for a world that has already been built: the
+    /// components are validated in the same way, and the world is left as it
+    /// was if they are invalid.
+    pub fn spawn(
+        &mut self,
+        components: impl IntoIterator<Item = (ComponentId, Vec<Value>)>,
+    ) -> Result<EntityId, BuildError> {
+        let components: Vec<(u16, Vec<Value>)> = components.into_iter()
+            .map(|(id, values)| (id.0, values))
+            .collect();
+        let idx = self.entities.entries.len() as u32;
+        for (i, (id, values)) in components.iter().enumerate() {
+            if components[..i].iter().any(|(other, _)| other == id) {
+                return Err(BuildError::ComponentAlreadyPresent { entity: idx, id: ComponentId(*id) });
+            }
+            self.check_component(*id, values, idx as usize + 1)?;
+        }
+
+        let mut entity = EntityData { is_deleted: false, components: Vec::with_capacity(components.len()) };
+        for (id, values) in components {
+            let idx = self.components[id as usize].push(values);
+            entity.insert_component(ComponentIdx { id, idx });
+        }
+        self.entities.entries.push(entity);
+        Ok(EntityId::Idx(idx))
+    }
+
+    /// Give a live entity a component it doesn't already have.
+    pub fn add_component(&mut self, entity: EntityId, id: ComponentId, values: Vec<Value>) -> Result<(), BuildError> {
+        let id = id.0;
+        let idx = match entity {
+            EntityId::Idx(idx) if self.entities.entries.get(idx as usize).map_or(false, |e| !e.is_deleted) => idx,
+            EntityId::Idx(idx) => return Err(BuildError::InvalidEntityId(idx)),
+            EntityId::Invalid => return Err(BuildError::InvalidEntityId(u32::MAX)),
+        };
+        if self.entities.entries[idx as usize].component_idx(id).is_some() {
+            return Err(BuildError::ComponentAlreadyPresent { entity: idx, id: ComponentId(id) });
+        }
+        self.check_component(id, &values, self.entities.entries.len())?;
+
+        let comp_idx = self.components[id as usize].push(values);
+        self.entities.entries[idx as usize].insert_component(ComponentIdx { id, idx: comp_idx });
+        Ok(())
+    }
+
+    // Check that a component can be added to an entity of a world with
+    // `num_entities` entities.
+    fn check_component(&self, id: u16, values: &[Value], num_entities: usize) -> Result<(), BuildError> {
+        let array = self.components.get(id as usize).ok_or(BuildError::UnknownComponent(ComponentId(id)))?;
+        if values.len() != array.scheme().len() {
+            return Err(BuildError::ValueCount {
+                id: ComponentId(id),
+                expected: array.scheme().len(),
+                got: values.len(),
+            });
+        }
+        check_entity_ids(values, num_entities)
+    }
+
+    /// Check that every live entity only refers to components that exist.
+    /// Encoding doesn't check this, so a world built by modifying its entities
+    /// directly should be checked before it's written out.
+    pub fn check_component_refs(&self) -> Result<(), BuildError> {
+        for entity in self.entities.entries.iter().filter(|entity| !entity.is_deleted) {
+            for comp_idx in &entity.components {
+                let array = self.components.get(comp_idx.id as usize)
+                    .ok_or(BuildError::UnknownComponent(ComponentId(comp_idx.id)))?;
+                if array.get(comp_idx.idx).is_none() {
+                    return Err(BuildError::MissingComponent { id: ComponentId(comp_idx.id), idx: comp_idx.idx });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The exact number of bytes that encoding the world would produce.
+    pub fn encoded_size(&self) -> io::Result<usize> {
+        let mut counter = encode::Counter::default();
+        encode::State::new(&mut counter).encode_world(self)?;
+        Ok(counter.0)
+    }
+
+    // A rough estimate of `encoded_size`, which is much cheaper to compute.
+    pub(crate) fn estimated_size(&self) -> usize {
+        let scheme_len = |scheme: &[component::FieldName]| scheme.iter().map(|field| field.len() + 1).sum::<usize>();
+        let values_len = |values: &[Value]| values.iter().map(Value::estimated_len).sum::<usize>();
+
+        let metadata = self.metadata.iter().map(|(key, value)| key.len() + value.len() + 7).sum::<usize>();
+        let components = self.components.values()
+            .map(|array| 32 + array.name().len() + scheme_len(array.scheme()) + values_len(array.values()))
+            .sum::<usize>();
+        let global = 8 + scheme_len(self.global.scheme()) + values_len(self.global.get().values);
+        let entities = 16 + self.entities.entries.iter()
+            .map(|entity| 1 + entity.components.len() * 3)
+            .sum::<usize>();
+        32 + metadata + components + global + entities
+    }
+
+    /// Encode the world into a vector, reserving space for it up front from an
+    /// estimate of its size. Use `encoded_size` with `codec::Encoder::with_capacity`
+    /// instead to allocate exactly the right amount, at the cost of encoding twice.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut state = encode::State::with_capacity(self.estimated_size());
+        state.encode_world(self)?;
+        Ok(state.into_inner())
+    }
+
+    /// Decode a world from the file at `path`, which must contain nothing else.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<WorldData, error::Error> {
+        let file = File::open(path).map_err(|err| error::Error::Decode(err.into()))?;
+        decode::State::new(io::BufReader::new(file)).decode_world().map_err(error::Error::Decode)
+    }
+
+    /// Encode the world into the file at `path`, replacing it if it already exists.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), error::Error> {
+        let file = File::create(path).map_err(error::Error::Encode)?;
+        let mut writer = io::BufWriter::new(file);
+        encode::State::new(&mut writer).encode_world(self).map_err(error::Error::Encode)?;
+        // flush explicitly, since errors are ignored when a `BufWriter` is dropped
+        writer.flush().map_err(error::Error::Encode)
+    }
+
+    /// Make an independent copy of the world, for example to run systems on
+    /// speculatively. This is much faster than encoding and decoding it.
+    pub fn deep_clone(&self) -> WorldData {
+        WorldData {
+            components: self.components.clone(),
+            global: self.global.clone(),
+            entities: self.entities.clone(),
+            metadata: self.metadata.clone(),
+            observers: VecMap::new(),
+        }
+    }
+
+    /// Save the state of the world as a deep clone, which is fast to take and
+    /// to restore.
+    pub fn snapshot_clone(&self) -> WorldSnapshot {
+        WorldSnapshot(Snapshot::Clone(self.deep_clone()))
+    }
+
+    /// Save the state of the world in its encoded form, which is slower but
+    /// much more compact. As when the world is saved, deleted entities are
+    /// dropped and the remaining ones renumbered.
+    pub fn snapshot_bytes(&self) -> io::Result<WorldSnapshot> {
+        Ok(WorldSnapshot(Snapshot::Bytes(self.to_vec()?)))
+    }
+
+    /// Return the world to the state saved in a snapshot. Callbacks registered
+    /// with `on_component_changed` are kept. If an encoded snapshot can't be
+    /// decoded, the error is returned and the world is left as it was.
+    pub fn restore(&mut self, snapshot: WorldSnapshot) -> Result<(), error::DecodeError> {
+        let world = match snapshot.0 {
+            Snapshot::Clone(world) => world,
+            Snapshot::Bytes(bytes) => decode::State::new(&bytes[..]).decode_world()?,
+        };
+        self.components = world.components;
+        self.global = world.global;
+        self.entities = world.entities;
+        self.metadata = world.metadata;
+        Ok(())
+    }
+
+    /// Split the world into its component arrays, global component and entity
+    /// array, for operations the rest of the API doesn't cover. Use `from_parts`
+    /// to put it back together. Metadata and observers are dropped.
+    pub fn into_parts(self) -> (VecMap<ComponentArray>, GlobalComponent, EntityArray) {
+        (self.components, self.global, self.entities)
+    }
+
+    /// Assemble a world from parts taken apart with `into_parts`, checking that
+    /// they are still consistent: component arrays must have distinct IDs and
+    /// names, every `EntityId` must refer to an entity in the array, and every
+    /// component of a live entity must exist. Arrays are stored by their own
+    /// IDs, whatever keys they are given under.
+    pub fn from_parts(
+        components: VecMap<ComponentArray>,
+        global: GlobalComponent,
+        mut entities: EntityArray,
+    ) -> Result<WorldData, BuildError> {
+        let num_entities = entities.entries.len();
+
+        // the arrays themselves can only be constructed with valid schemes
+        let mut arrays = VecMap::with_capacity(components.len());
+        let mut names = HashSet::with_capacity(components.len());
+        for (_, array) in components {
+            if arrays.contains_key(array.id().0 as usize) {
+                return Err(BuildError::DuplicateComponentId(array.id()));
+            }
+            if !names.insert(array.name().to_string()) {
+                return Err(BuildError::DuplicateComponentName(array.name().to_string()));
+            }
+            for idx in 0..array.len() as u32 {
+                check_entity_ids(array.get(idx).unwrap().values, num_entities)?;
+            }
+            arrays.insert(array.id().0 as usize, array);
+        }
+
+        check_entity_ids(global.get().values, num_entities)?;
+
+        for (i, entity) in entities.entries.iter_mut().enumerate() {
+            if entity.is_deleted {
+                continue;
+            }
+            entity.components.sort_by_key(|comp_idx| comp_idx.id);
+            for (j, comp_idx) in entity.components.iter().enumerate() {
+                let array = arrays.get(comp_idx.id as usize)
+                    .ok_or(BuildError::UnknownComponent(ComponentId(comp_idx.id)))?;
+                if array.get(comp_idx.idx).is_none() {
+                    return Err(BuildError::MissingComponent { id: ComponentId(comp_idx.id), idx: comp_idx.idx });
+                }
+                if j > 0 && entity.components[j - 1].id == comp_idx.id {
+                    return Err(BuildError::ComponentAlreadyPresent { entity: i as u32, id: ComponentId(comp_idx.id) });
+                }
+            }
+        }
+
+        Ok(WorldData {
+            components: arrays,
+            global,
+            entities,
+            metadata: BTreeMap::new(),
+            observers: VecMap::new(),
+        })
+    }
+
+    /// The metadata of the world, which is written in `META` lines after the
+    /// world header. Tools can use this to record things like the program or
+    /// author that wrote the file.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Set a metadata entry, returning the previous value for the key. The key
+    /// must be a valid name, and the value must be ASCII without line breaks,
+    /// since both are written in a header line; runs of whitespace in the value
+    /// are read back as single spaces.
+    pub fn set_metadata(&mut self, key: String, value: String) -> Result<Option<String>, BuildError> {
+        if !component::is_valid_name(&key) {
+            return Err(BuildError::InvalidName(key));
+        }
+        if !value.is_ascii() || value.contains(['\n', '\r']) {
+            return Err(BuildError::InvalidMetadataValue(value));
+        }
+        Ok(self.metadata.insert(key, value))
+    }
+
+    /// The IDs of the component arrays in the world, in increasing order.
+    pub fn component_ids(&self) -> impl Iterator<Item = ComponentId> + '_ {
+        self.components.keys().map(|id| ComponentId(id as u16))
+    }
+
+    /// The ID of the component array with the given name, if there is one.
+    /// Component IDs are a separate type from entity IDs, so they can't be
+    /// passed where one is expected by mistake:
+    ///
+    /// ```compile_fail
+    /// let world = serial_ecs::WorldContext::default();
+    /// let id = world.component_by_name("pos").unwrap();
+    /// world.component_for_entity(id, "pos");
+    /// ```
+    pub fn component_by_name(&self, name: &str) -> Option<ComponentId> {
+        self.components.values()
+            .find(|array| array.name() == name)
+            .map(|array| array.id())
+    }
+
+    /// The IDs up to and including `up_to` that no component array in the world
+    /// has, in increasing order, for example to pick IDs for several new arrays.
+    pub fn free_component_ids(&self, up_to: ComponentId) -> impl Iterator<Item = ComponentId> + '_ {
+        (0..=up_to.0)
+            .filter(move |&id| !self.components.contains_key(id as usize))
+            .map(ComponentId)
+    }
+
+    /// The largest ID of any component array in the world, or zero if there are none.
+    /// This is the same as the maximum ID in the header of the encoded world.
+    pub fn max_component_id(&self) -> ComponentId {
+        ComponentId(self.components.keys().next_back().unwrap_or(0) as u16)
+    }
+
+    /// The names of the component arrays in the world, in increasing order of ID.
+    pub fn component_names(&self) -> impl Iterator<Item = &str> {
+        self.components.values().map(|array| array.name())
+    }
+
+    /// The ID, name, and number of components of each component array, in
+    /// increasing order of ID. Marker components always have a count of zero.
+    pub fn component_counts(&self) -> Vec<(ComponentId, &str, u32)> {
+        self.components.values()
+            .map(|array| (array.id(), array.name(), array.len() as u32))
+            .collect()
+    }
+
+    /// Iterate over the name and contents of every component attached to an entity.
+    /// Returns `None` if the ID does not refer to a live entity.
+    pub fn entity_view(
+        &self,
+        id: EntityId,
+    ) -> Option<impl Iterator<Item = (&str, ComponentRef<'_>)>> {
+        let idx = match id {
+            EntityId::Idx(idx) => idx,
+            EntityId::Invalid => return None,
+        };
+        let entity = self.entities.entries.get(idx as usize)?;
+        if entity.is_deleted {
+            return None;
+        }
+
+        Some(entity.components.iter().filter_map(move |comp_idx| {
+            let array = self.components.get(comp_idx.id as usize)?;
+            Some((array.name(), array.get(comp_idx.idx)?))
+        }))
+    }
+
+    /// The IDs of the live entities that have every one of the given components.
+    pub fn query_entities(&self, component_ids: &[ComponentId]) -> impl Iterator<Item = EntityId> + '_ {
+        let mut sorted_ids: Vec<u16> = component_ids.iter().map(|id| id.0).collect();
+        sorted_ids.sort_unstable();
+        sorted_ids.dedup();
+
+        self.entities.entries.iter().enumerate()
+            .filter(move |(_, entity)| !entity.is_deleted && entity.has_all_sorted(&sorted_ids))
+            .map(|(idx, _)| EntityId::Idx(idx as u32))
+    }
+
+    // Find the index of the named component on a live entity, if it has one.
+    fn find_component_idx(&self, id: EntityId, component: &str) -> Option<ComponentIdx> {
+        let idx = match id {
+            EntityId::Idx(idx) => idx,
+            EntityId::Invalid => return None,
+        };
+        let entity = self.entities.entries.get(idx as usize)?;
+        if entity.is_deleted {
+            return None;
+        }
+
+        let (comp_id, _) = self.components.iter().find(|(_, array)| array.name() == component)?;
+        let pos = entity.component_idx(comp_id as u16)?;
+        Some(entity.components[pos])
+    }
+
+    /// Look up the named component of an entity.
+    pub fn component_for_entity(&self, id: EntityId, component: &str) -> Option<ComponentRef<'_>> {
+        let comp_idx = self.find_component_idx(id, component)?;
+        self.components.get(comp_idx.id as usize)?.get(comp_idx.idx)
+    }
+
+    /// Look up the named component of an entity, mutably. Setting its fields with
+    /// `ComponentMut::set_field` notifies the callbacks registered for it.
+    ///
+    /// After `intern_components`, the component may be shared with other
+    /// entities, and changing it changes theirs too. The callbacks are then
+    /// notified once for each live entity that shares it.
+    pub fn component_for_entity_mut(
+        &mut self,
+        id: EntityId,
+        component: &str,
+    ) -> Option<ComponentMut<'_>> {
+        let comp_idx = self.find_component_idx(id, component)?;
+        let mut comp = self.components.get_mut(comp_idx.id as usize)?.get_mut(comp_idx.idx)?;
+        if let Some(callbacks) = self.observers.get(comp_idx.id as usize) {
+            let entities = self.entities.entries.iter()
+                .enumerate()
+                .filter(|(_, entity)| !entity.is_deleted && entity.components.contains(&comp_idx))
+                .map(|(i, _)| EntityId::Idx(i as u32))
+                .collect();
+            comp.observer = Some(ChangeObserver { callbacks, entities });
+        }
+        Some(comp)
+    }
+
+    /// Register a callback to be called with the entity, field name, and new value
+    /// whenever a field of the given type of component is set with `set_field` on a
+    /// component obtained from `component_for_entity_mut`. Changes made by assigning
+    /// to `ComponentMut::values` or through `field_mut` are not reported. A change
+    /// to a component shared by several entities is reported for each of them.
+    ///
+    /// Callbacks are not carried over by `deep_clone` or `project`.
+    pub fn on_component_changed(
+        &mut self,
+        component_id: ComponentId,
+        callback: impl Fn(EntityId, &str, &Value) + Send + Sync + 'static,
+    ) {
+        self.observers.entry(component_id.0 as usize)
+            .or_insert_with(Vec::new)
+            .push(Box::new(callback));
+    }
+
+    /// Delete every live entity for which `f` returns false, along with the
+    /// component rows that belonged only to the deleted entities. The remaining
+    /// entities are updated to refer to the new locations of their components.
+    pub fn retain_entities<F: FnMut(EntityId, &EntityData) -> bool>(&mut self, mut f: F) {
+        let mut culled = Vec::new();
+        for (i, entity) in self.entities.entries.iter_mut().enumerate() {
+            if !entity.is_deleted && !f(EntityId::Idx(i as u32), entity) {
+                entity.is_deleted = true;
+                culled.push(std::mem::take(&mut entity.components));
+            }
+        }
+        if culled.is_empty() {
+            return;
+        }
+
+        for (id, array) in self.components.iter_mut() {
+            if array.is_marker() {
+                continue;
+            }
+
+            // Rows still used by a live entity must be kept, even if a culled entity
+            // also used them. Rows used by neither are left alone.
+            let len = array.len();
+            let mut used_by_live = vec![false; len];
+            let mut used_by_culled = vec![false; len];
+            let live = self.entities.entries.iter()
+                .filter(|e| !e.is_deleted)
+                .flat_map(|e| &e.components);
+            for comp_idx in live.filter(|c| c.id as usize == id) {
+                if let Some(used) = used_by_live.get_mut(comp_idx.idx as usize) {
+                    *used = true;
+                }
+            }
+            for comp_idx in culled.iter().flatten().filter(|c| c.id as usize == id) {
+                if let Some(used) = used_by_culled.get_mut(comp_idx.idx as usize) {
+                    *used = true;
+                }
+            }
+
+            let mut row = 0;
+            let remap = array.retain(|_| {
+                let keep = used_by_live[row] || !used_by_culled[row];
+                row += 1;
+                keep
+            });
+
+            let live = self.entities.entries.iter_mut()
+                .filter(|e| !e.is_deleted)
+                .flat_map(|e| &mut e.components);
+            for comp_idx in live.filter(|c| c.id as usize == id) {
+                if let Some(&Some(new_idx)) = remap.get(comp_idx.idx as usize) {
+                    comp_idx.idx = new_idx;
+                }
+            }
+        }
+    }
+
+    /// Reorder the components in an array to match the order of the entities
+    /// that have them, so that iterating over the entities visits the array in
+    /// order. A component shared by several entities is placed where the first
+    /// of them would put it, and components no entity has are moved to the end.
+    /// Returns `false` if there is no component array with the given ID.
+    pub fn sort_component_by_entity(&mut self, id: ComponentId) -> bool {
+        let id = id.0;
+        let array = match self.components.get_mut(id as usize) {
+            Some(array) => array,
+            None => return false,
+        };
+        if array.is_marker() {
+            return true;
+        }
+
+        // the new index of each component, and the old index of each new one
+        let mut remap = vec![None; array.len()];
+        let mut order = Vec::with_capacity(array.len());
+        let comp_idxs = self.entities.entries.iter()
+            .flat_map(|entity| &entity.components)
+            .filter(|comp_idx| comp_idx.id == id);
+        for comp_idx in comp_idxs {
+            if let Some(new_idx @ None) = remap.get_mut(comp_idx.idx as usize) {
+                *new_idx = Some(order.len() as u32);
+                order.push(comp_idx.idx);
+            }
+        }
+        for (old_idx, new_idx) in remap.iter_mut().enumerate() {
+            if new_idx.is_none() {
+                *new_idx = Some(order.len() as u32);
+                order.push(old_idx as u32);
+            }
+        }
+
+        array.permute(&order);
+        let comp_idxs = self.entities.entries.iter_mut()
+            .flat_map(|entity| &mut entity.components)
+            .filter(|comp_idx| comp_idx.id == id);
+        for comp_idx in comp_idxs {
+            if let Some(&Some(new_idx)) = remap.get(comp_idx.idx as usize) {
+                comp_idx.idx = new_idx;
+            }
+        }
+        true
+    }
+
+    /// The live entities with a component that refers to `target`, in increasing
+    /// order, for example to check that nothing still points at an entity before
+    /// deleting it. If the global component refers to `target`, the result begins
+    /// with `Owner::Global`.
+    pub fn referrers(&self, target: EntityId) -> Vec<Owner> {
+        let refers = |values: &[Value]| {
+            let mut found = false;
+            for value in values {
+                value.visit_entity_ids(&mut |id| found |= id == target);
+            }
+            found
+        };
+
+        let mut referrers = Vec::new();
+        if !target.is_valid() {
+            return referrers;
+        }
+        if refers(self.global.get().values) {
+            referrers.push(Owner::Global);
+        }
+        for (i, entity) in self.entities.entries.iter().enumerate() {
+            if entity.is_deleted {
+                continue;
+            }
+            let found = entity.components.iter().any(|comp_idx| {
+                self.components.get(comp_idx.id as usize)
+                    .and_then(|array| array.get(comp_idx.idx))
+                    .map_or(false, |comp| refers(comp.values))
+            });
+            if found {
+                referrers.push(Owner::Entity(EntityId::Idx(i as u32)));
+            }
+        }
+        referrers
+    }
+
+    /// Every reference from one entity to another, as pairs of the entity whose
+    /// component holds the reference and the entity it refers to, for example to
+    /// find the entities that nothing refers to. References held by the global
+    /// component have `Owner::Global` as their owner, and come first; the rest
+    /// are in order of the live entities that hold them. A reference that
+    /// appears several times is listed each time, and `EntityId::Invalid`
+    /// values, which don't refer to anything, are left out.
+    pub fn all_entity_references(&self) -> Vec<(Owner, EntityId)> {
+        let mut references = Vec::new();
+        let mut add = |owner: Owner, values: &[Value]| {
+            for value in values {
+                value.walk(&mut |v| match v {
+                    Value::EntityId(id) if id.is_valid() => references.push((owner, *id)),
+                    _ => {}
+                });
+            }
+        };
+
+        add(Owner::Global, self.global.get().values);
+        for (i, entity) in self.entities.entries.iter().enumerate() {
+            if entity.is_deleted {
+                continue;
+            }
+            for comp_idx in &entity.components {
+                let comp = self.components.get(comp_idx.id as usize)
+                    .and_then(|array| array.get(comp_idx.idx));
+                if let Some(comp) = comp {
+                    add(Owner::Entity(EntityId::Idx(i as u32)), comp.values);
+                }
+            }
+        }
+        references
+    }
+
+    /// Look for a cycle among live entities, treating every entity ID in the
+    /// given field of the named component as an edge, for example to check that
+    /// a chain of `parent` references terminates. The entities making up the
+    /// first cycle found are returned in the order their references are
+    /// followed; an entity referring to itself is a cycle of one.
+    pub fn find_reference_cycle(&self, component: &str, field: &str) -> Option<Vec<EntityId>> {
+        let edges = |i: usize| {
+            let mut targets = Vec::new();
+            let value = self.component_for_entity(EntityId::Idx(i as u32), component)
+                .and_then(|comp| comp.field(field));
+            if let Some(value) = value {
+                value.visit_entity_ids(&mut |id| if let EntityId::Idx(idx) = id {
+                    targets.push(idx as usize);
+                });
+            }
+            targets
+        };
+
+        // 0: not yet visited, 1: on the current path, 2: finished
+        let mut state = vec![0u8; self.entities.entries.len()];
+        for start in 0..state.len() {
+            if state[start] != 0 {
+                continue;
+            }
+
+            // depth-first search, keeping each entity on the path with its
+            // outgoing edges and how many of them have been followed so far
+            state[start] = 1;
+            let mut path = vec![(start, edges(start), 0)];
+            while let Some((node, targets, next)) = path.last_mut() {
+                let target = match targets.get(*next) {
+                    Some(&target) => target,
+                    None => {
+                        state[*node] = 2;
+                        path.pop();
+                        continue;
+                    }
+                };
+                *next += 1;
+
+                match state.get(target) {
+                    Some(0) => {
+                        state[target] = 1;
+                        path.push((target, edges(target), 0));
+                    }
+                    Some(1) => {
+                        let pos = path.iter().position(|(n, _, _)| *n == target).unwrap();
+                        return Some(path[pos..].iter().map(|(n, _, _)| EntityId::Idx(*n as u32)).collect());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+
+    /// Exchange the IDs of two live entities, rewriting every reference to
+    /// either of them in the component arrays and the global component so that
+    /// they still refer to the same entities.
+    pub fn swap_entities(&mut self, a: EntityId, b: EntityId) -> Result<(), BuildError> {
+        let live_idx = |id: EntityId| match id {
+            EntityId::Idx(idx) if self.entities.entries.get(idx as usize).map_or(false, |e| !e.is_deleted) => Ok(idx),
+            EntityId::Idx(idx) => Err(BuildError::InvalidEntityId(idx)),
+            EntityId::Invalid => Err(BuildError::InvalidEntityId(u32::MAX)),
+        };
+        let (a, b) = (live_idx(a)?, live_idx(b)?);
+        if a == b {
+            return Ok(());
+        }
+
+        self.entities.entries.swap(a as usize, b as usize);
+        let mut swap = |id: &mut EntityId| {
+            if *id == EntityId::Idx(a) {
+                *id = EntityId::Idx(b);
+            } else if *id == EntityId::Idx(b) {
+                *id = EntityId::Idx(a);
+            }
+        };
+        // only arrays that refer to one of the entities are rewritten, so that
+        // the others aren't marked dirty
+        let refers = |value: &Value| {
+            let mut found = false;
+            value.visit_entity_ids(&mut |id| found |= id == EntityId::Idx(a) || id == EntityId::Idx(b));
+            found
+        };
+        for array in self.components.values_mut() {
+            if !array.values().iter().any(refers) {
+                continue;
+            }
+            for value in array.values_mut() {
+                value.mutate_entity_ids(&mut swap);
+            }
+        }
+        for value in self.global.get_mut().values.iter_mut() {
+            value.mutate_entity_ids(&mut swap);
+        }
+        Ok(())
+    }
+
+    /// Release the memory reserved beyond what the world uses, in every component
+    /// array and in the entity array. This is worth doing after culling a large
+    /// number of entities with `retain_entities`.
+    pub fn shrink_to_fit(&mut self) {
+        for array in self.components.values_mut() {
+            array.shrink_to_fit();
+        }
+        for entity in &mut self.entities.entries {
+            entity.components.shrink_to_fit();
+        }
+        self.entities.entries.shrink_to_fit();
+    }
+
+    /// The ID that an entity will be written with when the world is encoded.
+    /// Deleted entities are not written, so the entities after them move down
+    /// to fill the gaps, and IDs that refer to deleted or nonexistent entities
+    /// are written as `Invalid`. If no entities are deleted, IDs of entities that
+    /// exist are unchanged.
+    pub fn resolve_packed(&self, id: EntityId) -> EntityId {
+        match self.entities.packed_idxs() {
+            None => check_entity_id(self.entities.entries.len(), id),
+            Some(packed_idxs) => pack_entity_id(&packed_idxs, id),
+        }
+    }
+
+    /// Make a copy of the world containing only the component arrays whose IDs are in
+    /// `keep`. Entities keep their IDs, even if they are left with no components.
+    pub fn project(&self, keep: &[ComponentId]) -> WorldData {
+        let components = self.components.iter()
+            .filter(|(id, _)| keep.contains(&ComponentId(*id as u16)))
+            .map(|(id, array)| (id, array.clone()))
+            .collect();
+
+        let entries = self.entities.entries.iter()
+            .map(|entity| EntityData {
+                is_deleted: entity.is_deleted,
+                components: entity.components.iter()
+                    .copied()
+                    .filter(|comp_idx| keep.contains(&ComponentId(comp_idx.id)))
+                    .collect(),
+            })
+            .collect();
+
+        WorldData {
+            components,
+            global: self.global.clone(),
+            entities: EntityArray { entries },
+            metadata: self.metadata.clone(),
+            observers: VecMap::new(),
+        }
+    }
+
+    /// Merge identical components within each component array, so that entities
+    /// with equal component data share a single copy of it. After this, mutating
+    /// such a component affects every entity that shares it.
+    pub fn intern_components(&mut self) {
+        for (id, array) in self.components.iter_mut() {
+            let remap = array.dedup();
+            for entity in &mut self.entities.entries {
+                for comp_idx in &mut entity.components {
+                    if comp_idx.id as usize != id {
+                        continue;
+                    }
+                    if let Some(&new_idx) = remap.get(comp_idx.idx as usize) {
+                        comp_idx.idx = new_idx;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The ID and values of each component of a live entity, or `None` if the
+// index does not refer to a live entity.
+fn live_components(world: &WorldData, idx: usize) -> Option<Vec<(u16, &[Value])>> {
+    let entity = world.entities.entries.get(idx)?;
+    if entity.is_deleted {
+        return None;
+    }
+    Some(entity.components.iter().filter_map(|comp_idx| {
+        let array = world.components.get(comp_idx.id as usize)?;
+        Some((comp_idx.id, array.get(comp_idx.idx)?.values))
+    }).collect())
+}
+
+// The changes needed to turn the component `old` into `new`. If the two have
+// different numbers of fields, the component is replaced outright.
+fn diff_component(
+    id: u16,
+    old: &[Value],
+    new: &[Value],
+    changes: &mut Vec<ComponentPatch>,
+) -> Result<(), BuildError> {
+    if old.len() != new.len() {
+        changes.push(ComponentPatch::Removed(id));
+        changes.push(ComponentPatch::Added(id, new.to_vec()));
+        return Ok(());
+    }
+    for (field, (old, new)) in old.iter().zip(new).enumerate() {
+        if !old.bitwise_eq(new) {
+            changes.push(ComponentPatch::Field(id, patch_field(Some(ComponentId(id)), field)?, new.clone()));
+        }
+    }
+    Ok(())
+}
+
+// The index of a changed field as stored in a patch, which only has room for 16 bits.
+fn patch_field(id: Option<ComponentId>, field: usize) -> Result<u16, BuildError> {
+    u16::try_from(field).map_err(|_| BuildError::TooManyFields { id, count: field + 1 })
+}
+
+impl WorldData {
+    /// Compute the changes that turn this world into `newer`. Entities are matched
+    /// up by index, and component arrays by ID.
+    ///
+    /// Component arrays which exist only in `newer` are recorded in the patch, but
+    /// changes to the name or scheme of an existing array are not. Floats are
+    /// compared by their bit patterns, so a change from 0.0 to -0.0 is recorded,
+    /// but a NaN which is left alone is not.
+    ///
+    /// Fails if a changed field's index doesn't fit in the 16 bits a patch has
+    /// for it.
+    pub fn diff(&self, newer: &WorldData) -> Result<WorldPatch, BuildError> {
+        let new_components = newer.components.iter()
+            .filter(|(id, _)| !self.components.contains_key(*id))
+            .map(|(_, array)| (array.name().to_string(), array.id(), array.scheme().to_vec()))
+            .collect();
+
+        let old_global = self.global.get().values;
+        let global = newer.global.get().values.iter()
+            .enumerate()
+            .filter(|&(field, value)| !old_global.get(field).map_or(false, |old| old.bitwise_eq(value)))
+            .map(|(field, value)| Ok((patch_field(None, field)?, value.clone())))
+            .collect::<Result<_, BuildError>>()?;
+
+        let mut entities = Vec::new();
+        let num_slots = self.entities.entries.len().max(newer.entities.entries.len());
+        for idx in 0..num_slots {
+            let patch = match (live_components(self, idx), live_components(newer, idx)) {
+                (None, None) => continue,
+                (Some(_), None) => EntityPatch::Removed,
+                (None, Some(new)) => EntityPatch::Added(
+                    new.into_iter().map(|(id, values)| (id, values.to_vec())).collect(),
+                ),
+                (Some(old), Some(new)) => {
+                    let mut changes = Vec::new();
+                    for &(id, _) in &old {
+                        if !new.iter().any(|&(new_id, _)| new_id == id) {
+                            changes.push(ComponentPatch::Removed(id));
+                        }
+                    }
+                    for &(id, values) in &new {
+                        match old.iter().find(|&&(old_id, _)| old_id == id) {
+                            Some(&(_, old_values)) => diff_component(id, old_values, values, &mut changes)?,
+                            None => changes.push(ComponentPatch::Added(id, values.to_vec())),
+                        }
+                    }
+                    if changes.is_empty() {
+                        continue;
+                    }
+                    EntityPatch::Changed(changes)
+                }
+            };
+            entities.push((idx as u32, patch));
         }
+
+        Ok(WorldPatch {
+            num_entities: newer.entities.entries.len() as u32,
+            new_components,
+            global,
+            entities,
+        })
+    }
+
+    /// Apply a patch computed by `diff`, turning this world into the newer one.
+    ///
+    /// The patch is validated before anything is changed, so if an error is
+    /// returned the world is left as it was. Components whose fields change are
+    /// copied to new rows rather than modified in place, since their rows may be
+    /// shared with other entities; the old rows are left in their arrays.
+    pub fn apply_patch(&mut self, patch: &WorldPatch) -> Result<(), BuildError> {
+        self.check_patch(patch)?;
+
+        for (name, id, scheme) in &patch.new_components {
+            let array = ComponentArray::with_scheme(name.clone(), id.0, scheme.clone());
+            self.components.insert(id.0 as usize, array);
+        }
+
+        let global = self.global.get_mut().values;
+        for (field, value) in &patch.global {
+            global[*field as usize] = value.clone();
+        }
+
+        let num_slots = self.entities.entries.len().max(patch.num_entities as usize);
+        self.entities.entries.resize_with(num_slots, || EntityData {
+            is_deleted: true,
+            components: Vec::new(),
+        });
+
+        for (idx, entity_patch) in &patch.entities {
+            let entity = &mut self.entities.entries[*idx as usize];
+            match entity_patch {
+                EntityPatch::Removed => {
+                    entity.is_deleted = true;
+                    entity.components.clear();
+                }
+                EntityPatch::Added(components) => {
+                    entity.is_deleted = false;
+                    entity.components.clear();
+                    for (id, values) in components {
+                        let idx = self.components[*id as usize].push(values.clone());
+                        entity.insert_component(ComponentIdx { id: *id, idx });
+                    }
+                }
+                EntityPatch::Changed(changes) => {
+                    // the components which have already been copied to a fresh row
+                    let mut copied = Vec::new();
+                    for change in changes {
+                        match change {
+                            ComponentPatch::Removed(id) => {
+                                let pos = entity.component_idx(*id).unwrap();
+                                entity.components.remove(pos);
+                            }
+                            ComponentPatch::Added(id, values) => {
+                                let idx = self.components[*id as usize].push(values.clone());
+                                entity.insert_component(ComponentIdx { id: *id, idx });
+                                copied.push(*id);
+                            }
+                            ComponentPatch::Field(id, field, value) => {
+                                let array = &mut self.components[*id as usize];
+                                let pos = entity.component_idx(*id).unwrap();
+                                let comp_idx = &mut entity.components[pos];
+                                if !copied.contains(id) {
+                                    let values = array.get(comp_idx.idx).unwrap().values.to_vec();
+                                    comp_idx.idx = array.push(values);
+                                    copied.push(*id);
+                                }
+                                array.get_mut(comp_idx.idx).unwrap().values[*field as usize] = value.clone();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.entities.entries.truncate(patch.num_entities as usize);
+        Ok(())
+    }
+
+    // Ensure that applying `patch` cannot fail partway through.
+    fn check_patch(&self, patch: &WorldPatch) -> Result<(), BuildError> {
+        let mut names: HashSet<&str> = self.component_names().collect();
+        for (name, id, scheme) in &patch.new_components {
+            if self.components.contains_key(id.0 as usize) {
+                return Err(BuildError::DuplicateComponentId(*id));
+            }
+            if !names.insert(name) {
+                return Err(BuildError::DuplicateComponentName(name.clone()));
+            }
+            if let Some(name) = scheme.iter().find(|n| !component::is_valid_name(n)) {
+                return Err(BuildError::InvalidName(name.to_string()));
+            }
+            if let Some(dup) = component::find_duplicate_quadratic(scheme) {
+                return Err(BuildError::DuplicateField(dup.to_string()));
+            }
+        }
+        let scheme_len = |id: u16| -> Result<usize, BuildError> {
+            if let Some(array) = self.components.get(id as usize) {
+                return Ok(array.scheme().len());
+            }
+            patch.new_components.iter()
+                .find(|(_, new_id, _)| new_id.0 == id)
+                .map(|(_, _, scheme)| scheme.len())
+                .ok_or(BuildError::UnknownComponent(ComponentId(id)))
+        };
+        let check_values = |id: u16, values: &[Value]| {
+            let expected = scheme_len(id)?;
+            if values.len() != expected {
+                return Err(BuildError::ValueCount { id: ComponentId(id), expected, got: values.len() });
+            }
+            Ok(())
+        };
+
+        let num_global = self.global.get().values.len();
+        if let Some(&(field, _)) = patch.global.iter().find(|(field, _)| *field as usize >= num_global) {
+            return Err(BuildError::InvalidField { id: None, field });
+        }
+
+        for (idx, entity_patch) in &patch.entities {
+            let live = live_components(self, *idx as usize);
+            match entity_patch {
+                EntityPatch::Removed => {}
+                EntityPatch::Added(components) => {
+                    if *idx >= patch.num_entities {
+                        return Err(BuildError::InvalidEntityId(*idx));
+                    }
+                    for (id, values) in components {
+                        check_values(*id, values)?;
+                    }
+                }
+                EntityPatch::Changed(changes) => {
+                    let mut ids: Vec<u16> = match live {
+                        Some(live) if *idx < patch.num_entities => live.iter().map(|&(id, _)| id).collect(),
+                        _ => return Err(BuildError::InvalidEntityId(*idx)),
+                    };
+                    for change in changes {
+                        match change {
+                            ComponentPatch::Removed(id) => match ids.iter().position(|i| i == id) {
+                                Some(pos) => { ids.remove(pos); }
+                                None => return Err(BuildError::UnknownComponent(ComponentId(*id))),
+                            },
+                            ComponentPatch::Added(id, values) => {
+                                check_values(*id, values)?;
+                                ids.push(*id);
+                            }
+                            ComponentPatch::Field(id, field, _) => {
+                                if !ids.contains(id) {
+                                    return Err(BuildError::UnknownComponent(ComponentId(*id)));
+                                }
+                                if *field as usize >= scheme_len(*id)? {
+                                    return Err(BuildError::InvalidField { id: Some(ComponentId(*id)), field: *field });
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl<R: io::Read> decode::State<R> {
     pub fn decode_world(&mut self) -> Result<WorldData, decode::Error> {
-        let header = self.decode_header_line("world state header")?;
+        self.decode_world_with_progress(&mut |_| {})
+    }
 
-        if header.len() != 3 {
+    // Decode a world, periodically calling `progress` with the number of bytes
+    // consumed so far. The last call is made with the total length of the input.
+    pub fn decode_world_with_progress(
+        &mut self,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<WorldData, decode::Error> {
+        let world = self.decode_embedded_world_with_progress(progress, None)?;
+        self.expect_eof()?;
+        progress(self.idx());
+        Ok(world)
+    }
+
+    // Decode a world, keeping only the component arrays whose IDs are in `keep`,
+    // for example to save memory when loading a world written by a newer
+    // program with components this one doesn't use. The values of the other
+    // arrays are read past without being decoded: their headers are checked as
+    // usual, and so are the tags and lengths of their values, but strings in
+    // them aren't checked to be UTF-8 and compressed values aren't inflated.
+    // They are removed from the entities that had them.
+    pub fn decode_world_filtered(&mut self, keep: &HashSet<ComponentId>) -> Result<WorldData, decode::Error> {
+        let world = self.decode_embedded_world_with_progress(&mut |_| {}, Some(keep))?;
+        self.expect_eof()?;
+        Ok(world)
+    }
+
+    // Decode a world without requiring that it be followed by the end of the
+    // input, for worlds embedded in a larger stream. No bytes after the end
+    // of the world are consumed.
+    pub fn decode_embedded_world(&mut self) -> Result<WorldData, decode::Error> {
+        self.decode_embedded_world_with_progress(&mut |_| {}, None)
+    }
+
+    // Decode a world written by `encode_world_framed`: a 64-bit big-endian
+    // length, followed by a world which must take up exactly that many bytes.
+    // Nothing past the end of the frame is consumed.
+    pub fn decode_world_framed(&mut self) -> Result<WorldData, decode::Error> {
+        let len = self.decode_u64()?;
+        let len = match usize::try_from(len) {
+            Ok(len) => len,
+            Err(_) => return Err(self.err_unexpected(
+                decode::ErrorKind::LengthLimitExceeded,
+                "world length that fits in memory",
+                format!("length {}", len),
+            )),
+        };
+
+        let outer_limit = self.limit;
+        let end = self.idx().saturating_add(len);
+        self.limit = end.min(outer_limit);
+        let result = self.decode_embedded_world();
+        self.limit = outer_limit;
+        let world = result?;
+
+        if self.idx() != end {
             return Err(self.err_unexpected(
-                "world state header with three fields",
+                decode::ErrorKind::TrailingBytes,
+                format!("end of the world frame at byte {}", end),
+                "trailing bytes",
+            ));
+        }
+        Ok(world)
+    }
+
+    fn decode_embedded_world_with_progress(
+        &mut self,
+        progress: &mut dyn FnMut(usize),
+        keep: Option<&HashSet<ComponentId>>,
+    ) -> Result<WorldData, decode::Error> {
+        let (num_component_arrays, max_component_id) = self.decode_world_header()?;
+        let metadata = self.decode_world_metadata()?;
+
+        // arrays which aren't kept are recorded as `None`, so that later arrays
+        // are still checked against their IDs
+        let mut component_arrays = VecMap::with_capacity(max_component_id.0 as usize + 1);
+        let mut component_names = HashSet::with_capacity(num_component_arrays as usize);
+
+        // Read a sequence of component arrays, reading past the values of
+        // those which aren't kept without decoding them
+        for _ in 0..num_component_arrays {
+            let header = self.decode_component_header()?;
+            let id = header.id;
+            let name = header.name.clone();
+            let array = if keep.map_or(true, |keep| keep.contains(&ComponentId(id))) {
+                Some(self.decode_component_values(header)?)
+            } else {
+                self.skip_component_values(&header)?;
+                None
+            };
+            self.check_component_array(ComponentId(id), &name, max_component_id, &component_arrays, &component_names)?;
+
+            component_names.insert(name);
+            component_arrays.insert(id as usize, array);
+            self.expect_newline()?;
+            progress(self.idx());
+        }
+        let components: VecMap<ComponentArray> = component_arrays.into_iter()
+            .filter_map(|(id, array)| Some((id, array?)))
+            .collect();
+
+        let global = self.decode_global_component()?;
+        self.expect_newline()?;
+        progress(self.idx());
+
+        let mut entities = self.decode_entity_array(progress)?;
+        if keep.is_some() {
+            for entity in &mut entities.entries {
+                entity.components.retain(|comp_idx| components.contains_key(comp_idx.id as usize));
+            }
+        }
+
+        Ok(WorldData { components, global, entities, metadata, observers: VecMap::new() })
+    }
+
+    // Decode the header line of a world, returning the number of component
+    // arrays and the maximum component ID. The format version the header
+    // declares is used for decoding the rest of the world.
+    //
+    // This can also be used on its own to inspect a world without decoding it.
+    pub fn decode_world_header(&mut self) -> Result<(u16, ComponentId), decode::Error> {
+        let header = self.decode_header_line_with_offsets("world state header")?;
+
+        // version 0 of the format had no version field
+        if header.len() != 3 && header.len() != 4 {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::MalformedHeader,
+                "world state header with three or four fields",
                 format!("{} fields", header.len()),
             ));
         }
 
-        let signature = &header[0];
+        let (signature, at) = &header[0];
         if signature != "WORLD" {
-            return Err(self.err_unexpected(
+            return Err(self.err_unexpected_at(
+                *at,
+                decode::ErrorKind::BadSignature,
                 "world state signature (WORLD)",
                 format!("invalid signature: {:?}", signature),
             ));
         }
 
-        let num_component_arrays = match header[1].parse::<u16>() {
+        let num_component_arrays = match header[1].0.parse::<u16>() {
             Ok(n) => n,
-            Err(_) => return Err(self.err_unexpected(
+            Err(_) => return Err(self.err_unexpected_at(
+                header[1].1,
+                decode::ErrorKind::InvalidNumber,
                 "16-bit entity array count",
-                "invalid entity array count",
+                format!("invalid entity array count: {:?}", header[1].0),
             )),
         };
 
-        let max_component_id = match header[2].parse::<u16>() {
-            Ok(n) => n,
-            Err(_) => return Err(self.err_unexpected(
+        let max_component_id = match header[2].0.parse::<u16>() {
+            Ok(n) => ComponentId(n),
+            Err(_) => return Err(self.err_unexpected_at(
+                header[2].1,
+                decode::ErrorKind::InvalidNumber,
                 "16-bit maximum component ID",
-                "invalid maximum component ID",
+                format!("invalid maximum component ID: {:?}", header[2].0),
             ))
         };
-        
-        let mut component_arrays = VecMap::with_capacity(max_component_id as usize + 1);
-        let mut component_names = HashSet::with_capacity(num_component_arrays as usize);
 
-        // Read a sequence of component arrays
-        for _ in 0..num_component_arrays {
-            let array = self.decode_component_array()?;
-            let id = array.id();
-            let name = array.name();
+        let version = match header.get(3).map(|(v, at)| (v.parse::<u32>(), v, *at)) {
+            None => 0,
+            Some((Ok(v), _, _)) if v <= decode::FORMAT_VERSION => v,
+            Some((Ok(v), _, at)) => return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::UnsupportedVersion,
+                format!("format version at most {}", decode::FORMAT_VERSION),
+                format!("version {}", v),
+            )),
+            Some((Err(_), v, at)) => return Err(self.err_unexpected_at(
+                at,
+                decode::ErrorKind::InvalidNumber,
+                "32-bit format version",
+                format!("invalid format version: {:?}", v),
+            )),
+        };
+        self.set_version(version);
 
-            if !component_names.insert(name.to_string()) {
-                return Err(self.err_unexpected(
-                    "unique component names",
-                    format!("duplicate component name {:?}", name),
-                ));
+        Ok((num_component_arrays, max_component_id))
+    }
+
+    // Decode the `META key value` lines following a world header. The value is
+    // the rest of the line, with runs of whitespace collapsed to single spaces.
+    // Like `decode_world_header`, this can be used on its own, in which case it
+    // must be called right after the header.
+    pub fn decode_world_metadata(&mut self) -> Result<BTreeMap<String, String>, decode::Error> {
+        let mut metadata = BTreeMap::new();
+        // metadata was added in version 4 of the format
+        if self.version() < 4 {
+            return Ok(metadata);
+        }
+
+        loop {
+            let (line, start) = self.read_header_line("metadata or component array header")?;
+            let mut header = decode::split_header_line(&line, start);
+            if header.first().map(|(field, _)| field.as_str()) != Some("META") {
+                self.unread_header_line(line, start);
+                return Ok(metadata);
             }
-            if id > max_component_id {
+            if header.len() < 2 {
                 return Err(self.err_unexpected(
-                    format!("all component IDs within the maximum specified ({})",
-                        max_component_id),
-                    format!("component {:?} with ID greater than the maximum ({})",
-                        name, id),
+                    decode::ErrorKind::MalformedHeader,
+                    "metadata key",
+                    "too few fields",
                 ));
             }
-            if component_arrays.contains_key(id as usize) {
-                return Err(self.err_unexpected(
-                    "unique component IDs",
-                    format!("component {:?} with duplicate ID: {}", name, id),
+
+            let value: Vec<String> = header.drain(2..).map(|(field, _)| field).collect();
+            let (key, at) = header.pop().unwrap();
+            if metadata.contains_key(&key) {
+                return Err(self.err_unexpected_at(
+                    at,
+                    decode::ErrorKind::MalformedHeader,
+                    "distinct metadata keys",
+                    format!("duplicate key: {:?}", key),
                 ));
             }
+            metadata.insert(key, value.join(" "));
+        }
+    }
 
-            component_arrays.insert(id as usize, array);
-            self.expect_newline()?;
+    // Check that a newly-decoded component array can be added to those already decoded.
+    fn check_component_array<T>(
+        &self,
+        id: ComponentId,
+        name: &str,
+        max_component_id: ComponentId,
+        component_arrays: &VecMap<T>,
+        component_names: &HashSet<String>,
+    ) -> Result<(), decode::Error> {
+        if component_names.contains(name) {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::DuplicateComponent,
+                "unique component names",
+                format!("duplicate component name {:?}", name),
+            ));
+        }
+        if id > max_component_id {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::ComponentIdOutOfRange,
+                format!("all component IDs within the maximum specified ({})",
+                    max_component_id),
+                format!("component {:?} with ID greater than the maximum ({})",
+                    name, id),
+            ));
+        }
+        if component_arrays.contains_key(id.0 as usize) {
+            return Err(self.err_unexpected(
+                decode::ErrorKind::DuplicateComponent,
+                "unique component IDs",
+                format!("component {:?} with duplicate ID: {}", name, id),
+            ));
         }
 
-        let global = self.decode_global_component()?;
-        self.expect_newline()?;
+        Ok(())
+    }
+}
+
+// Find the first position after `start` which follows a newline and begins with one of `markers`.
+fn find_resync_point(bytes: &[u8], start: usize, markers: &[&[u8]]) -> Option<usize> {
+    (start + 1 .. bytes.len()).find(|&i| {
+        bytes[i - 1] == b'\n' && markers.iter().any(|m| bytes[i..].starts_with(m))
+    })
+}
+
+/// Copy a world from `reader` to `writer` one section at a time, passing each
+/// component array to `transform` before it is written. This needs only one
+/// component array and one entity in memory at a time, so it can be used to
+/// rewrite worlds too large to load.
+///
+/// The output is written with the current format version, and with the
+/// settings of a default `encode::State`. Entities, including deleted ones,
+/// are copied as they are, so `EntityId`s don't need to be renumbered.
+/// Unlike `decode_world`, this doesn't check that the entities only refer to
+/// components that exist, since the arrays have already been written by the
+/// time the entities are read.
+pub fn transcode_world<R: io::Read, W: io::Write>(
+    reader: R,
+    writer: W,
+    mut transform: impl FnMut(&mut ComponentArray),
+) -> Result<(), error::Error> {
+    let mut state = decode::State::new(reader);
+    let mut out = encode::State::new(writer);
+
+    let (num_component_arrays, max_component_id) = state.decode_world_header().map_err(error::Error::Decode)?;
+    let metadata = state.decode_world_metadata().map_err(error::Error::Decode)?;
+    out.write_fmt(format_args!(
+        "WORLD {} {} {}\n",
+        num_component_arrays,
+        max_component_id,
+        decode::FORMAT_VERSION,
+    )).map_err(error::Error::Encode)?;
+    for (key, value) in &metadata {
+        out.write_fmt(format_args!("META {} {}\n", key, value)).map_err(error::Error::Encode)?;
+    }
+
+    // only the IDs and names of the arrays already copied are kept, to check
+    // each new one against them
+    let mut seen_ids = VecMap::with_capacity(max_component_id.0 as usize + 1);
+    let mut seen_names = HashSet::with_capacity(num_component_arrays as usize);
+    for _ in 0..num_component_arrays {
+        let mut array = state.decode_component_array().map_err(error::Error::Decode)?;
+        state.check_component_array(array.id(), array.name(), max_component_id, &seen_ids, &seen_names)
+            .map_err(error::Error::Decode)?;
+        state.expect_newline().map_err(error::Error::Decode)?;
+
+        transform(&mut array);
+        out.encode_component_array(&array, &mut |_: &mut EntityId| {}).map_err(error::Error::Encode)?;
+        out.write(b"\n").map_err(error::Error::Encode)?;
+
+        seen_names.insert(array.name().to_string());
+        seen_ids.insert(array.id().0 as usize, ());
+    }
+
+    let global = state.decode_global_component().map_err(error::Error::Decode)?;
+    state.expect_newline().map_err(error::Error::Decode)?;
+    out.encode_global_component(&global, |_| {}).map_err(error::Error::Encode)?;
+    out.write(b"\n").map_err(error::Error::Encode)?;
+
+    let mut entities = state.entity_array_iter().map_err(error::Error::Decode)?;
+    out.write_fmt(format_args!("ENTITIES {}\n", entities.remaining)).map_err(error::Error::Encode)?;
+    for entity in &mut entities {
+        let entity = entity.map_err(error::Error::Decode)?;
+        out.encode_entity_data(&entity).map_err(error::Error::Encode)?;
+    }
+
+    state.expect_eof().map_err(error::Error::Decode)
+}
+
+/// Decode a world on a best-effort basis, collecting errors instead of stopping
+/// at the first one. This is intended for tools that want to report everything
+/// wrong with a partly-corrupt file.
+///
+/// A malformed component array is skipped by scanning ahead to the next line
+/// that looks like a component array or global component header, and a malformed
+/// global component is replaced by an empty one. Since the binary data may itself
+/// contain such lines, this may skip too much or too little. The world is `None`
+/// if the world header or the entity array could not be decoded.
+pub fn decode_world_lenient<R: io::Read>(
+    mut reader: R,
+) -> (Option<WorldData>, Vec<decode::Error>) {
+    let mut bytes = Vec::new();
+    if let Err(err) = reader.read_to_end(&mut bytes) {
+        return (None, vec![err.into()]);
+    }
+
+    let mut errors = Vec::new();
+    let mut state = decode::State::new(&bytes[..]);
+
+    let (num_component_arrays, max_component_id) = match state.decode_world_header() {
+        Ok(header) => header,
+        Err(err) => return (None, vec![err]),
+    };
+    let metadata = match state.decode_world_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) => return (None, vec![err]),
+    };
+
+    let mut component_arrays = VecMap::with_capacity(max_component_id.0 as usize + 1);
+    let mut component_names = HashSet::with_capacity(num_component_arrays as usize);
+
+    for _ in 0..num_component_arrays {
+        let start = state.idx();
+        let result = state.decode_component_array().and_then(|array| {
+            state.check_component_array(array.id(), array.name(), max_component_id, &component_arrays, &component_names)?;
+            state.expect_newline()?;
+            Ok(array)
+        });
 
-        let entities = self.decode_entity_array()?;
+        match result {
+            Ok(array) => {
+                component_names.insert(array.name().to_string());
+                component_arrays.insert(array.id().0 as usize, array);
+            }
+            Err(err) => {
+                errors.push(err);
+                match find_resync_point(&bytes, start, &[b"COMPONENT ", b"COMPONENT-DEFLATE ", b"COMPONENT-BITS ", b"COMPONENT-SPARSE ", b"GLOBAL"]) {
+                    Some(i) => state = state.with_settings_of(&bytes[i..], i),
+                    None => return (None, errors),
+                }
+            }
+        }
+    }
+
+    let start = state.idx();
+    let global = match state.decode_global_component().and_then(|global| {
+        state.expect_newline()?;
+        Ok(global)
+    }) {
+        Ok(global) => global,
+        Err(err) => {
+            errors.push(err);
+            match find_resync_point(&bytes, start, &[b"ENTITIES "]) {
+                Some(i) => state = state.with_settings_of(&bytes[i..], i),
+                None => return (None, errors),
+            }
+            GlobalComponent::empty()
+        }
+    };
+
+    let entities = match state.decode_entity_array(&mut |_| {}) {
+        Ok(entities) => entities,
+        Err(err) => {
+            errors.push(err);
+            return (None, errors);
+        }
+    };
+
+    if let Err(err) = state.expect_eof() {
+        errors.push(err);
+    }
+
+    (Some(WorldData { components: component_arrays, global, entities, metadata, observers: VecMap::new() }), errors)
+}
+
+// Map an in-memory `EntityId` to the one it is written as, given the packed
+// indices of the entities. Deleted and out-of-range entities become `Invalid`.
+pub(crate) fn pack_entity_id(packed_idxs: &[Option<u32>], id: EntityId) -> EntityId {
+    match id {
+        EntityId::Idx(idx) => match packed_idxs.get(idx as usize) {
+            Some(&Some(new_idx)) => EntityId::Idx(new_idx),
+            _ => EntityId::Invalid,
+        },
+        EntityId::Invalid => EntityId::Invalid,
+    }
+}
 
-        Ok(WorldData { components: component_arrays, global, entities })
+// Map an in-memory `EntityId` to the one it is written as when no entities are
+// deleted, given the number of entities. Only out-of-range entities change,
+// becoming `Invalid` as they would if some entities were deleted.
+pub(crate) fn check_entity_id(num_entities: usize, id: EntityId) -> EntityId {
+    match id {
+        EntityId::Idx(idx) if (idx as usize) < num_entities => id,
+        _ => EntityId::Invalid,
     }
 }
 
 impl<W: io::Write> encode::State<W> {
     pub fn encode_world(&mut self, world: &WorldData) -> io::Result<()> {
         let num_component_arrays = world.components.len();
-        let max_component_arrays = world.components.iter()
-            .next_back()
-            .map(|(i, _)| i)
-            .unwrap_or(0);
-        
+        // taken from the arrays themselves rather than the keys they're stored
+        // under, since decoding rejects any array with an ID above this
+        let max_component_id = world.components.values().map(|array| array.id().0).max().unwrap_or(0);
+
         self.write_fmt(format_args!(
-            "WORLD {} {}\n",
+            "WORLD {} {} {}\n",
             num_component_arrays,
-            max_component_arrays,
+            max_component_id,
+            decode::FORMAT_VERSION,
         ))?;
 
-        let packed_idxs = world.entities.packed_idxs();
-        let transform_id = |id: &mut EntityId| {
-            if let EntityId::Idx(ref mut idx) = id {
-                if let Some(&Some(new_idx)) = packed_idxs.get(*idx as usize) {
-                    *idx = new_idx;
-                } else {
-                    *id = EntityId::Invalid;
-                }
+        for (key, value) in &world.metadata {
+            self.write_fmt(format_args!("META {} {}\n", key, value))?;
+        }
+
+        match world.entities.packed_idxs() {
+            // No entities are deleted, so the `EntityId`s can be written as-is,
+            // as long as they refer to entities which exist.
+            None => {
+                let num_entities = world.entities.entries.len();
+                self.encode_world_contents(world, |id: &mut EntityId| {
+                    *id = check_entity_id(num_entities, *id);
+                })
             }
-        };
+            Some(packed_idxs) => self.encode_world_contents(world, |id: &mut EntityId| {
+                *id = pack_entity_id(&packed_idxs, *id);
+            }),
+        }
+    }
+
+    // Encode a world preceded by its length in bytes, as a 64-bit big-endian
+    // integer, so that it can be embedded in a larger stream and skipped over
+    // without being decoded. Decode it with `decode_world_framed`.
+    pub fn encode_world_framed(&mut self, world: &WorldData) -> io::Result<()> {
+        let mut buffer = self.with_settings_of(Vec::new());
+        buffer.encode_world(world)?;
+        let encoded = buffer.into_inner();
+        self.write(&(encoded.len() as u64).to_be_bytes())?;
+        self.write(&encoded)
+    }
 
+    fn encode_world_contents<ET: FnMut(&mut EntityId)>(
+        &mut self,
+        world: &WorldData,
+        mut transform_id: ET,
+    ) -> io::Result<()> {
         // Encode the component arrays, but transform any `EntityId`s they contain
         // to reflect the fact that deleted entities are not serialzed.
+        //
+        // `VecMap` iterates in increasing order of ID regardless of the order in
+        // which the arrays were inserted, so the output is byte-stable. If this is
+        // ever changed to a map without ordered iteration, the arrays must be
+        // sorted here instead.
         for component_array in world.components.values() {
-            self.encode_component_array(component_array, transform_id)?;
+            self.encode_component_array(component_array, &mut transform_id)?;
             self.write(b"\n")?;
         }
 
         // Encode the global component, applying the same transformation.
-        self.encode_global_component(&world.global, transform_id)?;
+        self.encode_global_component(&world.global, &mut transform_id)?;
         self.write(b"\n")?;
 
         // Encode the entity array.